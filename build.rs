@@ -0,0 +1,54 @@
+// with the `compile-shaders` feature, compiles the built-in shaders in
+// src/graphics to SPIR-V, which graphics/shaders.rs then embeds from OUT_DIR
+// instead of the SPIR-V committed next to them
+
+#[cfg(feature = "compile-shaders")]
+use std::env;
+#[cfg(feature = "compile-shaders")]
+use std::fs;
+#[cfg(feature = "compile-shaders")]
+use std::path::Path;
+
+#[cfg(feature = "compile-shaders")]
+const SHADERS: &[(&str, shaderc::ShaderKind, &str)] = &[
+    (
+        "vertex_shader.vert",
+        shaderc::ShaderKind::Vertex,
+        "vert.spv",
+    ),
+    (
+        "fragment_shader.frag",
+        shaderc::ShaderKind::Fragment,
+        "frag.spv",
+    ),
+];
+
+#[cfg(not(feature = "compile-shaders"))]
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+#[cfg(feature = "compile-shaders")]
+fn main() {
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR isn't set");
+    let mut compiler = shaderc::Compiler::new().expect("failed to initialize shaderc");
+    let mut options = shaderc::CompileOptions::new().expect("failed to create compile options");
+    options.set_optimization_level(shaderc::OptimizationLevel::Performance);
+
+    for &(source_name, kind, output_name) in SHADERS {
+        let source_path = Path::new("src").join("graphics").join(source_name);
+        println!("cargo:rerun-if-changed={}", source_path.display());
+
+        let source = fs::read_to_string(&source_path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", source_path.display(), e));
+        let artifact = compiler
+            .compile_into_spirv(&source, kind, source_name, "main", Some(&options))
+            .unwrap_or_else(|e| panic!("failed to compile {}:\n{}", source_name, e));
+
+        fs::write(
+            Path::new(&out_dir).join(output_name),
+            artifact.as_binary_u8(),
+        )
+        .unwrap_or_else(|e| panic!("failed to write {}: {}", output_name, e));
+    }
+}
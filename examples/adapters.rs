@@ -0,0 +1,36 @@
+extern crate rmge;
+
+use rmge::graphics::AdapterPreference;
+use rmge::Renderer;
+
+// lists the adapters a headless renderer could use, then renders with the one
+// whose index is passed as the first argument, if any
+fn main() {
+    let adapters = Renderer::adapters().expect("failed to list adapters");
+    for adapter in &adapters {
+        let device_memory: u64 = adapter
+            .memory_heaps
+            .iter()
+            .filter(|heap| heap.device_local)
+            .map(|heap| heap.size)
+            .sum();
+        println!(
+            "{}: {} ({:?}, {} MiB of device memory)",
+            adapter.index,
+            adapter.info.name,
+            adapter.info.device_type,
+            device_memory / (1024 * 1024)
+        );
+    }
+
+    let preference = match std::env::args().nth(1) {
+        Some(index) => AdapterPreference::Index(index.parse().expect("not an adapter index")),
+        None => AdapterPreference::Any,
+    };
+    let renderer = Renderer::builder()
+        .adapter(preference)
+        .adapter_fallback(false)
+        .build_headless(64, 64)
+        .expect("failed to build context");
+    println!("{:#?}", renderer.choices());
+}
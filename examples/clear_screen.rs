@@ -27,6 +27,12 @@ fn main() {
         } => {
             *control_flow = ControlFlow::Exit;
         }
+        Event::WindowEvent {
+            event: WindowEvent::Resized(size),
+            ..
+        } => {
+            context.resize(size.width, size.height);
+        }
         Event::MainEventsCleared => {
             context
                 .clear([0.0, 1.0, 0.0, 1.0])
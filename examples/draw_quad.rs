@@ -1,11 +1,7 @@
-#![feature(backtrace)]
-
 extern crate rmge;
 extern crate winit;
-#[macro_use]
-extern crate log;
 
-use rmge::geometry::{Quad, Vec3};
+use rmge::geometry::{Quad, Vec2, Vec4};
 use rmge::Renderer;
 
 use log::LevelFilter;
@@ -36,12 +32,10 @@ fn main() {
         Err(e) => {
             use std::error::Error;
 
-            let back = e.backtrace();
             eprintln!(
-                "Error creating context\nerror: {}\nsource: {:?}\nbacktrace: {:?}",
+                "Error creating context\nerror: {}\nsource: {:?}",
                 e,
                 e.source(),
-                back,
             );
             std::process::exit(1);
         }
@@ -54,18 +48,23 @@ fn main() {
         } => {
             *control_flow = ControlFlow::Exit;
         }
+        Event::WindowEvent {
+            event: WindowEvent::Resized(size),
+            ..
+        } => {
+            context.resize(size.width, size.height);
+        }
         Event::MainEventsCleared => {
             context
                 .draw_quad(
-                    Quad {
-                        points: [
-                            Vec3::new(0.0, 0.0, 0.0),
-                            Vec3::new(0.5, 0.0, 0.0),
-                            Vec3::new(0.5, 0.5, 0.0),
-                            Vec3::new(0.0, 0.5, 0.0),
-                        ],
-                    },
-                    [0.0, 1.0, 0.0, 1.0],
+                    Quad::rect(Vec2::new(320.0, 240.0), Vec2::new(160.0, 120.0)).with_colors([
+                        Vec4::new(1.0, 0.0, 0.0, 1.0),
+                        Vec4::new(0.0, 1.0, 0.0, 1.0),
+                        Vec4::new(0.0, 0.0, 1.0, 1.0),
+                        Vec4::new(1.0, 1.0, 1.0, 1.0),
+                    ]),
+                    [1.0, 1.0, 1.0, 1.0],
+                    [0.0, 0.0, 0.0, 1.0],
                 )
                 .expect("failed to clear screen");
         }
@@ -0,0 +1,79 @@
+extern crate rmge;
+extern crate winit;
+
+use rmge::geometry::{Mat4, Quad, TextureHandle, Vec2, Vec3, Vec4};
+use rmge::graphics::Filter;
+use rmge::scene::SceneNode;
+use rmge::Renderer;
+
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+
+fn square(position: Vec2, size: f32, texture: TextureHandle) -> Quad {
+    Quad::rect(position, Vec2::new(size, size)).with_texture(texture)
+}
+
+// a 2x2 checkerboard, sampled with `Filter::Nearest` to keep the edges sharp
+fn checkerboard(renderer: &mut Renderer) -> TextureHandle {
+    let (black, white) = ([0x00, 0x00, 0x00, 0xff], [0xff, 0xff, 0xff, 0xff]);
+    let pixels = [white, black, black, white].concat();
+    renderer
+        .add_texture(2, 2, &pixels, Filter::Nearest)
+        .expect("failed to upload texture")
+}
+
+fn main() {
+    let event_loop = EventLoop::new();
+
+    let wb = winit::window::WindowBuilder::new()
+        .with_min_inner_size(winit::dpi::Size::Logical(winit::dpi::LogicalSize::new(
+            64.0, 64.0,
+        )))
+        .with_inner_size(winit::dpi::Size::Physical(winit::dpi::PhysicalSize::new(
+            640, 480,
+        )))
+        .with_title("scene".to_string());
+    let window = wb.build(&event_loop).expect("failed to build window");
+
+    let mut renderer = Renderer::new(&window, "something").expect("failed to build context");
+
+    let checkerboard = checkerboard(&mut renderer);
+
+    {
+        let root = renderer.scene_mut().root_mut();
+        // the default camera works in pixels, with the origin at the top left
+        root.add_quad(square(Vec2::new(320.0, 240.0), 120.0, checkerboard));
+
+        let mut child = SceneNode::new(Mat4::new_translation(&Vec3::new(160.0, 120.0, 0.0)));
+        child.add_quad(square(Vec2::new(0.0, 0.0), 120.0, TextureHandle::WHITE));
+        // the top left quarter of the checkerboard, tinted red and rotated
+        child.add_quad(
+            Quad::rotated_rect(Vec2::new(260.0, 60.0), Vec2::new(120.0, 120.0), 0.5)
+                .with_texture(checkerboard)
+                .with_uv_rect(Vec2::new(0.0, 0.0), Vec2::new(0.5, 0.5))
+                .with_color(Vec4::new(1.0, 0.0, 0.0, 1.0)),
+        );
+        root.add_child(child);
+    }
+
+    event_loop.run(move |e, _, control_flow| match e {
+        Event::WindowEvent {
+            event: WindowEvent::CloseRequested,
+            ..
+        } => {
+            *control_flow = ControlFlow::Exit;
+        }
+        Event::WindowEvent {
+            event: WindowEvent::Resized(size),
+            ..
+        } => {
+            renderer.resize(size.width, size.height);
+        }
+        Event::MainEventsCleared => {
+            renderer
+                .draw_scene([0.0, 0.0, 0.0, 1.0])
+                .expect("failed to draw scene");
+        }
+        _ => {}
+    });
+}
@@ -0,0 +1,31 @@
+extern crate rmge;
+
+use rmge::geometry::{Quad, Vec2, Vec4};
+use rmge::Renderer;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 256;
+
+// renders a quad without opening a window and saves it as `headless.ppm`
+fn main() {
+    let mut renderer = Renderer::headless(WIDTH, HEIGHT).expect("failed to build context");
+
+    renderer.scene_mut().root_mut().add_quad(
+        Quad::rect(Vec2::new(64.0, 64.0), Vec2::new(128.0, 128.0))
+            .with_color(Vec4::new(1.0, 0.5, 0.0, 1.0)),
+    );
+    renderer
+        .draw_scene([0.0, 0.0, 0.0, 1.0])
+        .expect("failed to draw scene");
+
+    let pixels = renderer.read_pixels().expect("failed to read pixels");
+
+    let mut file = BufWriter::new(File::create("headless.ppm").expect("failed to create file"));
+    writeln!(file, "P6 {} {} 255", WIDTH, HEIGHT).expect("failed to write header");
+    for pixel in pixels.chunks_exact(4) {
+        file.write_all(&pixel[..3]).expect("failed to write pixels");
+    }
+}
@@ -3,7 +3,7 @@ use rmge::scene::{SceneNode, SceneTree};
 
 fn main() {
     let mut root = SceneNode::new(Mat4::identity());
-    let mut child1 = SceneNode::new(2.0 * Mat4::identity());
+    let child1 = SceneNode::new(2.0 * Mat4::identity());
     let mut child2 = SceneNode::new(3.0 * Mat4::identity());
     let child21 = SceneNode::new(Mat4::identity());
     let child22 = SceneNode::new(2.0 * Mat4::identity());
@@ -38,19 +38,19 @@ fn display_tree(tree: &SceneTree) {
     println!("{}root:", if root.is_modified() { "*" } else { "" });
     println!(
         "{}cache: {:?}",
-        star(&root.cache()),
+        star(root.cache()),
         *root.transform.diagonal()
     );
 
-    for (c1, cl1) in root.get_children().enumerate() {
+    for (c1, cl1) in root.get_children().iter().enumerate() {
         println!("\t{}c{}:", star(cl1), c1);
         println!(
             "\t{}cache: {:?}",
-            star(&cl1.cache()),
+            star(cl1.cache()),
             *cl1.transform.diagonal()
         );
 
-        for (c2, cl2) in cl1.get_children().enumerate() {
+        for (c2, cl2) in cl1.get_children().iter().enumerate() {
             println!(
                 "\t\t{}c{}{}, {}cache: {:?}",
                 star(cl2),
@@ -0,0 +1,88 @@
+extern crate rmge;
+extern crate winit;
+
+use rmge::geometry::{Rect, Vec2};
+use rmge::graphics::{Filter, TextureHandle};
+use rmge::Renderer;
+
+use log::LevelFilter;
+use simple_logger::SimpleLogger;
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+
+const SPRITES: usize = 5000;
+
+fn main() {
+    SimpleLogger::new()
+        .with_module_level("gfx_backend_vulkan", LevelFilter::Warn)
+        .init()
+        .unwrap();
+
+    let event_loop = EventLoop::new();
+
+    let window = winit::window::WindowBuilder::new()
+        .with_inner_size(winit::dpi::Size::Physical(winit::dpi::PhysicalSize::new(
+            640, 480,
+        )))
+        .with_title("sprites".to_string())
+        .build(&event_loop)
+        .expect("failed to build window");
+
+    let mut renderer = Renderer::new(&window, "sprites").expect("failed to create renderer");
+
+    let (black, white) = ([0x00, 0x00, 0x00, 0xff], [0xff, 0xff, 0xff, 0xff]);
+    let checkerboard = renderer
+        .add_texture(
+            2,
+            2,
+            &[white, black, black, white].concat(),
+            Filter::Nearest,
+        )
+        .expect("failed to add texture");
+
+    let start = std::time::Instant::now();
+    event_loop.run(move |e, _, control_flow| match e {
+        Event::WindowEvent {
+            event: WindowEvent::CloseRequested,
+            ..
+        } => {
+            *control_flow = ControlFlow::Exit;
+        }
+        Event::WindowEvent {
+            event: WindowEvent::Resized(size),
+            ..
+        } => {
+            renderer.resize(size.width, size.height);
+        }
+        Event::MainEventsCleared => {
+            let time = start.elapsed().as_secs_f32();
+
+            let mut frame = renderer.begin_frame([0.0, 0.0, 0.0, 1.0]);
+            for i in 0..SPRITES {
+                // a spiral of spinning sprites, alternating between textures
+                let angle = i as f32 * 0.1 + time;
+                let distance = i as f32 * 0.05;
+                let center = Vec2::new(
+                    320.0 + angle.cos() * distance,
+                    240.0 + angle.sin() * distance,
+                );
+                let texture = if i % 2 == 0 {
+                    checkerboard
+                } else {
+                    TextureHandle::WHITE
+                };
+                let hue = i as f32 / SPRITES as f32;
+                frame.draw_sprite(
+                    texture,
+                    Rect::new(center.x - 4.0, center.y - 4.0, 8.0, 8.0),
+                    None,
+                    [hue, 1.0 - hue, 0.5, 1.0],
+                    time * 2.0,
+                    Vec2::new(4.0, 4.0),
+                );
+            }
+            frame.end_frame().expect("failed to draw sprites");
+        }
+        _ => {}
+    });
+}
@@ -12,6 +12,9 @@ pub enum Error {
     SemaphoreCreationError,
     RenderPassCreationError,
     ImageViewCreationError,
+    ImageCreationError,
+    ImageBindError,
+    SamplerCreationError,
     FramebufferCreationError,
     FenceError(FenceOp),
     SemaphoreError,
@@ -24,11 +27,25 @@ pub enum Error {
     MissingCommandPool(usize),
     MissingResourceManager(usize),
     MissingPipeline(usize),
+    MissingSurface,
+    MissingOffscreenTarget,
+    EmptyExtent(u32, u32),
     ShaderCreation(ShaderKind, gfx_hal::device::ShaderError),
+    InvalidSpirv(ShaderKind, SpirvError),
     DescriptorSetLayoutCreation,
     PipelineLayoutCreation,
     MissingDescriptorSetLayout,
+    DescriptorPoolCreation,
+    DescriptorSetAllocation,
     PipelineCreation,
+    InvalidPipelineDesc(PipelineDescError),
+    CapacityExceeded(BufferKind),
+    TextureDataLength(usize, usize),
+    EmptyTexture(u32, u32),
+    AtlasImageTooLarge(u32, u32),
+    UnsupportedSampleCount(u8),
+    NoMatchingAdapter,
+    InvalidSceneEdit(SceneError),
     IOError(std::io::Error),
 }
 
@@ -46,6 +63,9 @@ impl Display for Error {
             SemaphoreCreationError => "Failed creating a semaphore".to_string(),
             RenderPassCreationError => "Failed creating a render pass".to_string(),
             ImageViewCreationError => "Failed creating an image view".to_string(),
+            ImageCreationError => "Failed creating an image".to_string(),
+            ImageBindError => "Failed binding an image to memory".to_string(),
+            SamplerCreationError => "Failed creating a sampler".to_string(),
             FramebufferCreationError => "Failed creating a framebuffer".to_string(),
             FenceError(op) => format!(
                 "Failed trying to {} a fence",
@@ -70,6 +90,8 @@ impl Display for Error {
                     BufferKind::Matrix => "matrix",
                     BufferKind::Quad => "quad",
                     BufferKind::Image => "image",
+                    BufferKind::Region => "region",
+                    BufferKind::Sprite => "sprite",
                 }
             ),
             MemoryError(err_kind, mem_kind) => {
@@ -78,6 +100,8 @@ impl Display for Error {
                     MemoryKind::Geometry => "geometry",
                     MemoryKind::Index => "index",
                     MemoryKind::Image => "image",
+                    MemoryKind::Region => "region",
+                    MemoryKind::Sprite => "sprite",
                 };
                 match err_kind {
                     crate::error::MemoryError::AllocationError(_) => {
@@ -91,18 +115,82 @@ impl Display for Error {
                     }
                 }
             }
-            MissingSwapchain(idx) => format!("Failed to retreive swapchain at index {}", 0),
-            MissingCommandPool(idx) => format!("Failed to retreive command pool at index {}", 0),
+            MissingSwapchain(idx) => format!("Failed to retreive swapchain at index {}", idx),
+            MissingCommandPool(idx) => format!("Failed to retreive command pool at index {}", idx),
             MissingResourceManager(idx) => {
-                format!("Failed to retreive resource manager at index {}", 0)
+                format!("Failed to retreive resource manager at index {}", idx)
+            }
+            MissingPipeline(idx) => format!("Failed to retreive graphics pipeline at index {}", idx),
+            MissingSurface => "The context has no surface to present to".to_string(),
+            MissingOffscreenTarget => {
+                "The context has no offscreen target to read from".to_string()
+            }
+            EmptyExtent(width, height) => {
+                format!("Can't render to a {}x{} target", width, height)
             }
-            MissingPipeline(idx) => format!("Failed to retreive graphics pipeline at index {}", 0),
             PipelineCreation => "Failed to create pipeline".to_string(),
+            InvalidPipelineDesc(e) => match e {
+                PipelineDescError::DescriptorSets => {
+                    "The descriptor sets of a pipeline have to match the built-in ones".to_string()
+                }
+                PipelineDescError::PushConstants => {
+                    "Push constant ranges have to be aligned and come after the camera".to_string()
+                }
+                PipelineDescError::PushConstantStages => {
+                    "Push constant ranges can't share stages with each other or the camera"
+                        .to_string()
+                }
+                PipelineDescError::PushConstantsSize(max) => {
+                    format!("Push constant ranges have to end within {} bytes", max)
+                }
+            },
             DescriptorSetLayoutCreation => "Failed to create descriptor set layout".to_string(),
             PipelineLayoutCreation => "Failed to create pipeline layout".to_string(),
             MissingDescriptorSetLayout => {
                 "Missing descriptor set trying to add pipeline layout".to_string()
             }
+            DescriptorPoolCreation => "Failed to create descriptor pool".to_string(),
+            DescriptorSetAllocation => "Failed to allocate descriptor set".to_string(),
+            CapacityExceeded(kind) => format!(
+                "Ran out of space in the {} buffer",
+                match kind {
+                    BufferKind::Instance => "instance",
+                    BufferKind::Staging => "staging",
+                    BufferKind::Index => "index",
+                    BufferKind::Matrix => "matrix",
+                    BufferKind::Quad => "quad",
+                    BufferKind::Image => "image",
+                    BufferKind::Region => "region",
+                    BufferKind::Sprite => "sprite",
+                }
+            ),
+            TextureDataLength(expected, actual) => format!(
+                "Expected {} bytes of texture data, got {}",
+                expected, actual
+            ),
+            EmptyTexture(width, height) => {
+                format!("A {}x{} texture has no pixels to upload", width, height)
+            }
+            AtlasImageTooLarge(width, height) => {
+                format!("A {}x{} image doesn't fit in an atlas page", width, height)
+            }
+            UnsupportedSampleCount(samples) => {
+                format!(
+                    "The adapter can't render with {} samples per pixel",
+                    samples
+                )
+            }
+            NoMatchingAdapter => "None of the preferred adapters could be opened".to_string(),
+            InvalidSceneEdit(e) => match e {
+                SceneError::MissingNode => "The node isn't part of the scene tree".to_string(),
+                SceneError::RootNode => "The root of a scene tree can't be moved".to_string(),
+                SceneError::IntoOwnSubtree => {
+                    "A node can't be moved into its own subtree".to_string()
+                }
+                SceneError::ChildIndex(index) => {
+                    format!("There's no place for a child at index {}", index)
+                }
+            },
             ShaderCreation(kind, e) => format!(
                 "Failed to create {} shader ({})",
                 match kind {
@@ -111,6 +199,20 @@ impl Display for Error {
                 },
                 e
             ),
+            InvalidSpirv(kind, e) => format!(
+                "The {} shader isn't valid SPIR-V ({})",
+                match kind {
+                    ShaderKind::Vertex => "vertex",
+                    ShaderKind::Fragment => "fragment",
+                },
+                match e {
+                    SpirvError::Unaligned(len) => {
+                        format!("{} bytes isn't a whole number of words", len)
+                    }
+                    SpirvError::MissingHeader => "too short to hold a header".to_string(),
+                    SpirvError::BadMagic(magic) => format!("bad magic number {:#010x}", magic),
+                }
+            ),
             IOError(e) => format!("IO error: {}", e),
         };
 
@@ -131,12 +233,36 @@ impl error::Error for Error {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ShaderKind {
     Vertex,
     Fragment,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpirvError {
+    // the length in bytes, which has to be a multiple of 4
+    Unaligned(usize),
+    MissingHeader,
+    BadMagic(u32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SceneError {
+    MissingNode,
+    RootNode,
+    IntoOwnSubtree,
+    ChildIndex(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PipelineDescError {
+    DescriptorSets,
+    PushConstants,
+    PushConstantStages,
+    PushConstantsSize(u32),
+}
+
 #[derive(Debug)]
 pub enum FenceOp {
     Reset,
@@ -157,6 +283,8 @@ pub enum MemoryKind {
     Geometry,
     Index,
     Image,
+    Region,
+    Sprite,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -167,6 +295,8 @@ pub enum BufferKind {
     Staging,
     Image,
     Index,
+    Region,
+    Sprite,
 }
 
 #[derive(Debug)]
@@ -179,6 +309,7 @@ pub enum BufferOp {
 pub enum SwapchainError {
     NoImageViews,
     ImageAcquireError,
+    OutOfDate,
     NoPresentMode,
     CreationError(gfx_hal::window::CreationError),
 }
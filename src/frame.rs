@@ -0,0 +1,173 @@
+use crate::error::Error;
+use crate::geometry::{Mat4, Quad, Quad2d, Rect, Vec2};
+use crate::graphics::{DrawList, MaterialHandle, TextureHandle};
+use crate::scene::SceneTree;
+use crate::Renderer;
+
+/// A frame being recorded, started with `Renderer::begin_frame`. Nothing is
+/// drawn until `end_frame`, which submits everything at once, drawn in the
+/// order it was recorded. Dropping a frame without ending it discards it.
+pub struct Frame<'a> {
+    renderer: &'a mut Renderer,
+    draws: DrawList,
+    sprite_material: MaterialHandle,
+}
+
+impl<'a> Frame<'a> {
+    pub(crate) fn new(renderer: &'a mut Renderer, clear_color: [f32; 4]) -> Self {
+        Frame {
+            renderer,
+            draws: DrawList::new(clear_color),
+            sprite_material: MaterialHandle::DEFAULT,
+        }
+    }
+
+    /// Draws the renderer's scene tree, only uploading what changed since
+    /// it was last drawn.
+    pub fn draw_scene(&mut self) {
+        self.draws.draw_scene();
+    }
+
+    /// Draws another scene tree through the renderer's camera. All of it is
+    /// uploaded every frame it's drawn, so anything that stays the same
+    /// from frame to frame is better off in the renderer's scene tree.
+    pub fn draw_tree(&mut self, tree: &mut SceneTree) {
+        tree.recompute_caches();
+        let context = &self.renderer.context;
+        let batches = tree.draw_batches(
+            &self.renderer.camera,
+            |material| context.is_transparent(material),
+            |texture| context.texture_index(texture),
+        );
+        self.draws.push_tree(tree, &batches);
+    }
+
+    /// Draws a single quad through the camera, with its colors multiplied
+    /// by `color`.
+    pub fn draw_quad(&mut self, quad: Quad, color: [f32; 4]) {
+        self.draws.push_quads(
+            MaterialHandle::DEFAULT,
+            Mat4::identity(),
+            Some(quad.tinted(color.into())),
+        );
+    }
+
+    /// Draws `quads` transformed by `transform` with a material added
+    /// through `Renderer::add_material`, or the default one. Unlike in a
+    /// scene tree, transparent quads aren't sorted, so they should be drawn
+    /// back to front.
+    pub fn draw_quads(&mut self, material: MaterialHandle, transform: Mat4, quads: &[Quad]) {
+        self.draws
+            .push_quads(material, transform, quads.iter().copied());
+    }
+
+    /// Draws `texture`, or the part of it `src` covers in pixels, stretched
+    /// over `dst`, with its colors multiplied by `color`. It's rotated
+    /// `rotation` radians around `origin`, which is relative to the top
+    /// left of `dst`.
+    ///
+    /// Sprites are written into a ring buffer and drawn as few instanced
+    /// draws as possible: one per run of sprites that isn't interrupted by
+    /// other draws, a change of sprite material or a change of texture.
+    /// Regions of the same atlas page count as the same texture, so atlases
+    /// keep runs together.
+    pub fn draw_sprite(
+        &mut self,
+        texture: TextureHandle,
+        dst: Rect,
+        src: Option<Rect>,
+        color: [f32; 4],
+        rotation: f32,
+        origin: Vec2,
+    ) {
+        let size = self.renderer.context.texture_size(texture);
+        let quad = sprite_quad(texture, dst, src, size, color, rotation, origin);
+        self.draws.push_sprite(self.sprite_material, quad);
+    }
+
+    /// Sets the material the sprites drawn from now on use, which starts out
+    /// as the default one.
+    pub fn set_sprite_material(&mut self, material: MaterialHandle) {
+        self.sprite_material = material;
+    }
+
+    /// Submits everything recorded and presents the result, or renders it
+    /// into the offscreen image of a headless renderer.
+    pub fn end_frame(self) -> Result<(), Error> {
+        let Renderer {
+            context,
+            scenetree,
+            camera,
+        } = self.renderer;
+        context.render(Some(scenetree), camera, &self.draws)
+    }
+}
+
+// the quad `Frame::draw_sprite` draws, with `src` turned into uvs relative
+// to the `size` of the region `texture` refers to
+fn sprite_quad(
+    texture: TextureHandle,
+    dst: Rect,
+    src: Option<Rect>,
+    size: Option<Vec2>,
+    color: [f32; 4],
+    rotation: f32,
+    origin: Vec2,
+) -> Quad {
+    let quad =
+        Quad::from(Quad2d::rect(dst.position, dst.size).rotated(dst.position + origin, rotation))
+            .with_texture(texture)
+            .with_color(color.into());
+    match (src, size) {
+        (Some(src), Some(size)) => quad.with_uv_rect(
+            src.position.component_div(&size),
+            (src.position + src.size).component_div(&size),
+        ),
+        _ => quad,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+
+    fn sprite(src: Option<Rect>, size: Option<Vec2>) -> Quad {
+        let dst = Rect::new(10.0, 20.0, 4.0, 4.0);
+        sprite_quad(
+            TextureHandle::new(1),
+            dst,
+            src,
+            size,
+            [1.0; 4],
+            0.0,
+            Vec2::zeros(),
+        )
+    }
+
+    #[test]
+    fn sprite_src_is_divided_by_the_region_size() {
+        let src = Rect::new(2.0, 4.0, 2.0, 4.0);
+        let quad = sprite(Some(src), Some(Vec2::new(8.0, 8.0)));
+        assert_eq!(
+            quad.uvs,
+            [
+                Vec2::new(0.25, 0.5),
+                Vec2::new(0.5, 0.5),
+                Vec2::new(0.5, 1.0),
+                Vec2::new(0.25, 1.0),
+            ]
+        );
+        assert_eq!(quad.points[0], Vec3::new(10.0, 20.0, 0.0));
+        assert_eq!(quad.points[2], Vec3::new(14.0, 24.0, 0.0));
+    }
+
+    #[test]
+    fn sprites_without_src_cover_the_whole_region() {
+        let whole = Quad::rect(Vec2::zeros(), Vec2::new(1.0, 1.0)).uvs;
+        assert_eq!(sprite(None, Some(Vec2::new(8.0, 8.0))).uvs, whole);
+        // unknown textures have no size to divide by
+        let src = Rect::new(2.0, 4.0, 2.0, 4.0);
+        assert_eq!(sprite(Some(src), None).uvs, whole);
+    }
+}
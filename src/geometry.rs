@@ -1,29 +1,721 @@
-use nalgebra::base::{Matrix4, Vector2, Vector3};
+use nalgebra::base::{Matrix3, Matrix4, Vector2, Vector3, Vector4};
+use nalgebra::geometry::{
+    Orthographic3, Perspective3, Point3, Rotation3, Translation3, UnitQuaternion,
+};
+
+pub use crate::graphics::TextureHandle;
 
 pub type Vec2 = Vector2<f32>;
 pub type Vec3 = Vector3<f32>;
+pub type Vec4 = Vector4<f32>;
 pub type Mat4 = Matrix4<f32>;
+pub type Rotation = UnitQuaternion<f32>;
+
+// the uvs of the corners when a quad shows the whole texture
+const FULL_UVS: [[f32; 2]; 4] = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
 
-#[derive(Debug, Copy, Clone)]
+/// A quad, which is uploaded as is, so the layout has to match the vertex
+/// attributes. The uvs are relative to the region `texture` refers to, and
+/// the colors are multiplied with whatever is sampled from it.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Quad {
     pub points: [Vec3; 4],
+    pub uvs: [Vec2; 4],
+    pub colors: [Vec4; 4],
+    pub texture: TextureHandle,
+}
+
+impl Quad {
+    /// A white, untextured quad with the given corners, which should go
+    /// around the quad in order.
+    pub fn new(points: [Vec3; 4]) -> Self {
+        let uv = |[u, v]: [f32; 2]| Vec2::new(u, v);
+        Quad {
+            points,
+            uvs: [
+                uv(FULL_UVS[0]),
+                uv(FULL_UVS[1]),
+                uv(FULL_UVS[2]),
+                uv(FULL_UVS[3]),
+            ],
+            colors: [Vec4::repeat(1.0); 4],
+            texture: TextureHandle::WHITE,
+        }
+    }
+
+    /// An axis-aligned rectangle at z = 0, `position` being the corner the
+    /// top left of the texture ends up at.
+    pub fn rect(position: Vec2, size: Vec2) -> Self {
+        Quad2d::rect(position, size).into()
+    }
+
+    /// A rectangle at z = 0 centered on `center`, rotated `angle` radians
+    /// around it.
+    pub fn rotated_rect(center: Vec2, size: Vec2, angle: f32) -> Self {
+        Quad2d::rotated_rect(center, size, angle).into()
+    }
+
+    pub fn with_texture(mut self, texture: TextureHandle) -> Self {
+        self.texture = texture;
+        self
+    }
+
+    /// Shows only the part of the texture between `uv_min` and `uv_max`.
+    pub fn with_uv_rect(mut self, uv_min: Vec2, uv_max: Vec2) -> Self {
+        for (uv, [u, v]) in self.uvs.iter_mut().zip(FULL_UVS.iter()) {
+            *uv = Vec2::new(
+                uv_min.x + u * (uv_max.x - uv_min.x),
+                uv_min.y + v * (uv_max.y - uv_min.y),
+            );
+        }
+        self
+    }
+
+    pub fn with_color(mut self, color: Vec4) -> Self {
+        self.colors = [color; 4];
+        self
+    }
+
+    pub fn with_colors(mut self, colors: [Vec4; 4]) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    /// Multiplies the color of every corner with `tint`.
+    pub fn tinted(mut self, tint: Vec4) -> Self {
+        for color in &mut self.colors {
+            *color = color.component_mul(&tint);
+        }
+        self
+    }
+}
+
+impl Default for Quad {
+    fn default() -> Self {
+        Quad::new([Vec3::zeros(); 4])
+    }
+}
+
+/// An axis-aligned rectangle, `position` being its top left corner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub position: Vec2,
+    pub size: Vec2,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Rect {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Rect {
+            position: Vec2::new(x, y),
+            size: Vec2::new(width, height),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Quad2d {
     pub points: [Vec2; 4],
 }
 
+impl Quad2d {
+    pub fn rect(position: Vec2, size: Vec2) -> Self {
+        Quad2d {
+            points: [
+                position,
+                position + Vec2::new(size.x, 0.0),
+                position + size,
+                position + Vec2::new(0.0, size.y),
+            ],
+        }
+    }
+
+    pub fn rotated_rect(center: Vec2, size: Vec2, angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let half = size / 2.0;
+        let corner = |x: f32, y: f32| center + Vec2::new(x * cos - y * sin, x * sin + y * cos);
+        Quad2d {
+            points: [
+                corner(-half.x, -half.y),
+                corner(half.x, -half.y),
+                corner(half.x, half.y),
+                corner(-half.x, half.y),
+            ],
+        }
+    }
+
+    /// Rotates every corner `angle` radians around `pivot`.
+    pub fn rotated(mut self, pivot: Vec2, angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        for point in &mut self.points {
+            let offset = *point - pivot;
+            *point = pivot
+                + Vec2::new(
+                    offset.x * cos - offset.y * sin,
+                    offset.x * sin + offset.y * cos,
+                );
+        }
+        self
+    }
+}
+
 impl From<Quad2d> for Quad {
     fn from(quad: Quad2d) -> Quad {
         let a = quad.points;
-        Quad {
-            points: [
-                Vec3::new(a[0][0], a[0][1], 0.0),
-                Vec3::new(a[1][0], a[1][1], 0.0),
-                Vec3::new(a[2][0], a[2][1], 0.0),
-                Vec3::new(a[3][0], a[3][1], 0.0),
-            ],
+        Quad::new([
+            Vec3::new(a[0][0], a[0][1], 0.0),
+            Vec3::new(a[1][0], a[1][1], 0.0),
+            Vec3::new(a[2][0], a[2][1], 0.0),
+            Vec3::new(a[3][0], a[3][1], 0.0),
+        ])
+    }
+}
+
+/// An axis-aligned box. It's empty when `min` is greater than `max` on any
+/// axis, like the one `empty` returns, which every other box contains.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vec3::repeat(f32::INFINITY),
+            max: Vec3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    /// The smallest box containing all of `points`.
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        points.into_iter().fold(Aabb::empty(), |aabb, point| {
+            Aabb::new(aabb.min.inf(&point), aabb.max.sup(&point))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    /// The smallest box containing both boxes.
+    pub fn union(&self, other: &Aabb) -> Self {
+        Aabb::new(self.min.inf(&other.min), self.max.sup(&other.max))
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+            && self.min.z <= other.max.z
+            && other.min.z <= self.max.z
+    }
+
+    pub fn contains(&self, point: &Vec3) -> bool {
+        (0..3).all(|axis| self.min[axis] <= point[axis] && point[axis] <= self.max[axis])
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    /// The smallest box containing this one after transforming it by
+    /// `matrix`, which can be bigger than the transformed box itself.
+    pub fn transformed(&self, matrix: &Mat4) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+        Aabb::from_points((0..8).map(|corner| {
+            let pick = |axis: usize| {
+                if corner & (1 << axis) == 0 {
+                    self.min[axis]
+                } else {
+                    self.max[axis]
+                }
+            };
+            matrix
+                .transform_point(&Point3::new(pick(0), pick(1), pick(2)))
+                .coords
+        }))
+    }
+}
+
+/// The volume a view-projection matrix shows, as the planes bounding it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frustum {
+    // each plane's normal points inwards, so a point is on the inside when
+    // `plane.xyz().dot(point) + plane.w` isn't negative
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// The volume ending up in Vulkan's clip space, where x and y go from
+    /// -w to w and depth from 0 to w.
+    pub fn from_matrix(view_projection: &Mat4) -> Self {
+        let row = |i| view_projection.row(i).transpose();
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        Frustum {
+            planes: [w + x, w - x, w + y, w - y, z, w - z],
+        }
+    }
+
+    /// Whether any part of `aabb` might be inside. Boxes near the corners
+    /// can be reported inside without being so, but never the other way
+    /// around.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        !aabb.is_empty()
+            && self.planes.iter().all(|plane| {
+                // the corner furthest along the plane's normal
+                let corner = Vec3::new(
+                    if plane.x > 0.0 {
+                        aabb.max.x
+                    } else {
+                        aabb.min.x
+                    },
+                    if plane.y > 0.0 {
+                        aabb.max.y
+                    } else {
+                        aabb.min.y
+                    },
+                    if plane.z > 0.0 {
+                        aabb.max.z
+                    } else {
+                        aabb.min.z
+                    },
+                );
+                plane.xyz().dot(&corner) + plane.w >= 0.0
+            })
+    }
+}
+
+/// A transform for flat scenes: scaled by `scale` and rotated `rotation`
+/// radians around `pivot`, which is then moved to `translation`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform2D {
+    pub translation: Vec2,
+    pub rotation: f32,
+    pub scale: Vec2,
+    pub pivot: Vec2,
+}
+
+impl Transform2D {
+    pub fn to_matrix(&self) -> Mat4 {
+        Transform3D::from(*self).to_matrix()
+    }
+}
+
+impl Default for Transform2D {
+    fn default() -> Self {
+        Transform2D {
+            translation: Vec2::zeros(),
+            rotation: 0.0,
+            scale: Vec2::new(1.0, 1.0),
+            pivot: Vec2::zeros(),
+        }
+    }
+}
+
+/// Scaled by `scale` and turned by `rotation` around `pivot`, which is then
+/// moved to `translation`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform3D {
+    pub translation: Vec3,
+    pub rotation: Rotation,
+    pub scale: Vec3,
+    pub pivot: Vec3,
+}
+
+impl Transform3D {
+    pub fn to_matrix(&self) -> Mat4 {
+        Mat4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * Mat4::new_nonuniform_scaling(&self.scale)
+            * Mat4::new_translation(&-self.pivot)
+    }
+
+    /// Splits `matrix` into a translation, rotation and scale, with the
+    /// pivot at the origin. Shearing and projection are lost along the way.
+    pub fn from_matrix(matrix: &Mat4) -> Self {
+        let column = |i| Vec3::new(matrix[(0, i)], matrix[(1, i)], matrix[(2, i)]);
+        let (x, y, z) = (column(0), column(1), column(2));
+        let mut scale = Vec3::new(x.norm(), y.norm(), z.norm());
+        // a mirrored matrix can't be a rotation, so one of the axes gets a
+        // negative scale instead
+        if x.cross(&y).dot(&z) < 0.0 {
+            scale.x = -scale.x;
+        }
+
+        let rotation = if scale.iter().all(|axis| *axis != 0.0) {
+            let axes = Matrix3::from_columns(&[x / scale.x, y / scale.y, z / scale.z]);
+            Rotation::from_rotation_matrix(&Rotation3::from_matrix_unchecked(axes))
+        } else {
+            Rotation::identity()
+        };
+
+        Transform3D {
+            translation: column(3),
+            rotation,
+            scale,
+            pivot: Vec3::zeros(),
+        }
+    }
+
+    /// The rotation around the z axis in radians, which is all of it for
+    /// transforms of flat scenes.
+    pub fn angle_2d(&self) -> f32 {
+        let x = self.rotation * Vec3::x();
+        x.y.atan2(x.x)
+    }
+}
+
+impl Default for Transform3D {
+    fn default() -> Self {
+        Transform3D {
+            translation: Vec3::zeros(),
+            rotation: Rotation::identity(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            pivot: Vec3::zeros(),
+        }
+    }
+}
+
+impl From<Transform2D> for Transform3D {
+    fn from(transform: Transform2D) -> Self {
+        Transform3D {
+            translation: transform.translation.push(0.0),
+            rotation: Rotation::from_axis_angle(&Vec3::z_axis(), transform.rotation),
+            scale: transform.scale.push(1.0),
+            pivot: transform.pivot.push(0.0),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    /// Pixel coordinates, with the origin in the top left corner and y
+    /// pointing down. Only depths between `near` and `far` are visible.
+    Orthographic { near: f32, far: f32 },
+    /// Looks down the camera's -z axis, with y pointing up. `fov_y` is the
+    /// vertical field of view in radians.
+    Perspective { fov_y: f32, near: f32, far: f32 },
+}
+
+// nalgebra's projections are made for OpenGL, whose clip space has y
+// pointing up and depths between -1 and 1 rather than 0 and 1
+#[rustfmt::skip]
+fn opengl_to_vulkan() -> Mat4 {
+    Mat4::new(
+        1.0,  0.0, 0.0, 0.0,
+        0.0, -1.0, 0.0, 0.0,
+        0.0,  0.0, 0.5, 0.5,
+        0.0,  0.0, 0.0, 1.0,
+    )
+}
+
+/// What the scene is seen through. The camera's view-projection matrix is
+/// applied after the transforms of the nodes.
+///
+/// Orthographic cameras are panned by moving `position`, which is the point
+/// that ends up in the top left corner, and rotate and zoom around the center
+/// of the screen. Perspective cameras sit at `position`, turned by `rotation`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Camera {
+    pub projection: Projection,
+    pub position: Vec3,
+    pub rotation: Rotation,
+    pub zoom: f32,
+}
+
+impl Camera {
+    pub fn orthographic() -> Self {
+        Camera {
+            projection: Projection::Orthographic {
+                near: -1.0,
+                far: 1.0,
+            },
+            position: Vec3::zeros(),
+            rotation: Rotation::identity(),
+            zoom: 1.0,
+        }
+    }
+
+    pub fn perspective(fov_y: f32, near: f32, far: f32) -> Self {
+        Camera {
+            projection: Projection::Perspective { fov_y, near, far },
+            ..Camera::orthographic()
         }
     }
+
+    /// Moves the camera along its own axes.
+    pub fn pan(&mut self, offset: Vec3) {
+        self.position += self.rotation * offset;
+    }
+
+    /// Multiplies the zoom by `factor`, which narrows the field of view of
+    /// perspective cameras.
+    pub fn zoom_by(&mut self, factor: f32) {
+        self.zoom *= factor;
+    }
+
+    /// Rolls the camera `angle` radians around the direction it looks in.
+    pub fn rotate(&mut self, angle: f32) {
+        self.rotation *= Rotation::from_axis_angle(&Vec3::z_axis(), angle);
+    }
+
+    /// Turns the camera towards `target`, keeping `up` pointing up.
+    pub fn look_at(&mut self, target: Vec3, up: Vec3) {
+        // the camera looks down its -z axis
+        self.rotation = Rotation::face_towards(&(self.position - target), &up);
+    }
+
+    pub fn view(&self, width: f32, height: f32) -> Mat4 {
+        let inverse_rotation = self.rotation.inverse().to_homogeneous();
+        match self.projection {
+            Projection::Orthographic { .. } => {
+                let center = Vec3::new(width / 2.0, height / 2.0, 0.0);
+                Mat4::new_translation(&center)
+                    * Mat4::new_nonuniform_scaling(&Vec3::new(self.zoom, self.zoom, 1.0))
+                    * inverse_rotation
+                    * Mat4::new_translation(&(-center - self.position))
+            }
+            Projection::Perspective { .. } => {
+                inverse_rotation * Translation3::from(-self.position).to_homogeneous()
+            }
+        }
+    }
+
+    pub fn projection(&self, width: f32, height: f32) -> Mat4 {
+        let projection = match self.projection {
+            Projection::Orthographic { near, far } => {
+                Orthographic3::new(0.0, width, height, 0.0, near, far).to_homogeneous()
+            }
+            Projection::Perspective { fov_y, near, far } => {
+                Perspective3::new(width / height, fov_y / self.zoom, near, far).to_homogeneous()
+            }
+        };
+        opengl_to_vulkan() * projection
+    }
+
+    /// How far in front of the camera `point` is, along the direction it
+    /// looks in. Only meant for comparing points, since the scale depends on
+    /// the projection.
+    pub fn depth(&self, point: &Vec3) -> f32 {
+        -(self.rotation.inverse() * (point - self.position)).z
+    }
+
+    /// The matrix taking world coordinates to clip space, for a viewport of
+    /// `width` by `height` pixels.
+    pub fn view_projection(&self, width: f32, height: f32) -> Mat4 {
+        self.projection(width, height) * self.view(width, height)
+    }
+
+    /// What the camera shows of the world in a viewport of `width` by
+    /// `height` pixels.
+    pub fn frustum(&self, width: f32, height: f32) -> Frustum {
+        Frustum::from_matrix(&self.view_projection(width, height))
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::orthographic()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec2, b: Vec2) {
+        assert!((a - b).norm() < 1e-6, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn rect_corners_go_around() {
+        let quad = Quad::rect(Vec2::new(1.0, 2.0), Vec2::new(3.0, 4.0));
+        assert_eq!(
+            quad.points,
+            [
+                Vec3::new(1.0, 2.0, 0.0),
+                Vec3::new(4.0, 2.0, 0.0),
+                Vec3::new(4.0, 6.0, 0.0),
+                Vec3::new(1.0, 6.0, 0.0),
+            ]
+        );
+        assert_eq!(quad.uvs[2], Vec2::new(1.0, 1.0));
+    }
+
+    #[test]
+    fn rotated_rect_rotates_around_the_center() {
+        let quad = Quad2d::rotated_rect(
+            Vec2::new(1.0, 1.0),
+            Vec2::new(2.0, 4.0),
+            std::f32::consts::FRAC_PI_2,
+        );
+        assert_close(quad.points[0], Vec2::new(3.0, 0.0));
+        assert_close(quad.points[1], Vec2::new(3.0, 2.0));
+        assert_close(quad.points[2], Vec2::new(-1.0, 2.0));
+        assert_close(quad.points[3], Vec2::new(-1.0, 0.0));
+    }
+
+    #[test]
+    fn rotated_keeps_the_pivot_in_place() {
+        let quad = Quad2d::rect(Vec2::new(0.0, 0.0), Vec2::new(2.0, 4.0))
+            .rotated(Vec2::new(1.0, 2.0), std::f32::consts::PI);
+        assert_close(quad.points[0], Vec2::new(2.0, 4.0));
+        assert_close(quad.points[2], Vec2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn uv_rect_maps_the_corners() {
+        let quad = Quad::default().with_uv_rect(Vec2::new(0.5, 0.25), Vec2::new(1.0, 0.75));
+        assert_eq!(
+            quad.uvs,
+            [
+                Vec2::new(0.5, 0.25),
+                Vec2::new(1.0, 0.25),
+                Vec2::new(1.0, 0.75),
+                Vec2::new(0.5, 0.75),
+            ]
+        );
+    }
+
+    #[test]
+    fn transformed_boxes_contain_every_corner() {
+        let aabb = Aabb::from_points(vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 1.0, 0.0)]);
+        let rotated = aabb.transformed(&Mat4::new_rotation(Vec3::new(
+            0.0,
+            0.0,
+            std::f32::consts::FRAC_PI_2,
+        )));
+
+        assert_close3(rotated.min, Vec3::new(-1.0, 0.0, 0.0));
+        assert_close3(rotated.max, Vec3::new(0.0, 2.0, 0.0));
+        assert!(Aabb::empty().transformed(&Mat4::identity()).is_empty());
+        assert_eq!(Aabb::empty().union(&aabb), aabb);
+        assert!(!aabb.intersects(&Aabb::empty()));
+        assert!(aabb.intersects(&rotated));
+    }
+
+    #[test]
+    fn transforms_turn_around_the_pivot() {
+        let transform = Transform2D {
+            translation: Vec2::new(10.0, 0.0),
+            rotation: std::f32::consts::FRAC_PI_2,
+            scale: Vec2::new(2.0, 1.0),
+            pivot: Vec2::new(1.0, 1.0),
+        };
+        let matrix = transform.to_matrix();
+
+        assert_close3(
+            clip(matrix, Vec3::new(1.0, 1.0, 0.0)),
+            Vec3::new(10.0, 0.0, 0.0),
+        );
+        // one to the right of the pivot, scaled by 2 and turned to point down
+        assert_close3(
+            clip(matrix, Vec3::new(2.0, 1.0, 0.0)),
+            Vec3::new(10.0, 2.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn matrices_split_back_into_transforms() {
+        let transform = Transform3D {
+            translation: Vec3::new(1.0, 2.0, 3.0),
+            rotation: Rotation::from_axis_angle(&Vec3::z_axis(), 0.5),
+            scale: Vec3::new(-2.0, 3.0, 4.0),
+            pivot: Vec3::zeros(),
+        };
+        let split = Transform3D::from_matrix(&transform.to_matrix());
+
+        assert_close3(split.translation, transform.translation);
+        assert_close3(split.scale, transform.scale);
+        assert!((split.angle_2d() - 0.5).abs() < 1e-5);
+    }
+
+    fn clip(matrix: Mat4, point: Vec3) -> Vec3 {
+        let clip = matrix * point.push(1.0);
+        clip.xyz() / clip.w
+    }
+
+    fn assert_close3(a: Vec3, b: Vec3) {
+        assert!((a - b).norm() < 1e-5, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn orthographic_maps_pixels_to_the_screen() {
+        let matrix = Camera::orthographic().view_projection(640.0, 480.0);
+        assert_close3(clip(matrix, Vec3::zeros()), Vec3::new(-1.0, -1.0, 0.5));
+        assert_close3(
+            clip(matrix, Vec3::new(640.0, 480.0, 0.0)),
+            Vec3::new(1.0, 1.0, 0.5),
+        );
+    }
+
+    #[test]
+    fn orthographic_zooms_and_rotates_around_the_center() {
+        let mut camera = Camera::orthographic();
+        camera.pan(Vec3::new(10.0, 20.0, 0.0));
+        camera.zoom_by(2.0);
+        camera.rotate(std::f32::consts::FRAC_PI_2);
+        let matrix = camera.view_projection(100.0, 100.0);
+
+        // the panned center stays put
+        assert_close3(
+            clip(matrix, Vec3::new(60.0, 70.0, 0.0)),
+            Vec3::new(0.0, 0.0, 0.5),
+        );
+        // and a point to the right of it ends up above it, twice as far away
+        assert_close3(
+            clip(matrix, Vec3::new(70.0, 70.0, 0.0)),
+            Vec3::new(0.0, -0.4, 0.5),
+        );
+    }
+
+    #[test]
+    fn frustums_keep_what_the_camera_sees() {
+        let mut camera = Camera::orthographic();
+        camera.pan(Vec3::new(100.0, 0.0, 0.0));
+        let frustum = camera.frustum(64.0, 64.0);
+        let square =
+            |x: f32, y: f32| Aabb::new(Vec3::new(x, y, 0.0), Vec3::new(x + 16.0, y + 16.0, 0.0));
+
+        assert!(frustum.intersects(&square(100.0, 0.0)));
+        // partly in view on either side
+        assert!(frustum.intersects(&square(90.0, 56.0)));
+        assert!(frustum.intersects(&square(160.0, -8.0)));
+        assert!(!frustum.intersects(&square(0.0, 0.0)));
+        assert!(!frustum.intersects(&square(120.0, 70.0)));
+        // beyond the far plane
+        let far = Aabb::new(Vec3::new(100.0, 0.0, 2.0), Vec3::new(110.0, 10.0, 3.0));
+        assert!(!frustum.intersects(&far));
+    }
+
+    #[test]
+    fn perspective_looks_at_the_target() {
+        let mut camera = Camera::perspective(std::f32::consts::FRAC_PI_2, 1.0, 100.0);
+        camera.position = Vec3::new(5.0, 0.0, 0.0);
+        camera.look_at(Vec3::zeros(), Vec3::y());
+        let matrix = camera.view_projection(100.0, 100.0);
+
+        let center = clip(matrix, Vec3::zeros());
+        assert_close3(center, Vec3::new(0.0, 0.0, center.z));
+        assert!(center.z > 0.0 && center.z < 1.0);
+        // up in the world is up on the screen, which is -y in clip space
+        assert!(clip(matrix, Vec3::new(0.0, 1.0, 0.0)).y < 0.0);
+
+        assert!((camera.depth(&Vec3::zeros()) - 5.0).abs() < 1e-5);
+        assert!(camera.depth(&Vec3::new(-1.0, 0.0, 0.0)) > camera.depth(&Vec3::zeros()));
+    }
 }
@@ -0,0 +1,505 @@
+//! Renders known scenes headlessly and compares them against the reference
+//! images in `tests/golden`, writing the rendered image and a diff to
+//! `target/golden` when they don't match.
+//!
+//! Rendering needs a Vulkan implementation, a software one like lavapipe
+//! does fine, so those tests are ignored by default and run with
+//! `cargo test -- --ignored`, which CI does with lavapipe. Run them with
+//! `RMGE_BLESS=1` to accept the rendered images as the new references.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::geometry::{Mat4, Quad, Rect, Vec2, Vec3, Vec4};
+use crate::graphics::{
+    AdapterPreference, AtlasBuilder, BlendMode, Filter, MaterialHandle, PipelineDesc, TextureHandle,
+};
+use crate::scene::{SceneNode, SceneTree};
+use crate::{Renderer, RendererBuilder};
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 64;
+// how far apart a channel of a rendered pixel and its reference may be
+const TOLERANCE: u8 = 2;
+
+const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+#[derive(Debug, Clone, PartialEq)]
+struct Image {
+    width: u32,
+    height: u32,
+    // RGBA8, row by row
+    pixels: Vec<u8>,
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.png", name))
+}
+
+fn output_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("golden")
+}
+
+fn read_png(path: &Path) -> io::Result<Image> {
+    let decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    let (info, mut reader) = decoder.read_info().map_err(io::Error::from)?;
+    if info.color_type != png::ColorType::RGBA || info.bit_depth != png::BitDepth::Eight {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} isn't an RGBA8 image", path.display()),
+        ));
+    }
+
+    let mut pixels = vec![0; info.buffer_size()];
+    reader.next_frame(&mut pixels).map_err(io::Error::from)?;
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+fn write_png(path: &Path, image: &Image) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        image.width,
+        image.height,
+    );
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()?
+        .write_image_data(&image.pixels)
+        .map_err(io::Error::from)
+}
+
+// counts the pixels differing from the reference by more than `tolerance` in
+// any channel, and returns an image marking them red over a faded reference
+fn compare(expected: &Image, actual: &Image, tolerance: u8) -> (usize, Image) {
+    let mut mismatched = 0;
+    let mut diff = Vec::with_capacity(expected.pixels.len());
+
+    for (expected, actual) in expected
+        .pixels
+        .chunks_exact(4)
+        .zip(actual.pixels.chunks_exact(4))
+    {
+        let differs = expected
+            .iter()
+            .zip(actual)
+            .any(|(a, b)| (i16::from(*a) - i16::from(*b)).abs() > i16::from(tolerance));
+
+        if differs {
+            mismatched += 1;
+            diff.extend_from_slice(&[0xff, 0x00, 0x00, 0xff]);
+        } else {
+            diff.extend(expected[..3].iter().map(|channel| channel / 4));
+            diff.push(0xff);
+        }
+    }
+
+    (
+        mismatched,
+        Image {
+            width: expected.width,
+            height: expected.height,
+            pixels: diff,
+        },
+    )
+}
+
+// compares what `renderer` last rendered against the reference called `name`
+fn check(renderer: &mut Renderer, name: &str) {
+    let actual = Image {
+        width: WIDTH,
+        height: HEIGHT,
+        pixels: renderer.read_pixels().expect("failed to read pixels"),
+    };
+
+    let reference = reference_path(name);
+    if env::var_os("RMGE_BLESS").is_some() {
+        fs::create_dir_all(reference.parent().unwrap()).unwrap();
+        write_png(&reference, &actual).expect("failed to write the reference");
+        return;
+    }
+
+    let out = output_dir();
+    fs::create_dir_all(&out).unwrap();
+    let actual_path = out.join(format!("{}.actual.png", name));
+
+    if !reference.exists() {
+        write_png(&actual_path, &actual).unwrap();
+        panic!(
+            "there's no reference for `{}`, check {} and run with RMGE_BLESS=1 to accept it",
+            name,
+            actual_path.display()
+        );
+    }
+
+    let expected = read_png(&reference).expect("failed to read the reference");
+    assert_eq!(
+        (expected.width, expected.height),
+        (actual.width, actual.height),
+        "the reference for `{}` has the wrong size",
+        name
+    );
+
+    let (mismatched, diff) = compare(&expected, &actual, TOLERANCE);
+    if mismatched > 0 {
+        let diff_path = out.join(format!("{}.diff.png", name));
+        write_png(&actual_path, &actual).unwrap();
+        write_png(&diff_path, &diff).unwrap();
+        panic!(
+            "{} pixels of `{}` differ from the reference, see {}",
+            mismatched,
+            name,
+            diff_path.display()
+        );
+    }
+}
+
+// software adapters render the same everywhere, so they're preferred when
+// there's also a GPU around
+fn builder() -> RendererBuilder {
+    Renderer::builder().adapter(AdapterPreference::Software)
+}
+
+fn renderer() -> Renderer {
+    builder()
+        .build_headless(WIDTH, HEIGHT)
+        .expect("failed to build a headless renderer")
+}
+
+fn solid(width: u32, height: u32, color: [u8; 4]) -> Vec<u8> {
+    color.repeat((width * height) as usize)
+}
+
+#[test]
+#[ignore]
+fn clear() {
+    let mut renderer = renderer();
+    renderer.clear([0.2, 0.4, 0.6, 1.0]).unwrap();
+    check(&mut renderer, "clear");
+}
+
+#[test]
+#[ignore]
+fn single_quad() {
+    let mut renderer = renderer();
+    let quad = Quad::rect(Vec2::new(16.0, 16.0), Vec2::new(32.0, 32.0)).with_colors([
+        Vec4::new(1.0, 0.0, 0.0, 1.0),
+        Vec4::new(0.0, 1.0, 0.0, 1.0),
+        Vec4::new(0.0, 0.0, 1.0, 1.0),
+        Vec4::new(1.0, 1.0, 1.0, 1.0),
+    ]);
+    renderer.draw_quad(quad, [1.0; 4], BLACK).unwrap();
+    check(&mut renderer, "single_quad");
+}
+
+#[test]
+#[ignore]
+fn nested_transforms() {
+    let mut renderer = renderer();
+    let square = |color| Quad::rect(Vec2::new(0.0, 0.0), Vec2::new(16.0, 16.0)).with_color(color);
+
+    let mut grandchild = SceneNode::new(
+        Mat4::new_translation(&Vec3::new(32.0, 32.0, 0.0))
+            * Mat4::new_rotation(Vec3::new(0.0, 0.0, std::f32::consts::FRAC_PI_4)),
+    );
+    grandchild.add_quad(square(Vec4::new(0.0, 0.0, 1.0, 1.0)));
+
+    let mut child = SceneNode::new(Mat4::new_scaling(0.5));
+    child.add_quad(square(Vec4::new(0.0, 1.0, 0.0, 1.0)));
+    child.add_child(grandchild);
+
+    let root = renderer.scene_mut().root_mut();
+    *root.transform = Mat4::new_translation(&Vec3::new(16.0, 16.0, 0.0));
+    root.add_quad(square(Vec4::new(1.0, 0.0, 0.0, 1.0)));
+    root.add_child(child);
+
+    renderer.draw_scene(BLACK).unwrap();
+    check(&mut renderer, "nested_transforms");
+}
+
+#[test]
+#[ignore]
+fn textured_sprites() {
+    let mut renderer = renderer();
+
+    let (black, white) = ([0x00, 0x00, 0x00, 0xff], [0xff, 0xff, 0xff, 0xff]);
+    let checkerboard = renderer
+        .add_texture(
+            2,
+            2,
+            &[white, black, black, white].concat(),
+            Filter::Nearest,
+        )
+        .unwrap();
+
+    let mut atlas = AtlasBuilder::new(16, 16, 1);
+    atlas
+        .add_image(4, 4, &solid(4, 4, [0xff, 0x80, 0x00, 0xff]))
+        .unwrap();
+    atlas
+        .add_image(4, 8, &solid(4, 8, [0x00, 0x80, 0xff, 0xff]))
+        .unwrap();
+    let sprites = renderer.add_atlas(&atlas, Filter::Nearest).unwrap();
+
+    let root = renderer.scene_mut().root_mut();
+    root.add_quad(
+        Quad::rect(Vec2::new(0.0, 0.0), Vec2::new(32.0, 32.0)).with_texture(checkerboard),
+    );
+    // only the top left square of the checkerboard, tinted
+    root.add_quad(
+        Quad::rect(Vec2::new(32.0, 0.0), Vec2::new(32.0, 32.0))
+            .with_texture(checkerboard)
+            .with_uv_rect(Vec2::new(0.0, 0.0), Vec2::new(0.5, 0.5))
+            .with_color(Vec4::new(1.0, 0.5, 0.5, 1.0)),
+    );
+    root.add_quad(Quad::rect(Vec2::new(0.0, 32.0), Vec2::new(32.0, 32.0)).with_texture(sprites[0]));
+    root.add_quad(
+        Quad::rotated_rect(Vec2::new(48.0, 48.0), Vec2::new(16.0, 25.6), 0.3)
+            .with_texture(sprites[1]),
+    );
+
+    renderer.draw_scene(BLACK).unwrap();
+    check(&mut renderer, "textured_sprites");
+}
+
+#[test]
+#[ignore]
+fn blend_modes() {
+    let mut renderer = renderer();
+    let mut material = |blend| {
+        renderer
+            .add_material(PipelineDesc {
+                blend,
+                ..PipelineDesc::default()
+            })
+            .unwrap()
+    };
+    let alpha = material(BlendMode::Alpha);
+    let additive = material(BlendMode::Additive);
+    let multiply = material(BlendMode::Multiply);
+
+    let root = renderer.scene_mut().root_mut();
+    root.add_quad(
+        Quad::rect(Vec2::new(8.0, 8.0), Vec2::new(48.0, 48.0))
+            .with_color(Vec4::new(0.5, 0.5, 0.5, 1.0)),
+    );
+    let mut child = |material, position, color| {
+        let mut node = SceneNode::new(Mat4::identity()).with_material(material);
+        node.add_quad(Quad::rect(position, Vec2::new(24.0, 24.0)).with_color(color));
+        root.add_child(node);
+    };
+    child(alpha, Vec2::new(0.0, 0.0), Vec4::new(1.0, 0.0, 0.0, 0.5));
+    child(
+        additive,
+        Vec2::new(40.0, 0.0),
+        Vec4::new(0.0, 0.5, 0.0, 1.0),
+    );
+    child(
+        multiply,
+        Vec2::new(20.0, 40.0),
+        Vec4::new(0.0, 0.0, 1.0, 1.0),
+    );
+
+    renderer.draw_scene(BLACK).unwrap();
+    check(&mut renderer, "blend_modes");
+}
+
+#[test]
+#[ignore]
+fn depth_layering() {
+    let mut renderer = renderer();
+    let at_depth = |mut quad: Quad, z| {
+        for point in quad.points.iter_mut() {
+            point.z = z;
+        }
+        quad
+    };
+
+    // drawn nearest first, so only the depth buffer puts it on top
+    let root = renderer.scene_mut().root_mut();
+    root.add_quad(at_depth(
+        Quad::rect(Vec2::new(16.0, 16.0), Vec2::new(32.0, 32.0))
+            .with_color(Vec4::new(1.0, 0.0, 0.0, 1.0)),
+        0.5,
+    ));
+    root.add_quad(at_depth(
+        Quad::rect(Vec2::new(0.0, 0.0), Vec2::new(40.0, 40.0))
+            .with_color(Vec4::new(0.0, 1.0, 0.0, 1.0)),
+        0.0,
+    ));
+    root.add_quad(at_depth(
+        Quad::rect(Vec2::new(24.0, 24.0), Vec2::new(40.0, 40.0))
+            .with_color(Vec4::new(0.0, 0.0, 1.0, 1.0)),
+        -0.5,
+    ));
+
+    renderer.draw_scene(BLACK).unwrap();
+    check(&mut renderer, "depth_layering");
+}
+
+// renders a white quad rotated so its edges cut through pixels, returning
+// how many pixels it only partly covers
+fn partly_covered_pixels(renderer: &mut Renderer) -> usize {
+    renderer.scene_mut().root_mut().add_quad(Quad::rotated_rect(
+        Vec2::new(32.0, 32.0),
+        Vec2::new(40.0, 24.0),
+        0.4,
+    ));
+    renderer.draw_scene(BLACK).unwrap();
+
+    let pixels = renderer.read_pixels().expect("failed to read pixels");
+    pixels
+        .chunks_exact(4)
+        .filter(|pixel| {
+            pixel[..3]
+                .iter()
+                .any(|channel| *channel != 0 && *channel != 0xff)
+        })
+        .count()
+}
+
+#[test]
+#[ignore]
+fn multisampled_edges() {
+    // without a reference to compare against, this at least checks the
+    // edges get blended at all, which they can't with a single sample
+    assert_eq!(partly_covered_pixels(&mut renderer()), 0);
+
+    let mut renderer = builder()
+        .samples(4)
+        .build_headless(WIDTH, HEIGHT)
+        .expect("failed to build a multisampled headless renderer");
+    assert!(partly_covered_pixels(&mut renderer) > 0);
+    check(&mut renderer, "multisampled_edges");
+}
+
+#[test]
+#[ignore]
+fn one_frame_many_draws() {
+    let mut renderer = renderer();
+    let square = |x, y, color| Quad::rect(Vec2::new(x, y), Vec2::new(16.0, 16.0)).with_color(color);
+    renderer
+        .scene_mut()
+        .root_mut()
+        .add_quad(square(8.0, 8.0, Vec4::new(1.0, 0.0, 0.0, 1.0)));
+
+    let mut overlay = SceneTree::new(SceneNode::new(Mat4::new_translation(&Vec3::new(
+        32.0, 0.0, 0.0,
+    ))));
+    overlay
+        .root_mut()
+        .add_quad(square(8.0, 8.0, Vec4::new(0.0, 1.0, 0.0, 1.0)));
+
+    let mut frame = renderer.begin_frame(BLACK);
+    frame.draw_scene();
+    frame.draw_tree(&mut overlay);
+    frame.draw_quad(
+        square(8.0, 40.0, Vec4::new(1.0, 1.0, 1.0, 1.0)),
+        [0.0, 0.0, 1.0, 1.0],
+    );
+    frame.draw_quads(
+        MaterialHandle::DEFAULT,
+        Mat4::new_translation(&Vec3::new(32.0, 32.0, 0.0)),
+        &[square(8.0, 8.0, Vec4::new(1.0, 1.0, 0.0, 1.0))],
+    );
+    frame.end_frame().unwrap();
+    check(&mut renderer, "one_frame_many_draws");
+}
+
+#[test]
+#[ignore]
+fn sprite_batch() {
+    let mut renderer = renderer();
+    let (black, white) = ([0x00, 0x00, 0x00, 0xff], [0xff, 0xff, 0xff, 0xff]);
+    let checkerboard = renderer
+        .add_texture(
+            2,
+            2,
+            &[white, black, black, white].concat(),
+            Filter::Nearest,
+        )
+        .unwrap();
+
+    let mut frame = renderer.begin_frame(BLACK);
+    // a grid of small sprites, alternating between textures
+    for i in 0..64 {
+        let (x, y) = ((i % 8) as f32 * 4.0, (i / 8) as f32 * 4.0);
+        let texture = if i % 2 == 0 {
+            checkerboard
+        } else {
+            TextureHandle::WHITE
+        };
+        frame.draw_sprite(
+            texture,
+            Rect::new(x, y, 4.0, 4.0),
+            None,
+            [1.0, 0.5, 0.25, 1.0],
+            0.0,
+            Vec2::zeros(),
+        );
+    }
+    // the top right pixel of the checkerboard, spinning around its center
+    frame.draw_sprite(
+        checkerboard,
+        Rect::new(40.0, 40.0, 16.0, 16.0),
+        Some(Rect::new(1.0, 0.0, 1.0, 1.0)),
+        [1.0; 4],
+        0.5,
+        Vec2::new(8.0, 8.0),
+    );
+    frame.end_frame().unwrap();
+    check(&mut renderer, "sprite_batch");
+}
+
+#[test]
+fn compare_respects_the_tolerance() {
+    let image = |pixels: &[u8]| Image {
+        width: 2,
+        height: 1,
+        pixels: pixels.to_vec(),
+    };
+    let expected = image(&[100, 100, 100, 255, 40, 80, 120, 255]);
+
+    let (mismatched, _) = compare(&expected, &image(&[102, 98, 100, 255, 40, 80, 120, 255]), 2);
+    assert_eq!(mismatched, 0);
+
+    let (mismatched, diff) = compare(
+        &expected,
+        &image(&[100, 100, 100, 255, 40, 83, 120, 255]),
+        2,
+    );
+    assert_eq!(mismatched, 1);
+    assert_eq!(diff.pixels, vec![25, 25, 25, 255, 255, 0, 0, 255]);
+}
+
+#[test]
+fn png_round_trip() {
+    let image = Image {
+        width: 2,
+        height: 2,
+        pixels: [
+            [1, 2, 3, 4],
+            [5, 6, 7, 8],
+            [9, 10, 11, 12],
+            [13, 14, 15, 16],
+        ]
+        .concat(),
+    };
+    let out = output_dir();
+    fs::create_dir_all(&out).unwrap();
+    let path = out.join("round_trip.png");
+
+    write_png(&path, &image).unwrap();
+    assert_eq!(read_png(&path).unwrap(), image);
+}
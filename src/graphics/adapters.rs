@@ -0,0 +1,131 @@
+use gfx_hal::{
+    adapter::{Adapter, AdapterInfo, DeviceType, PhysicalDevice},
+    memory::Properties,
+    Backend, Features, Limits,
+};
+
+use super::settings::AdapterPreference;
+
+/// An adapter the context can render with, as listed by
+/// `Context::adapters`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterDetails {
+    /// The position in the backend's list of adapters, which is what
+    /// `AdapterPreference::Index` refers to. Unusable adapters aren't
+    /// listed, so there may be gaps.
+    pub index: usize,
+    pub info: AdapterInfo,
+    pub memory_heaps: Vec<MemoryHeap>,
+    pub limits: Limits,
+    pub features: Features,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryHeap {
+    /// In bytes.
+    pub size: u64,
+    /// Whether it's the adapter's own memory, as opposed to system memory
+    /// it can access.
+    pub device_local: bool,
+}
+
+impl AdapterDetails {
+    pub(crate) fn new<B: Backend>(index: usize, adapter: &Adapter<B>) -> Self {
+        let memory = adapter.physical_device.memory_properties();
+        let memory_heaps = memory
+            .memory_heaps
+            .iter()
+            .enumerate()
+            .map(|(heap_index, &size)| MemoryHeap {
+                size,
+                device_local: memory.memory_types.iter().any(|memory_type| {
+                    memory_type.heap_index == heap_index
+                        && memory_type.properties.contains(Properties::DEVICE_LOCAL)
+                }),
+            })
+            .collect();
+
+        AdapterDetails {
+            index,
+            info: adapter.info.clone(),
+            memory_heaps,
+            limits: adapter.physical_device.limits(),
+            features: adapter.physical_device.features(),
+        }
+    }
+}
+
+// lower is tried first
+fn type_rank(device_type: &DeviceType) -> u8 {
+    match device_type {
+        DeviceType::DiscreteGpu => 0,
+        DeviceType::IntegratedGpu => 1,
+        DeviceType::VirtualGpu => 2,
+        DeviceType::Cpu => 3,
+        DeviceType::Other => 4,
+    }
+}
+
+// the indices of the adapters in `infos` in the order they should be tried:
+// the ones matching `preference` first, then by type, discrete GPUs first and
+// software ones last, then in the order they're listed. Without `fallback`,
+// only the matching ones are tried.
+pub(crate) fn candidate_order(
+    infos: &[&AdapterInfo],
+    preference: &AdapterPreference,
+    fallback: bool,
+) -> Vec<usize> {
+    let mut order = (0..infos.len())
+        .filter(|&index| fallback || preference.matches(index, infos[index]))
+        .collect::<Vec<_>>();
+    order.sort_by_key(|&index| {
+        (
+            !preference.matches(index, infos[index]),
+            type_rank(&infos[index].device_type),
+        )
+    });
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(name: &str, device_type: DeviceType) -> AdapterInfo {
+        AdapterInfo {
+            name: name.to_string(),
+            vendor: 0,
+            device: 0,
+            device_type,
+        }
+    }
+
+    #[test]
+    fn candidates_are_ordered_deterministically() {
+        let infos = [
+            info("llvmpipe", DeviceType::Cpu),
+            info("Intel UHD", DeviceType::IntegratedGpu),
+            info("Radeon", DeviceType::DiscreteGpu),
+            info("Intel Arc", DeviceType::DiscreteGpu),
+        ];
+        let infos = infos.iter().collect::<Vec<_>>();
+
+        assert_eq!(
+            candidate_order(&infos, &AdapterPreference::Any, true),
+            vec![2, 3, 1, 0]
+        );
+        assert_eq!(
+            candidate_order(&infos, &AdapterPreference::Software, true),
+            vec![0, 2, 3, 1]
+        );
+        assert_eq!(
+            candidate_order(&infos, &AdapterPreference::Named("intel".to_string()), true),
+            vec![3, 1, 2, 0]
+        );
+        assert_eq!(
+            candidate_order(&infos, &AdapterPreference::Index(1), false),
+            vec![1]
+        );
+        assert!(candidate_order(&infos, &AdapterPreference::Index(7), false).is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+use core::mem::ManuallyDrop;
+
+use gfx_hal::{
+    adapter::{Adapter, PhysicalDevice},
+    device::Device as DeviceTrait,
+    format::{Aspects, Format, ImageFeature, Swizzle},
+    image::{
+        Kind, NumSamples, SubresourceRange, Tiling, Usage as ImageUsage, ViewCapabilities, ViewKind,
+    },
+    memory::Properties,
+    window::Extent2D,
+    Backend,
+};
+
+use super::resources::buffer::Memory;
+use crate::error::*;
+
+use std::rc::Rc;
+
+// tried in order, the first two have the best precision
+const DEPTH_FORMATS: [Format; 4] = [
+    Format::D32Sfloat,
+    Format::D32SfloatS8Uint,
+    Format::D24UnormS8Uint,
+    Format::D16Unorm,
+];
+
+/// The first depth format the adapter can render to, if any.
+pub fn depth_format<B: Backend>(adapter: &Adapter<B>) -> Option<Format> {
+    DEPTH_FORMATS.iter().copied().find(|&format| {
+        adapter
+            .physical_device
+            .format_properties(Some(format))
+            .optimal_tiling
+            .contains(ImageFeature::DEPTH_STENCIL_ATTACHMENT)
+    })
+}
+
+/// Whether the adapter can render to color attachments, and depth ones if
+/// `depth` is set, with `samples` samples per pixel.
+pub fn supports_samples<B: Backend>(
+    adapter: &Adapter<B>,
+    samples: NumSamples,
+    depth: bool,
+) -> bool {
+    let limits = adapter.physical_device.limits();
+    sample_count_supported(limits.framebuffer_color_sample_counts, samples)
+        && (!depth || sample_count_supported(limits.framebuffer_depth_sample_counts, samples))
+}
+
+// the limits are masks with the bit of every supported power of two set,
+// a single sample is always supported
+fn sample_count_supported(counts: NumSamples, samples: NumSamples) -> bool {
+    samples == 1 || (samples.is_power_of_two() && counts & samples != 0)
+}
+
+/// An image only used within the render pass, like a depth buffer.
+#[derive(Debug)]
+pub struct AttachmentImage<B: Backend> {
+    device: Rc<ManuallyDrop<B::Device>>,
+    image: ManuallyDrop<B::Image>,
+    pub view: ManuallyDrop<B::ImageView>,
+    memory: Memory<B, B::Device>,
+}
+
+impl<B: Backend> AttachmentImage<B> {
+    pub fn new(
+        device: Rc<ManuallyDrop<B::Device>>,
+        adapter: &Adapter<B>,
+        extent: Extent2D,
+        format: Format,
+        samples: NumSamples,
+        usage: ImageUsage,
+        aspects: Aspects,
+    ) -> Result<Self, Error> {
+        unsafe {
+            let mut image = device
+                .create_image(
+                    Kind::D2(extent.width, extent.height, 1, samples),
+                    1,
+                    format,
+                    Tiling::Optimal,
+                    usage,
+                    ViewCapabilities::empty(),
+                )
+                .map_err(|_| Error::ImageCreationError)?;
+
+            let memory = match Memory::new(
+                device.clone(),
+                adapter,
+                Properties::DEVICE_LOCAL,
+                device.get_image_requirements(&image),
+                MemoryKind::Image,
+            ) {
+                Ok(memory) => memory,
+                Err(e) => {
+                    device.destroy_image(image);
+                    return Err(e);
+                }
+            };
+
+            if device
+                .bind_image_memory(&memory.memory, 0, &mut image)
+                .is_err()
+            {
+                device.destroy_image(image);
+                return Err(Error::ImageBindError);
+            }
+
+            let range = SubresourceRange {
+                aspects,
+                levels: 0..1,
+                layers: 0..1,
+            };
+            let view = match device
+                .create_image_view(&image, ViewKind::D2, format, Swizzle::NO, range)
+                .map_err(|_| Error::ImageViewCreationError)
+            {
+                Ok(view) => view,
+                Err(e) => {
+                    device.destroy_image(image);
+                    return Err(e);
+                }
+            };
+
+            Ok(AttachmentImage {
+                device,
+                image: ManuallyDrop::new(image),
+                view: ManuallyDrop::new(view),
+                memory,
+            })
+        }
+    }
+
+    pub fn depth(
+        device: Rc<ManuallyDrop<B::Device>>,
+        adapter: &Adapter<B>,
+        extent: Extent2D,
+        format: Format,
+        samples: NumSamples,
+    ) -> Result<Self, Error> {
+        Self::new(
+            device,
+            adapter,
+            extent,
+            format,
+            samples,
+            ImageUsage::DEPTH_STENCIL_ATTACHMENT,
+            Aspects::DEPTH,
+        )
+    }
+
+    /// A color image drawn to with more than one sample per pixel, which
+    /// only lives until the render pass resolves it into the target.
+    pub fn multisampled_color(
+        device: Rc<ManuallyDrop<B::Device>>,
+        adapter: &Adapter<B>,
+        extent: Extent2D,
+        format: Format,
+        samples: NumSamples,
+    ) -> Result<Self, Error> {
+        Self::new(
+            device,
+            adapter,
+            extent,
+            format,
+            samples,
+            ImageUsage::COLOR_ATTACHMENT | ImageUsage::TRANSIENT_ATTACHMENT,
+            Aspects::COLOR,
+        )
+    }
+}
+
+impl<B: Backend> Drop for AttachmentImage<B> {
+    fn drop(&mut self) {
+        unsafe {
+            use std::ptr::read;
+
+            self.device
+                .destroy_image_view(ManuallyDrop::into_inner(read(&self.view)));
+            self.device
+                .destroy_image(ManuallyDrop::into_inner(read(&self.image)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_counts_have_to_be_supported_powers_of_two() {
+        let counts = 0b0000_0101;
+        assert!(sample_count_supported(counts, 1));
+        assert!(sample_count_supported(counts, 4));
+        assert!(!sample_count_supported(counts, 2));
+        assert!(!sample_count_supported(counts, 5));
+        assert!(!sample_count_supported(0, 8));
+        assert!(sample_count_supported(0, 1));
+    }
+}
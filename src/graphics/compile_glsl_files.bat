@@ -1,2 +0,0 @@
-glslc -O -o vert.spv vertex_shader.vert
-glslc -O -o frag.spv fragment_shader.frag
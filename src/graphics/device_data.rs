@@ -1,65 +1,100 @@
 use core::mem::ManuallyDrop;
 
-use log::{info, warn};
-
 use gfx_hal::{
+    adapter::Adapter,
     buffer::{IndexBufferView, SubRange},
     command::{
-        ClearColor, ClearValue, CommandBuffer as CommandBufferTrait, CommandBufferFlags, Level,
-        SubpassContents,
+        ClearColor, ClearDepthStencil, ClearValue, CommandBuffer as CommandBufferTrait,
+        CommandBufferFlags, Level, SubpassContents,
     },
     device::Device as DeviceTrait,
     format::{Aspects, Format, Swizzle},
-    image::{Layout, SubresourceRange, ViewKind},
+    image::{Layout, NumSamples, SubresourceRange, ViewKind},
     pass::{Attachment, AttachmentLoadOp, AttachmentOps, AttachmentStoreOp, SubpassDesc},
     pool::CommandPool as CommandPoolTrait,
-    pso::{
-        AttributeDesc, DescriptorPool, Element, PipelineStage, VertexBufferDesc, VertexInputRate,
-    },
+    pso::{PipelineStage, Viewport},
     queue::{CommandQueue, QueueGroup, Submission},
-    window::Swapchain,
+    window::{Extent2D, Swapchain},
     Backend, IndexType,
 };
 
+use super::attachment::AttachmentImage;
+use super::frames::{FrameTracker, HalFences};
+use super::material::{DrawBatch, PipelineDesc, QuadSource};
+use super::offscreen_data::OffscreenData;
 use super::pipeline_data::PipelineData;
 use super::resources::ResourceManager;
 use super::swapchain_data::SwapchainData;
 use crate::error::*;
+use crate::geometry::Camera;
 
 use arrayvec::ArrayVec;
 use std::{mem, rc::Rc};
 
+// what's needed to draw the geometry of a frame: where it's stored, the
+// camera to draw it with, and what to draw
+type Geometry<'a, B> = (
+    &'a ResourceManager<B, <B as Backend>::Device>,
+    &'a Camera,
+    &'a [DrawBatch],
+);
+
 #[derive(Debug)]
 pub struct DeviceData<B: Backend> {
     pub adapter_index: usize,
     pub device: Rc<ManuallyDrop<B::Device>>,
     pub queue: QueueGroup<B>,
     pub swapchains: Vec<SwapchainData<B>>,
+    // rendered to instead of the swapchains when headless
+    pub offscreen: Option<OffscreenData<B>>,
     pub render_passes: Vec<B::RenderPass>,
+    // how many frames may be recorded while the GPU is still busy with
+    // earlier ones, each with its own command buffer and sync objects
+    pub frames_in_flight: usize,
+    // the format of the render pass' depth attachment, if it has one
+    pub depth_format: Option<Format>,
+    // samples per pixel of the render pass' color and depth attachments
+    pub samples: NumSamples,
+    // how many bytes of push constants the pipelines can have, the camera's
+    // included
+    pub max_push_constants_size: u32,
     pub pipelines: Vec<PipelineData<B, B::Device>>,
 }
 
 impl<B: Backend> DeviceData<B> {
-    pub fn from(adapter_index: usize, device: B::Device, queue: QueueGroup<B>) -> Self {
+    pub fn from(
+        adapter_index: usize,
+        device: B::Device,
+        queue: QueueGroup<B>,
+        frames_in_flight: usize,
+        max_push_constants_size: u32,
+    ) -> Self {
         Self {
             adapter_index,
             device: Rc::new(ManuallyDrop::new(device)),
             queue,
             swapchains: vec![],
+            offscreen: None,
             render_passes: vec![],
+            frames_in_flight: frames_in_flight.max(1),
+            depth_format: None,
+            samples: 1,
+            max_push_constants_size,
             pipelines: vec![],
         }
     }
-    //make this index safe
+    /// Creates the fences and semaphores of every frame in flight.
     pub fn add_semaphores(&mut self, swapchain_index: usize) -> Result<(), Error> {
-        let image_count = self.swapchains[swapchain_index].config.image_count;
+        let frames = self.frames_in_flight;
         let device = &self.device;
+        let swapchain = &mut self.swapchains[swapchain_index];
+        swapchain.frames = FrameTracker::new(frames, swapchain.backbuffer.len());
         self.swapchains[swapchain_index].fences = Some(
-            (0..image_count)
+            (0..frames)
                 .map(|n| {
                     let mut fence = device
                         .create_fence(true)
-                        .map_err(|e| Error::FenceCreationError)?;
+                        .map_err(|_| Error::FenceCreationError)?;
                     unsafe {
                         device.set_fence_name(&mut fence, &format!("fence #{}", n));
                     }
@@ -68,51 +103,153 @@ impl<B: Backend> DeviceData<B> {
                 .collect::<Result<Vec<_>, _>>()?,
         );
         self.swapchains[swapchain_index].available_semaphores = Some(
-            (0..image_count)
+            (0..frames)
                 .map(|_| {
                     device
                         .create_semaphore()
-                        .map_err(|e| Error::SemaphoreCreationError)
+                        .map_err(|_| Error::SemaphoreCreationError)
                 })
                 .collect::<Result<Vec<_>, _>>()?,
         );
         self.swapchains[swapchain_index].finished_semaphores = Some(
-            (0..image_count)
+            (0..frames)
                 .map(|_| {
                     device
                         .create_semaphore()
-                        .map_err(|e| Error::SemaphoreCreationError)
+                        .map_err(|_| Error::SemaphoreCreationError)
                 })
                 .collect::<Result<Vec<_>, _>>()?,
         );
         Ok(())
     }
 
-    pub fn add_render_pass(&mut self) -> Result<(), Error> {
+    /// Adds a render pass drawing to a single image of `format`, leaving it
+    /// in `final_layout`, with a depth attachment of `depth_format` if given.
+    /// With more than one sample per pixel, it draws to multisampled color
+    /// and depth attachments instead, and resolves the color into the image.
+    pub fn add_render_pass(
+        &mut self,
+        format: Format,
+        final_layout: Layout,
+        depth_format: Option<Format>,
+        samples: NumSamples,
+    ) -> Result<(), Error> {
+        let multisampled = samples > 1;
         self.render_passes.push({
-            let color_attachment = Attachment {
-                format: Some(self.swapchains[0].config.format),
+            let target = Attachment {
+                format: Some(format),
                 samples: 1,
                 ops: AttachmentOps {
                     load: AttachmentLoadOp::Clear,
                     store: AttachmentStoreOp::Store,
                 },
                 stencil_ops: AttachmentOps::DONT_CARE,
-                layouts: Layout::Undefined..Layout::Present,
+                layouts: Layout::Undefined..final_layout,
+            };
+            // the multisampled color is only needed until it's resolved
+            // into the target, which is overwritten entirely by that
+            let (color_attachment, resolve_attachment) = if multisampled {
+                let color = Attachment {
+                    samples,
+                    ops: AttachmentOps {
+                        load: AttachmentLoadOp::Clear,
+                        store: AttachmentStoreOp::DontCare,
+                    },
+                    layouts: Layout::Undefined..Layout::ColorAttachmentOptimal,
+                    ..target.clone()
+                };
+                let resolve = Attachment {
+                    ops: AttachmentOps {
+                        load: AttachmentLoadOp::DontCare,
+                        store: AttachmentStoreOp::Store,
+                    },
+                    ..target
+                };
+                (color, Some(resolve))
+            } else {
+                (target, None)
             };
+            // only needed while rendering, so it's never stored
+            let depth_attachment = depth_format.map(|depth| Attachment {
+                format: Some(depth),
+                samples,
+                ops: AttachmentOps {
+                    load: AttachmentLoadOp::Clear,
+                    store: AttachmentStoreOp::DontCare,
+                },
+                stencil_ops: AttachmentOps::DONT_CARE,
+                layouts: Layout::Undefined..Layout::DepthStencilAttachmentOptimal,
+            });
+
+            // the color comes first, then the depth and the resolve target
+            // if there are any, which is also the order of the framebuffers'
+            // attachments and the clear values
+            let depth_ref = (1, Layout::DepthStencilAttachmentOptimal);
+            let resolve_ref = (
+                1 + depth_attachment.iter().count(),
+                Layout::ColorAttachmentOptimal,
+            );
             let subpass = SubpassDesc {
                 colors: &[(0, Layout::ColorAttachmentOptimal)],
-                depth_stencil: None,
+                depth_stencil: depth_attachment.as_ref().map(|_| &depth_ref),
                 inputs: &[],
-                resolves: &[],
+                resolves: if multisampled {
+                    std::slice::from_ref(&resolve_ref)
+                } else {
+                    &[]
+                },
                 preserves: &[],
             };
             unsafe {
                 self.device
-                    .create_render_pass(&[color_attachment], &[subpass], &[])
-                    .map_err(|e| Error::RenderPassCreationError)?
+                    .create_render_pass(
+                        Some(color_attachment)
+                            .into_iter()
+                            .chain(depth_attachment)
+                            .chain(resolve_attachment)
+                            .collect::<ArrayVec<[_; 3]>>(),
+                        &[subpass],
+                        &[],
+                    )
+                    .map_err(|_| Error::RenderPassCreationError)?
             }
         });
+        self.depth_format = depth_format;
+        self.samples = samples;
+        Ok(())
+    }
+
+    /// Creates the images the render pass draws to besides the swapchain
+    /// images, a depth image and a multisampled color image for each of them
+    /// if it needs those. Has to happen before `add_framebuffers`.
+    pub fn add_attachment_images(
+        &mut self,
+        swapchain_index: usize,
+        adapter: &Adapter<B>,
+    ) -> Result<(), Error> {
+        let device = &self.device;
+        let samples = self.samples;
+        let swapchain = &mut self.swapchains[swapchain_index];
+        let (extent, image_count) = (swapchain.config.extent, swapchain.backbuffer.len());
+        if let Some(format) = self.depth_format {
+            swapchain.depth_images = (0..image_count)
+                .map(|_| AttachmentImage::depth(device.clone(), adapter, extent, format, samples))
+                .collect::<Result<Vec<_>, Error>>()?;
+        }
+        if samples > 1 {
+            let format = swapchain.config.format;
+            swapchain.color_images = (0..image_count)
+                .map(|_| {
+                    AttachmentImage::multisampled_color(
+                        device.clone(),
+                        adapter,
+                        extent,
+                        format,
+                        samples,
+                    )
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+        }
         Ok(())
     }
 
@@ -124,7 +261,7 @@ impl<B: Backend> DeviceData<B> {
                 .map(|image| unsafe {
                     self.device
                         .create_image_view(
-                            &image,
+                            image,
                             ViewKind::D2,
                             self.swapchains[swapchain_index].config.format,
                             Swizzle::NO,
@@ -134,7 +271,7 @@ impl<B: Backend> DeviceData<B> {
                                 layers: 0..1,
                             },
                         )
-                        .map_err(|e| Error::ImageViewCreationError)
+                        .map_err(|_| Error::ImageViewCreationError)
                 })
                 .collect::<Result<Vec<_>, Error>>()?,
         );
@@ -157,7 +294,12 @@ impl<B: Backend> DeviceData<B> {
         &mut self,
         command_pool: &mut B::CommandPool,
     ) -> Vec<B::CommandBuffer> {
-        let num_buffers = self.swapchains[0].framebuffers.len();
+        // one per frame in flight, an offscreen target only ever needs one
+        let num_buffers = if self.swapchains.is_empty() {
+            1
+        } else {
+            self.frames_in_flight
+        };
         let mut buffers = Vec::new();
         unsafe {
             command_pool.allocate(num_buffers, Level::Primary, &mut buffers);
@@ -175,276 +317,321 @@ impl<B: Backend> DeviceData<B> {
 
     pub fn add_graphics_pipeline(
         &mut self,
-        swapchain_index: usize,
         render_pass_index: usize,
+        desc: PipelineDesc,
     ) -> Result<(), Error> {
-        let vertex_buffers = vec![
-            // the vertices
-            VertexBufferDesc {
-                binding: 0,
-                stride: mem::size_of::<f32>() as u32 * 3,
-                rate: VertexInputRate::Vertex,
-            },
-            // the texture indices
-            VertexBufferDesc {
-                binding: 1,
-                stride: mem::size_of::<u32>() as u32,
-                rate: VertexInputRate::Instance(1),
-            },
-        ];
-
-        let attributes = vec![
-            AttributeDesc {
-                location: 0,
-                binding: 0,
-                element: Element {
-                    format: Format::Rgb32Sfloat,
-                    offset: 0,
-                },
-            },
-            AttributeDesc {
-                location: 1,
-                binding: 1,
-                element: Element {
-                    format: Format::R32Uint,
-                    offset: 0,
-                },
-            },
-        ];
-
         let data = PipelineData::new(
             self.device.clone(),
-            self.swapchains[swapchain_index].config.extent.to_extent(),
             &self.render_passes[render_pass_index],
-            vertex_buffers,
-            attributes,
+            desc,
+            self.samples,
+            self.max_push_constants_size,
         )?;
+        self.pipelines.push(data);
+        Ok(())
+    }
 
-        Ok(self.pipelines.push(data))
+    /// Rebuilds the pipeline at `index` from `desc`, keeping the old one if
+    /// that fails. Waits for the device to go idle first.
+    pub fn replace_graphics_pipeline(
+        &mut self,
+        index: usize,
+        render_pass_index: usize,
+        desc: PipelineDesc,
+    ) -> Result<(), Error> {
+        if index >= self.pipelines.len() {
+            return Err(Error::MissingPipeline(index));
+        }
+        let mut data = PipelineData::new(
+            self.device.clone(),
+            &self.render_passes[render_pass_index],
+            desc,
+            self.samples,
+            self.max_push_constants_size,
+        )?;
+        data.constants = mem::take(&mut self.pipelines[index].constants);
+
+        self.device
+            .wait_idle()
+            .map_err(|_| Error::FenceError(FenceOp::Wait))?;
+        self.pipelines[index] = data;
+        Ok(())
     }
 
-    pub fn reset_current_fence(&self, swapchain_index: usize) -> Result<(), Error> {
-        unsafe {
-            self.device
-                .wait_for_fence(
-                    &self.swapchains[swapchain_index]
-                        .fences
-                        .as_ref()
-                        .ok_or(Error::FenceError(FenceOp::Acquire))?
-                        [self.swapchains[swapchain_index].current_frame],
-                    u64::max_value(),
-                )
-                .map_err(|e| Error::FenceError(FenceOp::Wait))?;
-            self.device
-                .reset_fence(
-                    &self.swapchains[swapchain_index]
+    /// The size of what's rendered to, which is the offscreen target when
+    /// headless.
+    pub fn target_extent(&self) -> Option<Extent2D> {
+        match &self.offscreen {
+            Some(offscreen) => Some(offscreen.extent),
+            None => self
+                .swapchains
+                .first()
+                .map(|swapchain| swapchain.config.extent),
+        }
+    }
+
+    /// Waits until the frame about to be recorded has finished its last
+    /// submission, returning its index among the frames in flight. Headless
+    /// frames are waited for right away, so they're never in flight.
+    pub fn wait_for_frame(&self) -> Result<usize, Error> {
+        match self.swapchains.first() {
+            Some(swapchain) => {
+                swapchain.frames.wait_for_frame(
+                    &HalFences::new(&**self.device),
+                    swapchain
                         .fences
                         .as_ref()
-                        .ok_or(Error::FenceError(FenceOp::Acquire))?
-                        [self.swapchains[swapchain_index].current_frame],
-                )
-                .map_err(|e| Error::FenceError(FenceOp::Reset))?;
+                        .ok_or(Error::FenceError(FenceOp::Acquire))?,
+                )?;
+                Ok(swapchain.frames.current())
+            }
+            None => Ok(0),
         }
-        Ok(())
     }
 
     pub fn draw(
         &mut self,
         color: [f32; 4],
         resources: &ResourceManager<B, B::Device>,
+        camera: &Camera,
         command_buffers: &mut [B::CommandBuffer],
+        batches: &[DrawBatch],
     ) -> Result<(), Error> {
-        self.swapchains[0].advance_frame();
+        self.render(color, Some((resources, camera, batches)), command_buffers)
+    }
 
-        // as said in clear_frame, we do this twice to reset the fence after get_current_image signals it
-        self.reset_current_fence(0)?;
+    pub fn clear_frame(
+        &mut self,
+        color: [f32; 4],
+        command_buffers: &mut [B::CommandBuffer],
+    ) -> Result<(), Error> {
+        self.render(color, None, command_buffers)
+    }
 
-        let (i_u32, i_usize) = unsafe { self.swapchains[0].get_current_image()? };
+    // renders to the offscreen target if there is one, and to the swapchain
+    // otherwise
+    fn render(
+        &mut self,
+        color: [f32; 4],
+        geometry: Option<Geometry<'_, B>>,
+        command_buffers: &mut [B::CommandBuffer],
+    ) -> Result<(), Error> {
+        if self.offscreen.is_some() {
+            self.render_offscreen(color, geometry, command_buffers)
+        } else {
+            self.render_to_swapchain(color, geometry, command_buffers)
+        }
+    }
 
-        self.reset_current_fence(0)?;
+    // records a render pass clearing the framebuffer to `color`, which draws
+    // the given number of quads through the camera if there is any geometry
+    unsafe fn record(
+        &self,
+        buffer: &mut B::CommandBuffer,
+        framebuffer: &B::Framebuffer,
+        extent: Extent2D,
+        color: [f32; 4],
+        geometry: Option<Geometry<'_, B>>,
+    ) -> Result<(), Error> {
+        // look the pipelines up before starting to record
+        let pipelines = geometry.map_or(Ok(vec![]), |(_, _, batches)| {
+            batches
+                .iter()
+                .map(|batch| {
+                    let index = batch.material.index() as usize;
+                    self.pipelines
+                        .get(index)
+                        .ok_or(Error::MissingPipeline(index))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        let mut clear_values = ArrayVec::<[ClearValue; 2]>::new();
+        clear_values.push(ClearValue {
+            color: ClearColor { float32: color },
+        });
+        if self.depth_format.is_some() {
+            clear_values.push(ClearValue {
+                depth_stencil: ClearDepthStencil {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            });
+        }
+        let rect = extent.to_extent().rect();
+
+        buffer.reset(true);
+        buffer.begin_primary(CommandBufferFlags::ONE_TIME_SUBMIT);
+        buffer.begin_render_pass(
+            &self.render_passes[0],
+            framebuffer,
+            rect,
+            clear_values.iter(),
+            SubpassContents::Inline,
+        );
 
-        unsafe {
-            let clear_values = [ClearValue {
-                color: ClearColor { float32: color },
-            }];
+        if let Some((resources, camera, batches)) = geometry {
             let index_buffer_view = IndexBufferView {
                 buffer: &*resources.geometry_buffer.quad_index_buffer.buffer,
                 range: SubRange::WHOLE,
                 index_type: IndexType::U16,
             };
+            let view_projection = camera.view_projection(extent.width as f32, extent.height as f32);
 
-            let pipeline = &self
-                .pipelines
-                .get(0)
-                .ok_or(Error::MissingPipeline(0))?
-                .graphics_pipeline;
-            let buffer = &mut command_buffers[i_usize];
-
-            buffer.reset(true);
-            buffer.begin_primary(CommandBufferFlags::ONE_TIME_SUBMIT);
-            buffer.begin_render_pass(
-                &self.render_passes[0],
-                &self.swapchains[0].framebuffers[i_usize],
-                self.swapchains[0].config.extent.to_extent().rect(),
-                clear_values.iter(),
-                SubpassContents::Inline,
-            );
-            buffer.bind_graphics_pipeline(pipeline);
-            buffer.bind_index_buffer(index_buffer_view);
-            buffer.bind_vertex_buffers(
+            // the viewport and scissor are dynamic so the pipelines survive resizes
+            buffer.set_viewports(
                 0,
-                vec![
-                    (
-                        &*resources.geometry_buffer.quad_buffer.buffer,
-                        SubRange::WHOLE,
-                    ),
-                    (
-                        &*resources.geometry_buffer.quad_instance_buffer.buffer,
-                        SubRange::WHOLE,
-                    ),
-                ],
+                &[Viewport {
+                    rect,
+                    depth: 0.0..1.0,
+                }],
             );
-            buffer.draw_indexed(0..6, 0, 0..4);
-            buffer.end_render_pass();
-            buffer.finish();
+            buffer.set_scissors(0, [rect]);
+            buffer.bind_index_buffer(index_buffer_view);
+
+            let mut bound = None;
+            for (pipeline, batch) in pipelines.iter().zip(batches) {
+                // the sprites are read through the same bindings as the
+                // quads, from the ring buffer instead
+                if bound != Some(batch.source) {
+                    let (quads, instances) = match batch.source {
+                        QuadSource::Geometry => (
+                            &*resources.geometry_buffer.quad_buffer.buffer,
+                            &*resources.geometry_buffer.quad_instance_buffer.buffer,
+                        ),
+                        QuadSource::Sprites => (
+                            resources.sprites.quad_buffer(),
+                            resources.sprites.instance_buffer(),
+                        ),
+                    };
+                    buffer.bind_vertex_buffers(
+                        0,
+                        vec![(quads, SubRange::WHOLE), (instances, SubRange::WHOLE)],
+                    );
+                    bound = Some(batch.source);
+                }
+                buffer.bind_graphics_pipeline(&pipeline.graphics_pipeline);
+                buffer.bind_graphics_descriptor_sets(
+                    &pipeline.pipeline_layout,
+                    0,
+                    &pipeline.descriptor_sets,
+                    &[],
+                );
+                pipeline.push_constants(buffer, &view_projection);
+                buffer.draw_indexed(0..6, 0, batch.quads.clone());
+            }
         }
 
-        // SUBMISSION AND PRESENT
-        let command_buffers = &command_buffers[i_usize..=i_usize];
-        let wait_semaphores: ArrayVec<[_; 1]> = [(
-            &self.swapchains[0]
-                .available_semaphores
-                .as_ref()
-                .ok_or(Error::SubmissionError)?[self.swapchains[0].current_frame],
-            PipelineStage::COLOR_ATTACHMENT_OUTPUT,
-        )]
-        .into();
+        buffer.end_render_pass();
+        buffer.finish();
+        Ok(())
+    }
 
-        let signal_semaphores: ArrayVec<[_; 1]> = [&self.swapchains[0]
-            .finished_semaphores
-            .as_ref()
-            .ok_or(Error::SubmissionError)?[self.swapchains[0].current_frame]]
-        .into();
-        // yes, you have to write it twice like this. yes, it's silly.
-        let present_wait_semaphores: ArrayVec<[_; 1]> = [&self.swapchains[0]
-            .finished_semaphores
+    // there's nothing to present, so the frame is waited on right away, which
+    // also means nothing is ever in flight when the next frame starts
+    fn render_offscreen(
+        &mut self,
+        color: [f32; 4],
+        geometry: Option<Geometry<'_, B>>,
+        command_buffers: &mut [B::CommandBuffer],
+    ) -> Result<(), Error> {
+        let offscreen = self
+            .offscreen
             .as_ref()
-            .ok_or(Error::SubmissionError)?[self.swapchains[0].current_frame]]
-        .into();
-
-        let submission = Submission {
-            command_buffers,
-            wait_semaphores,
-            signal_semaphores,
-        };
-
-        let the_command_queue = &mut self.queue.queues[0];
+            .ok_or(Error::MissingOffscreenTarget)?;
 
         unsafe {
-            the_command_queue.submit(
-                submission,
-                Some(
-                    &self.swapchains[0]
-                        .fences
-                        .as_ref()
-                        .ok_or(Error::FenceError(FenceOp::Acquire))?
-                        [self.swapchains[0].current_frame],
-                ),
-            );
-            self.swapchains[0]
-                .swapchain
-                .present(the_command_queue, i_u32, present_wait_semaphores)
-                .map_err(|e| Error::SubmissionError)?
-        };
+            self.record(
+                &mut command_buffers[0],
+                &offscreen.framebuffer,
+                offscreen.extent,
+                color,
+                geometry,
+            )?;
 
+            self.device
+                .reset_fence(&offscreen.fence)
+                .map_err(|_| Error::FenceError(FenceOp::Reset))?;
+            self.queue.queues[0]
+                .submit_without_semaphores(&command_buffers[0..1], Some(&*offscreen.fence));
+            self.device
+                .wait_for_fence(&offscreen.fence, u64::MAX)
+                .map_err(|_| Error::FenceError(FenceOp::Wait))?;
+        }
+
+        if let Some(target) = &mut self.offscreen {
+            target.rendered = true;
+        }
         Ok(())
     }
 
-    pub fn clear_frame(
+    fn render_to_swapchain(
         &mut self,
         color: [f32; 4],
+        geometry: Option<Geometry<'_, B>>,
         command_buffers: &mut [B::CommandBuffer],
     ) -> Result<(), Error> {
-        // Advance the frame _before_ we start using the `?` operator
-        self.swapchains[0].advance_frame();
-
-        // we first reset the fence for get_current_image
-        self.reset_current_fence(0)?;
-
-        let (i_u32, i_usize) = unsafe { self.swapchains[0].get_current_image()? };
-
-        // we then reset the fence again because it was signalled by get_current_image and it needs to be unsignalled
-        self.reset_current_fence(0)?;
-
-        // RECORD COMMANDS
-        unsafe {
-            let buffer = &mut command_buffers[i_usize];
-
-            let clear_values = [ClearValue {
-                color: ClearColor { float32: color },
-            }];
-
-            buffer.begin_primary(CommandBufferFlags::ONE_TIME_SUBMIT);
-            buffer.begin_render_pass(
-                &self.render_passes[0],
-                &self.swapchains[0].framebuffers[i_usize],
-                self.swapchains[0].config.extent.to_extent().rect(),
-                clear_values.iter(),
-                SubpassContents::Inline,
-            );
-            buffer.end_render_pass();
-            buffer.finish();
-        }
-        {
-            // SUBMISSION AND PRESENT
-            let command_buffers = &command_buffers[i_usize..=i_usize];
-            let wait_semaphores: ArrayVec<[_; 1]> = [(
-                &self.swapchains[0]
-                    .available_semaphores
-                    .as_ref()
-                    .ok_or(Error::SubmissionError)?[self.swapchains[0].current_frame],
-                PipelineStage::COLOR_ATTACHMENT_OUTPUT,
-            )]
-            .into();
-
-            let signal_semaphores: ArrayVec<[_; 1]> = [&self.swapchains[0]
-                .finished_semaphores
+        let fences = HalFences::new(&**self.device);
+        let swapchain = &mut self.swapchains[0];
+        let frame = swapchain.frames.current();
+
+        // the frame's semaphores and command buffer have to be done with
+        // before acquiring, and the image once it's known which one it is
+        swapchain.frames.wait_for_frame(
+            &fences,
+            swapchain
+                .fences
                 .as_ref()
-                .ok_or(Error::SubmissionError)?[self.swapchains[0].current_frame]]
-            .into();
-            // yes, you have to write it twice like this. yes, it's silly.
-            let present_wait_semaphores: ArrayVec<[_; 1]> = [&self.swapchains[0]
-                .finished_semaphores
+                .ok_or(Error::FenceError(FenceOp::Acquire))?,
+        )?;
+        let image = unsafe { swapchain.acquire_image()? };
+        swapchain.frames.claim_image(
+            &fences,
+            swapchain
+                .fences
                 .as_ref()
-                .ok_or(Error::SubmissionError)?[self.swapchains[0].current_frame]]
-            .into();
+                .ok_or(Error::FenceError(FenceOp::Acquire))?,
+            image as usize,
+        )?;
 
-            let submission = Submission {
-                command_buffers,
-                wait_semaphores,
-                signal_semaphores,
-            };
+        let acquired = &self.swapchains[0];
+        unsafe {
+            self.record(
+                &mut command_buffers[frame],
+                &acquired.framebuffers[image as usize],
+                acquired.config.extent,
+                color,
+                geometry,
+            )?;
+        }
 
-            let the_command_queue = &mut self.queue.queues[0];
+        let available = &acquired
+            .available_semaphores
+            .as_ref()
+            .ok_or(Error::SubmissionError)?[frame];
+        let finished = &acquired
+            .finished_semaphores
+            .as_ref()
+            .ok_or(Error::SubmissionError)?[frame];
+        let submission = Submission {
+            command_buffers: &command_buffers[frame..=frame],
+            wait_semaphores: Some((available, PipelineStage::COLOR_ATTACHMENT_OUTPUT)),
+            signal_semaphores: Some(finished),
+        };
+        let fence = acquired.frames.submit_fence(
+            &fences,
+            acquired
+                .fences
+                .as_ref()
+                .ok_or(Error::FenceError(FenceOp::Acquire))?,
+        )?;
 
-            unsafe {
-                the_command_queue.submit(
-                    submission,
-                    Some(
-                        &self.swapchains[0]
-                            .fences
-                            .as_ref()
-                            .ok_or(Error::FenceError(FenceOp::Acquire))?
-                            [self.swapchains[0].current_frame],
-                    ),
-                );
-                self.swapchains[0]
-                    .swapchain
-                    .present(the_command_queue, i_u32, present_wait_semaphores)
-                    .map_err(|e| Error::SubmissionError)?
-            };
-        }
+        let queue = &mut self.queue.queues[0];
+        let presented = unsafe {
+            queue.submit(submission, Some(fence));
+            acquired.swapchain.present(queue, image, Some(finished))
+        };
 
-        Ok(())
+        self.swapchains[0].frames.advance();
+        self.swapchains[0].check_present(presented)
     }
 }
@@ -0,0 +1,332 @@
+use core::ops::Range;
+
+use crate::geometry::{Mat4, Quad};
+use crate::scene::SceneTree;
+
+use super::material::{DrawBatch, MaterialHandle, QuadSource};
+use super::TextureHandle;
+
+/// Everything recorded into a frame, drawn in the order it was recorded
+/// once the frame ends.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DrawList {
+    pub clear_color: [f32; 4],
+    // geometry that isn't part of the renderer's scene tree, written after
+    // it every frame, each quad with the index of its matrix in `matrices`
+    matrices: Vec<Mat4>,
+    quads: Vec<(u32, Quad)>,
+    // sprites go into the sprite ring buffer instead, all with the same
+    // identity matrix
+    sprites: Vec<Quad>,
+    sprite_matrix: Option<u32>,
+    draws: Vec<Draw>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Draw {
+    // the renderer's scene tree, which is only uploaded where it changed
+    Scene,
+    // a run of `DrawList::quads` or `DrawList::sprites`, which is only split
+    // up by texture in `DrawList::batches`
+    Immediate(DrawBatch),
+}
+
+impl DrawList {
+    pub fn new(clear_color: [f32; 4]) -> Self {
+        DrawList {
+            clear_color,
+            matrices: vec![],
+            quads: vec![],
+            sprites: vec![],
+            sprite_matrix: None,
+            draws: vec![],
+        }
+    }
+
+    pub fn draw_scene(&mut self) {
+        self.draws.push(Draw::Scene);
+    }
+
+    pub fn draws_scene(&self) -> bool {
+        self.draws.contains(&Draw::Scene)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.draws.is_empty()
+    }
+
+    pub fn matrices(&self) -> &[Mat4] {
+        &self.matrices
+    }
+
+    pub fn quads(&self) -> &[(u32, Quad)] {
+        &self.quads
+    }
+
+    pub fn sprites(&self) -> &[Quad] {
+        &self.sprites
+    }
+
+    /// The index in `matrices` of the matrix the sprites are drawn with.
+    pub fn sprite_matrix(&self) -> Option<u32> {
+        self.sprite_matrix
+    }
+
+    /// Adds `quads`, all transformed by `transform`.
+    pub fn push_quads(
+        &mut self,
+        material: MaterialHandle,
+        transform: Mat4,
+        quads: impl IntoIterator<Item = Quad>,
+    ) {
+        let matrix = self.matrices.len() as u32;
+        self.matrices.push(transform);
+
+        let start = self.quads.len() as u32;
+        self.quads
+            .extend(quads.into_iter().map(|quad| (matrix, quad)));
+        self.push_batch(
+            material,
+            start..self.quads.len() as u32,
+            QuadSource::Geometry,
+        );
+    }
+
+    /// Adds a sprite, whose corners are already where it's drawn.
+    pub fn push_sprite(&mut self, material: MaterialHandle, quad: Quad) {
+        if self.sprite_matrix.is_none() {
+            self.sprite_matrix = Some(self.matrices.len() as u32);
+            self.matrices.push(Mat4::identity());
+        }
+
+        let index = self.sprites.len() as u32;
+        self.sprites.push(quad);
+        self.push_batch(material, index..index + 1, QuadSource::Sprites);
+    }
+
+    /// Copies every cache and quad of `tree`, which has to be up to date,
+    /// along with its `batches` in the order they should be drawn.
+    pub fn push_tree(&mut self, tree: &SceneTree, batches: &[DrawBatch]) {
+        let first_matrix = self.matrices.len();
+        let first_quad = self.quads.len() as u32;
+        let (caches, quads) = tree.get_cache_and_quad_array();
+
+        for &(index, cache) in &caches {
+            let slot = first_matrix + **index;
+            if slot >= self.matrices.len() {
+                self.matrices.resize(slot + 1, Mat4::identity());
+            }
+            self.matrices[slot] = **cache;
+        }
+        self.quads.extend(
+            quads
+                .iter()
+                .map(|&(index, quad)| ((first_matrix + **index) as u32, **quad)),
+        );
+        for batch in batches {
+            self.push_batch(
+                batch.material,
+                first_quad + batch.quads.start..first_quad + batch.quads.end,
+                QuadSource::Geometry,
+            );
+        }
+    }
+
+    // extends the last batch if it's a run of the same material and source
+    // that ends where `quads` start
+    fn push_batch(&mut self, material: MaterialHandle, quads: Range<u32>, source: QuadSource) {
+        if quads.start == quads.end {
+            return;
+        }
+        match self.draws.last_mut() {
+            Some(Draw::Immediate(batch))
+                if batch.material == material
+                    && batch.source == source
+                    && batch.quads.end == quads.start =>
+            {
+                batch.quads.end = quads.end
+            }
+            _ => self.draws.push(Draw::Immediate(DrawBatch {
+                material,
+                texture: 0,
+                quads,
+                source,
+            })),
+        }
+    }
+
+    /// The batches to draw, given the scene's own and where the immediate
+    /// quads and the sprites were written. The immediate runs are split
+    /// wherever the texture `texture` looks up for their quads' handles
+    /// changes.
+    pub fn batches(
+        &self,
+        scene: &[DrawBatch],
+        first_quad: u32,
+        first_sprite: u32,
+        texture: impl Fn(TextureHandle) -> u32,
+    ) -> Vec<DrawBatch> {
+        let mut batches: Vec<DrawBatch> = vec![];
+        for draw in &self.draws {
+            match draw {
+                Draw::Scene => batches.extend_from_slice(scene),
+                Draw::Immediate(batch) => {
+                    for index in batch.quads.clone() {
+                        let (first, quad) = match batch.source {
+                            QuadSource::Geometry => (first_quad, &self.quads[index as usize].1),
+                            QuadSource::Sprites => (first_sprite, &self.sprites[index as usize]),
+                        };
+                        let sampled = texture(quad.texture);
+                        let slot = first + index;
+                        match batches.last_mut() {
+                            Some(last)
+                                if index != batch.quads.start
+                                    && last.texture == sampled
+                                    && last.quads.end == slot =>
+                            {
+                                last.quads.end = slot + 1
+                            }
+                            _ => batches.push(DrawBatch {
+                                texture: sampled,
+                                quads: slot..slot + 1,
+                                ..batch.clone()
+                            }),
+                        }
+                    }
+                }
+            }
+        }
+        batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+    use crate::scene::SceneNode;
+
+    fn quad() -> Quad {
+        Quad::new([
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ])
+    }
+
+    fn batch(material: MaterialHandle, quads: Range<u32>, source: QuadSource) -> DrawBatch {
+        DrawBatch {
+            material,
+            texture: 0,
+            quads,
+            source,
+        }
+    }
+
+    fn matrix_indices(list: &DrawList) -> Vec<u32> {
+        list.quads().iter().map(|&(matrix, _)| matrix).collect()
+    }
+
+    #[test]
+    fn draws_keep_the_order_they_were_recorded_in() {
+        let custom = MaterialHandle(1);
+        let mut list = DrawList::new([0.0; 4]);
+        list.push_quads(MaterialHandle::DEFAULT, Mat4::identity(), vec![quad()]);
+        list.push_quads(MaterialHandle::DEFAULT, Mat4::identity(), vec![quad()]);
+        list.draw_scene();
+        list.push_quads(custom, 2.0 * Mat4::identity(), vec![quad(), quad()]);
+
+        let scene = [batch(MaterialHandle::DEFAULT, 0..5, QuadSource::Geometry)];
+        assert_eq!(
+            list.batches(&scene, 5, 0, |_| 0),
+            vec![
+                batch(MaterialHandle::DEFAULT, 5..7, QuadSource::Geometry),
+                scene[0].clone(),
+                batch(custom, 7..9, QuadSource::Geometry),
+            ]
+        );
+        assert_eq!(list.matrices().len(), 3);
+        assert_eq!(matrix_indices(&list), vec![0, 1, 2, 2]);
+    }
+
+    #[test]
+    fn trees_are_copied_after_earlier_draws() {
+        let mut root = SceneNode::new(2.0 * Mat4::identity());
+        root.add_quad(quad());
+        root.add_quad(quad());
+        let mut tree = SceneTree::new(root);
+        tree.recompute_caches();
+
+        let mut list = DrawList::new([0.0; 4]);
+        list.push_quads(MaterialHandle::DEFAULT, Mat4::identity(), vec![quad()]);
+        list.push_tree(
+            &tree,
+            &[batch(MaterialHandle::DEFAULT, 0..2, QuadSource::Geometry)],
+        );
+
+        assert!(!list.draws_scene());
+        assert_eq!(
+            list.batches(&[], 0, 0, |_| 0),
+            vec![batch(MaterialHandle::DEFAULT, 0..3, QuadSource::Geometry)]
+        );
+        assert_eq!(list.matrices()[1], 2.0 * Mat4::identity());
+        assert_eq!(matrix_indices(&list), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn sprites_are_batched_until_something_else_is_drawn() {
+        let custom = MaterialHandle(1);
+        let mut list = DrawList::new([0.0; 4]);
+        for _ in 0..6 {
+            list.push_sprite(MaterialHandle::DEFAULT, quad());
+        }
+        list.push_sprite(custom, quad());
+        list.push_quads(MaterialHandle::DEFAULT, Mat4::identity(), vec![quad()]);
+        list.push_sprite(custom, quad());
+
+        assert_eq!(
+            list.batches(&[], 0, 100, |_| 0),
+            vec![
+                batch(MaterialHandle::DEFAULT, 100..106, QuadSource::Sprites),
+                batch(custom, 106..107, QuadSource::Sprites),
+                batch(MaterialHandle::DEFAULT, 0..1, QuadSource::Geometry),
+                batch(custom, 107..108, QuadSource::Sprites),
+            ]
+        );
+        assert_eq!(list.sprites().len(), 8);
+        assert_eq!(list.sprite_matrix(), Some(0));
+        assert_eq!(matrix_indices(&list), vec![1]);
+    }
+
+    #[test]
+    fn runs_are_split_where_the_texture_changes() {
+        // the first two handles are regions of the same atlas page
+        let texture = |handle: TextureHandle| [0, 1, 1, 2][handle.index() as usize];
+        let sprite = |handle| quad().with_texture(TextureHandle::new(handle));
+        let mut list = DrawList::new([0.0; 4]);
+        for &handle in &[1, 2, 3, 3, 1] {
+            list.push_sprite(MaterialHandle::DEFAULT, sprite(handle));
+        }
+        list.push_quads(
+            MaterialHandle::DEFAULT,
+            Mat4::identity(),
+            vec![quad(), sprite(2)],
+        );
+
+        let textured = |sampled, quads, source| DrawBatch {
+            texture: sampled,
+            ..batch(MaterialHandle::DEFAULT, quads, source)
+        };
+        assert_eq!(
+            list.batches(&[], 0, 0, texture),
+            vec![
+                textured(1, 0..2, QuadSource::Sprites),
+                textured(2, 2..4, QuadSource::Sprites),
+                textured(1, 4..5, QuadSource::Sprites),
+                textured(0, 0..1, QuadSource::Geometry),
+                textured(1, 1..2, QuadSource::Geometry),
+            ]
+        );
+    }
+}
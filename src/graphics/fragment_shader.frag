@@ -1,7 +1,16 @@
 #version 450
 
-layout(location = 0) out vec4 color;
+// has to match MAX_TEXTURES in resources/textures.rs
+layout (set = 1, binding = 0) uniform sampler2D textures[64];
+
+layout (location = 0) in vec2 uv;
+layout (location = 1) flat in uint texture_index;
+layout (location = 2) in vec4 tint;
+
+layout (location = 0) out vec4 color;
 
 void main() {
-  color = vec4(1.0);
+  // every quad of a draw samples the same texture, so the index is
+  // dynamically uniform
+  color = texture(textures[texture_index], uv) * tint;
 }
@@ -0,0 +1,282 @@
+use core::marker::PhantomData;
+
+use gfx_hal::{device::Device, Backend};
+
+use crate::error::{Error, FenceOp};
+
+// what the frame bookkeeping needs from the device, so it can be tested
+// without a GPU
+pub(crate) trait FenceDevice {
+    type Fence;
+
+    fn wait_for_fence(&self, fence: &Self::Fence) -> Result<(), Error>;
+    fn reset_fence(&self, fence: &Self::Fence) -> Result<(), Error>;
+}
+
+pub(crate) struct HalFences<'a, B: Backend, D: Device<B>>(&'a D, PhantomData<B>);
+
+impl<'a, B: Backend, D: Device<B>> HalFences<'a, B, D> {
+    pub fn new(device: &'a D) -> Self {
+        HalFences(device, PhantomData)
+    }
+}
+
+impl<'a, B: Backend, D: Device<B>> FenceDevice for HalFences<'a, B, D> {
+    type Fence = B::Fence;
+
+    fn wait_for_fence(&self, fence: &B::Fence) -> Result<(), Error> {
+        unsafe { self.0.wait_for_fence(fence, u64::MAX) }
+            .map(|_| ())
+            .map_err(|_| Error::FenceError(FenceOp::Wait))
+    }
+
+    fn reset_fence(&self, fence: &B::Fence) -> Result<(), Error> {
+        unsafe { self.0.reset_fence(fence) }.map_err(|_| Error::FenceError(FenceOp::Reset))
+    }
+}
+
+/// Keeps track of the frames in flight, each with its own fence, semaphores
+/// and command buffer, and of which frame last rendered to each swapchain
+/// image. The fences are signalled once a frame's submission finishes, and
+/// have to start out signalled.
+#[derive(Debug)]
+pub(crate) struct FrameTracker {
+    frames: usize,
+    current: usize,
+    // per swapchain image, the frame that last rendered to it
+    image_frames: Vec<Option<usize>>,
+}
+
+impl FrameTracker {
+    pub fn new(frames: usize, images: usize) -> Self {
+        FrameTracker {
+            frames: frames.max(1),
+            current: 0,
+            image_frames: vec![None; images],
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// The frame being recorded, whose sync objects and command buffer to use.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Waits until the current frame's last submission has finished, so its
+    /// semaphores and command buffer can be reused. Has to happen before
+    /// acquiring an image with its semaphore.
+    pub fn wait_for_frame<D: FenceDevice>(
+        &self,
+        device: &D,
+        fences: &[D::Fence],
+    ) -> Result<(), Error> {
+        device.wait_for_fence(&fences[self.current])
+    }
+
+    /// Waits until the other frames' last submissions have finished too, so
+    /// memory every frame reads from can be written, like when the copies of
+    /// the scene move. The current frame has to have been waited for already.
+    pub fn wait_for_all<D: FenceDevice>(
+        &self,
+        device: &D,
+        fences: &[D::Fence],
+    ) -> Result<(), Error> {
+        fences
+            .iter()
+            .enumerate()
+            .filter(|&(frame, _)| frame != self.current)
+            .try_for_each(|(_, fence)| device.wait_for_fence(fence))
+    }
+
+    /// Waits until the frame that last rendered to the acquired `image` has
+    /// finished, if that's another one, then hands the image to the current
+    /// frame.
+    pub fn claim_image<D: FenceDevice>(
+        &mut self,
+        device: &D,
+        fences: &[D::Fence],
+        image: usize,
+    ) -> Result<(), Error> {
+        if let Some(frame) = self.image_frames[image] {
+            if frame != self.current {
+                device.wait_for_fence(&fences[frame])?;
+            }
+        }
+        self.image_frames[image] = Some(self.current);
+        Ok(())
+    }
+
+    /// Resets the current frame's fence, right before submitting with it,
+    /// so an error before that can't leave it unsignalled for good.
+    pub fn submit_fence<'a, D: FenceDevice>(
+        &self,
+        device: &D,
+        fences: &'a [D::Fence],
+    ) -> Result<&'a D::Fence, Error> {
+        let fence = &fences[self.current];
+        device.reset_fence(fence)?;
+        Ok(fence)
+    }
+
+    /// Moves on to the next frame, once the current one was submitted.
+    pub fn advance(&mut self) {
+        self.current = (self.current + 1) % self.frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    use crate::geometry::{Mat4, Quad};
+    use crate::graphics::resources::geometry::SceneCopies;
+    use crate::scene::{SceneNode, SceneTree};
+
+    // fences are indices into `signalled`, and the GPU only finishes work
+    // when it's waited on
+    struct MockDevice {
+        signalled: RefCell<Vec<bool>>,
+        waits: Cell<usize>,
+    }
+
+    impl MockDevice {
+        fn new(fences: usize) -> Self {
+            MockDevice {
+                signalled: RefCell::new(vec![true; fences]),
+                waits: Cell::new(0),
+            }
+        }
+
+        fn is_signalled(&self, fence: usize) -> bool {
+            self.signalled.borrow()[fence]
+        }
+    }
+
+    impl FenceDevice for MockDevice {
+        type Fence = usize;
+
+        fn wait_for_fence(&self, &fence: &usize) -> Result<(), Error> {
+            self.waits.set(self.waits.get() + 1);
+            self.signalled.borrow_mut()[fence] = true;
+            Ok(())
+        }
+
+        fn reset_fence(&self, &fence: &usize) -> Result<(), Error> {
+            self.signalled.borrow_mut()[fence] = false;
+            Ok(())
+        }
+    }
+
+    // renders a frame to each of `images` in turn, checking that neither the
+    // frame's resources nor the image are used while a submission still is
+    fn render(
+        tracker: &mut FrameTracker,
+        device: &MockDevice,
+        images: &[usize],
+        image_fences: &mut [Option<usize>],
+    ) {
+        let fences = (0..tracker.frames()).collect::<Vec<_>>();
+        for &image in images {
+            tracker.wait_for_frame(device, &fences).unwrap();
+            assert!(device.is_signalled(tracker.current()));
+
+            tracker.claim_image(device, &fences, image).unwrap();
+            if let Some(fence) = image_fences[image] {
+                assert!(device.is_signalled(fence), "image {} still in use", image);
+            }
+
+            let &fence = tracker.submit_fence(device, &fences).unwrap();
+            assert_eq!(fence, tracker.current());
+            image_fences[image] = Some(fence);
+            tracker.advance();
+        }
+    }
+
+    #[test]
+    fn nothing_is_reused_before_its_fence_signals() {
+        let images = [0, 1, 2, 0, 2, 2, 1, 0, 1, 1, 2];
+        for frames in 1..=3 {
+            let device = MockDevice::new(frames);
+            let mut tracker = FrameTracker::new(frames, 3);
+            let mut image_fences = [None; 3];
+            render(&mut tracker, &device, &images, &mut image_fences);
+        }
+    }
+
+    #[test]
+    fn moved_geometry_is_written_once_no_frame_reads_it() {
+        let device = MockDevice::new(3);
+        let mut tracker = FrameTracker::new(3, 3);
+        let fences = [0, 1, 2];
+        for frame in 0..5 {
+            tracker.wait_for_frame(&device, &fences).unwrap();
+            tracker.wait_for_all(&device, &fences).unwrap();
+            for fence in 0..3 {
+                assert!(
+                    device.is_signalled(fence),
+                    "frame {} still in flight",
+                    fence
+                );
+            }
+            assert_eq!(device.waits.get(), 3 * (frame + 1));
+
+            tracker.submit_fence(&device, &fences).unwrap();
+            tracker.advance();
+        }
+    }
+
+    #[test]
+    fn scene_edits_only_wait_for_the_frame_writing_them() {
+        let device = MockDevice::new(3);
+        let mut tracker = FrameTracker::new(3, 3);
+        let fences = [0, 1, 2];
+        let mut root = SceneNode::new(Mat4::identity());
+        root.add_quad(Quad::default());
+        let mut scene = SceneTree::new(root);
+        let mut copies = SceneCopies::new(3);
+
+        for frame in 0..6 {
+            tracker.wait_for_frame(&device, &fences).unwrap();
+            scene.root_mut().get_quads_mut()[0].points[0].x = frame as f32;
+            scene.recompute_caches();
+            // only the first upload has to make room for the copies
+            let (_, moved) = copies.update(tracker.current(), &scene);
+            assert_eq!(moved, frame == 0);
+            if moved {
+                tracker.wait_for_all(&device, &fences).unwrap();
+            }
+            scene.unset_modifications();
+
+            // the other frames are left in flight once they've all started
+            if frame >= 3 {
+                for fence in 0..3 {
+                    assert_eq!(device.is_signalled(fence), fence == tracker.current());
+                }
+            }
+            assert_eq!(device.waits.get(), frame + 3);
+
+            tracker.submit_fence(&device, &fences).unwrap();
+            tracker.advance();
+        }
+    }
+
+    #[test]
+    fn frames_only_wait_on_what_they_reuse() {
+        let device = MockDevice::new(2);
+        let mut tracker = FrameTracker::new(2, 3);
+        let mut image_fences = [None; 3];
+
+        // each frame waits on its own fence, and nothing else while it gets
+        // the image it rendered to last
+        render(&mut tracker, &device, &[0, 1, 0, 1], &mut image_fences);
+        assert_eq!(device.waits.get(), 4);
+
+        // image 1 was last rendered by frame 1, but frame 0 is up next
+        render(&mut tracker, &device, &[1], &mut image_fences);
+        assert_eq!(device.waits.get(), 6);
+    }
+}
@@ -0,0 +1,447 @@
+use core::ops::Range;
+use std::mem;
+
+use gfx_hal::{
+    format::Format,
+    pso::{
+        AttributeDesc, BlendOp, BlendState, Comparison, DepthStencilDesc, DepthTest,
+        DescriptorSetLayoutBinding, Element, Factor, ShaderStageFlags, VertexBufferDesc,
+        VertexInputRate,
+    },
+};
+
+use super::pipeline_data::{builtin_descriptor_sets, CAMERA_PUSH_CONSTANTS_SIZE};
+use super::shaders::ShaderSource;
+use crate::error::{Error, PipelineDescError};
+use crate::geometry::{Quad, Vec2, Vec3, Vec4};
+
+/// Refers to a pipeline added with `Context::add_material`, which scene
+/// nodes select through their `material` field.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MaterialHandle(pub(crate) u32);
+
+impl MaterialHandle {
+    /// The pipeline built from the built-in shaders, which every node uses
+    /// unless told otherwise.
+    pub const DEFAULT: MaterialHandle = MaterialHandle(0);
+
+    pub fn index(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for MaterialHandle {
+    fn default() -> Self {
+        MaterialHandle::DEFAULT
+    }
+}
+
+/// How the colors a material draws are combined with what's already in
+/// the target. Anything but `Opaque` counts as transparent, and is drawn
+/// after all opaque quads, sorted back to front.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    /// Replaces the target, alpha included.
+    Opaque,
+    /// `src * src_alpha + dst * (1 - src_alpha)`, for straight alpha.
+    Alpha,
+    /// `src + dst * (1 - src_alpha)`, for colors already multiplied by
+    /// their alpha.
+    PremultipliedAlpha,
+    /// `src * src_alpha + dst`, brightening what's behind.
+    Additive,
+    /// `src * dst`, darkening what's behind.
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn is_transparent(self) -> bool {
+        self != BlendMode::Opaque
+    }
+
+    pub(crate) fn blend_state(self) -> Option<BlendState> {
+        match self {
+            BlendMode::Opaque => None,
+            BlendMode::Alpha => Some(BlendState::ALPHA),
+            BlendMode::PremultipliedAlpha => Some(BlendState::PREMULTIPLIED_ALPHA),
+            BlendMode::Additive => Some(BlendState {
+                color: BlendOp::Add {
+                    src: Factor::SrcAlpha,
+                    dst: Factor::One,
+                },
+                alpha: BlendOp::Add {
+                    src: Factor::Zero,
+                    dst: Factor::One,
+                },
+            }),
+            BlendMode::Multiply => Some(BlendState {
+                color: BlendOp::Add {
+                    src: Factor::DstColor,
+                    dst: Factor::Zero,
+                },
+                alpha: BlendOp::Add {
+                    src: Factor::Zero,
+                    dst: Factor::One,
+                },
+            }),
+        }
+    }
+}
+
+/// Everything a material's pipeline is built from. The default describes
+/// the built-in pipeline, so custom ones usually start from that:
+///
+/// ```ignore
+/// let desc = PipelineDesc {
+///     shaders: ShaderSource::Files { vertex, fragment },
+///     ..PipelineDesc::default()
+/// };
+/// ```
+///
+/// Every material draws from the same quad buffers, bound as binding 0 (one
+/// `Quad` per instance) and binding 1 (the depth-first index of its node).
+#[derive(Debug, Clone)]
+pub struct PipelineDesc {
+    pub shaders: ShaderSource,
+    pub vertex_buffers: Vec<VertexBufferDesc>,
+    pub attributes: Vec<AttributeDesc>,
+    pub blend: BlendMode,
+    /// Whether quads are hidden behind what was already drawn closer to the
+    /// camera. Needs a depth buffer, without one both depth flags are
+    /// ignored.
+    pub depth_test: bool,
+    /// Whether quads hide what's drawn behind them later. Usually turned off
+    /// for transparent materials, which are sorted back to front anyway.
+    pub depth_write: bool,
+    /// The layouts of the descriptor sets, which the renderer keeps pointed
+    /// at the node matrices (set 0) and the textures and their regions (set
+    /// 1). Only the stages seeing each binding may differ from the default.
+    /// Any sets after those are allocated and bound along with them, but
+    /// never written by the renderer.
+    pub descriptor_sets: Vec<Vec<DescriptorSetLayoutBinding>>,
+    /// Push constant ranges on top of the camera's view-projection matrix,
+    /// which takes up the first 64 bytes, so they have to start after it,
+    /// be aligned to 4 bytes and end within the device's limit. Since the
+    /// camera's range is the vertex shader's, and no two ranges may share a
+    /// stage, they can't include the vertex stage or each other's stages.
+    /// Filled from the words given to `Context::set_material_constants`, the
+    /// first going to offset 64.
+    pub push_constants: Vec<(ShaderStageFlags, Range<u32>)>,
+}
+
+impl Default for PipelineDesc {
+    fn default() -> Self {
+        PipelineDesc {
+            shaders: ShaderSource::Embedded,
+            vertex_buffers: quad_vertex_buffers(),
+            attributes: quad_attributes(),
+            blend: BlendMode::Opaque,
+            depth_test: true,
+            depth_write: true,
+            descriptor_sets: builtin_descriptor_sets(),
+            push_constants: vec![],
+        }
+    }
+}
+
+impl PipelineDesc {
+    pub(crate) fn depth_stencil(&self) -> DepthStencilDesc {
+        // equal depths pass so quads at the same z are layered in draw order
+        let depth = if self.depth_test || self.depth_write {
+            Some(DepthTest {
+                fun: if self.depth_test {
+                    Comparison::LessEqual
+                } else {
+                    Comparison::Always
+                },
+                write: self.depth_write,
+            })
+        } else {
+            None
+        };
+        DepthStencilDesc {
+            depth,
+            depth_bounds: false,
+            stencil: None,
+        }
+    }
+
+    // checks the parts the renderer relies on weren't changed, and that the
+    // push constants fit in `max_push_constants_size` bytes
+    pub(crate) fn validate(&self, max_push_constants_size: u32) -> Result<(), Error> {
+        let builtin = builtin_descriptor_sets();
+        let sets_match = self.descriptor_sets.len() >= builtin.len()
+            && self
+                .descriptor_sets
+                .iter()
+                .zip(&builtin)
+                .all(|(set, builtin)| {
+                    set.len() == builtin.len()
+                        && set.iter().zip(builtin).all(|(binding, builtin)| {
+                            binding.binding == builtin.binding
+                                && binding.ty == builtin.ty
+                                && binding.count == builtin.count
+                                && binding.immutable_samplers == builtin.immutable_samplers
+                        })
+                });
+        if !sets_match {
+            return Err(Error::InvalidPipelineDesc(
+                PipelineDescError::DescriptorSets,
+            ));
+        }
+
+        if self.push_constants.iter().any(|(_, range)| {
+            range.start < CAMERA_PUSH_CONSTANTS_SIZE
+                || range.end < range.start
+                || range.start % 4 != 0
+                || range.end % 4 != 0
+        }) {
+            return Err(Error::InvalidPipelineDesc(PipelineDescError::PushConstants));
+        }
+
+        let mut stages = ShaderStageFlags::VERTEX;
+        for (range_stages, _) in &self.push_constants {
+            if stages.intersects(*range_stages) {
+                return Err(Error::InvalidPipelineDesc(
+                    PipelineDescError::PushConstantStages,
+                ));
+            }
+            stages |= *range_stages;
+        }
+
+        if self
+            .push_constants
+            .iter()
+            .any(|(_, range)| range.end > max_push_constants_size)
+        {
+            return Err(Error::InvalidPipelineDesc(
+                PipelineDescError::PushConstantsSize(max_push_constants_size),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The vertex buffers every material draws from: the quads, and the
+/// depth-first index of the node each quad belongs to, both one per instance.
+pub fn quad_vertex_buffers() -> Vec<VertexBufferDesc> {
+    vec![
+        VertexBufferDesc {
+            binding: 0,
+            stride: mem::size_of::<Quad>() as u32,
+            rate: VertexInputRate::Instance(1),
+        },
+        VertexBufferDesc {
+            binding: 1,
+            stride: mem::size_of::<u32>() as u32,
+            rate: VertexInputRate::Instance(1),
+        },
+    ]
+}
+
+/// The attributes the built-in vertex shader reads: the corners at
+/// locations 0 to 3, the node index at 4, the texture at 5, the uvs at 6
+/// to 9 and the colors at 10 to 13. The shader picks the corner, uv and
+/// color of each vertex using the vertex index.
+pub fn quad_attributes() -> Vec<AttributeDesc> {
+    let attribute = |location, binding, format, offset| AttributeDesc {
+        location,
+        binding,
+        element: Element { format, offset },
+    };
+
+    let uvs_offset = mem::size_of::<[Vec3; 4]>() as u32;
+    let colors_offset = uvs_offset + mem::size_of::<[Vec2; 4]>() as u32;
+    // stored after the corners, uvs and colors
+    let texture_offset = colors_offset + mem::size_of::<[Vec4; 4]>() as u32;
+
+    let mut attributes = (0..4)
+        .map(|corner| {
+            attribute(
+                corner,
+                0,
+                Format::Rgb32Sfloat,
+                corner * mem::size_of::<Vec3>() as u32,
+            )
+        })
+        .collect::<Vec<_>>();
+    attributes.push(attribute(4, 1, Format::R32Uint, 0));
+    attributes.push(attribute(5, 0, Format::R32Uint, texture_offset));
+    attributes.extend((0..4).map(|corner| {
+        attribute(
+            6 + corner,
+            0,
+            Format::Rg32Sfloat,
+            uvs_offset + corner * mem::size_of::<Vec2>() as u32,
+        )
+    }));
+    attributes.extend((0..4).map(|corner| {
+        attribute(
+            10 + corner,
+            0,
+            Format::Rgba32Sfloat,
+            colors_offset + corner * mem::size_of::<Vec4>() as u32,
+        )
+    }));
+    attributes
+}
+
+// where the quads of a batch are read from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum QuadSource {
+    Geometry,
+    Sprites,
+}
+
+// a run of quads, consecutive in their source, drawn with one material.
+// They all sample the same texture, the index of which in the texture array
+// is then dynamically uniform within the draw, as the shaders require
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DrawBatch {
+    pub material: MaterialHandle,
+    pub texture: u32,
+    pub quads: Range<u32>,
+    pub source: QuadSource,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the least Vulkan guarantees
+    const MAX_PUSH_CONSTANTS_SIZE: u32 = 128;
+
+    fn validate(desc: &PipelineDesc) -> Result<(), Error> {
+        desc.validate(MAX_PUSH_CONSTANTS_SIZE)
+    }
+
+    fn with_push_constants(push_constants: Vec<(ShaderStageFlags, Range<u32>)>) -> PipelineDesc {
+        PipelineDesc {
+            push_constants,
+            ..PipelineDesc::default()
+        }
+    }
+
+    #[test]
+    fn additive_and_multiply_leave_the_target_alpha_alone() {
+        let add = |src, dst| BlendOp::Add { src, dst };
+        let keep_alpha = add(Factor::Zero, Factor::One);
+        assert_eq!(
+            BlendMode::Additive.blend_state(),
+            Some(BlendState {
+                color: add(Factor::SrcAlpha, Factor::One),
+                alpha: keep_alpha,
+            })
+        );
+        assert_eq!(
+            BlendMode::Multiply.blend_state(),
+            Some(BlendState {
+                color: add(Factor::DstColor, Factor::Zero),
+                alpha: keep_alpha,
+            })
+        );
+        assert_eq!(BlendMode::Opaque.blend_state(), None);
+    }
+
+    #[test]
+    fn depth_flags_pick_the_depth_test() {
+        let depth = |depth_test, depth_write| {
+            PipelineDesc {
+                depth_test,
+                depth_write,
+                ..PipelineDesc::default()
+            }
+            .depth_stencil()
+            .depth
+        };
+        let test = |fun, write| Some(DepthTest { fun, write });
+
+        assert_eq!(depth(true, true), test(Comparison::LessEqual, true));
+        assert_eq!(depth(true, false), test(Comparison::LessEqual, false));
+        assert_eq!(depth(false, true), test(Comparison::Always, true));
+        assert_eq!(depth(false, false), None);
+    }
+
+    #[test]
+    fn default_desc_is_valid() {
+        validate(&PipelineDesc::default()).unwrap();
+    }
+
+    #[test]
+    fn rejects_changes_the_renderer_relies_on() {
+        let mut desc = PipelineDesc::default();
+        desc.descriptor_sets[0][0].stage_flags = ShaderStageFlags::ALL;
+        validate(&desc).unwrap();
+
+        desc.descriptor_sets[1].pop();
+        assert!(matches!(
+            validate(&desc),
+            Err(Error::InvalidPipelineDesc(
+                PipelineDescError::DescriptorSets
+            ))
+        ));
+
+        let overlapping = with_push_constants(vec![(ShaderStageFlags::FRAGMENT, 0..16)]);
+        assert!(matches!(
+            validate(&overlapping),
+            Err(Error::InvalidPipelineDesc(PipelineDescError::PushConstants))
+        ));
+    }
+
+    #[test]
+    fn extra_descriptor_sets_are_allowed() {
+        let mut desc = PipelineDesc::default();
+        desc.descriptor_sets.push(vec![]);
+        desc.descriptor_sets.push(desc.descriptor_sets[0].clone());
+        validate(&desc).unwrap();
+
+        desc.descriptor_sets.swap(0, 2);
+        desc.descriptor_sets[0].clear();
+        assert!(matches!(
+            validate(&desc),
+            Err(Error::InvalidPipelineDesc(
+                PipelineDescError::DescriptorSets
+            ))
+        ));
+    }
+
+    #[test]
+    fn push_constant_ranges_have_stages_of_their_own() {
+        let fragment = with_push_constants(vec![(ShaderStageFlags::FRAGMENT, 64..80)]);
+        validate(&fragment).unwrap();
+
+        for stages in &[ShaderStageFlags::VERTEX, ShaderStageFlags::ALL] {
+            assert!(matches!(
+                validate(&with_push_constants(vec![(*stages, 64..80)])),
+                Err(Error::InvalidPipelineDesc(
+                    PipelineDescError::PushConstantStages
+                ))
+            ));
+        }
+        let shared = with_push_constants(vec![
+            (ShaderStageFlags::FRAGMENT, 64..80),
+            (ShaderStageFlags::FRAGMENT, 80..96),
+        ]);
+        assert!(matches!(
+            validate(&shared),
+            Err(Error::InvalidPipelineDesc(
+                PipelineDescError::PushConstantStages
+            ))
+        ));
+    }
+
+    #[test]
+    fn push_constant_ranges_end_within_the_limit() {
+        let exact = with_push_constants(vec![(ShaderStageFlags::FRAGMENT, 64..128)]);
+        validate(&exact).unwrap();
+
+        let past = with_push_constants(vec![(ShaderStageFlags::FRAGMENT, 64..132)]);
+        assert!(matches!(
+            validate(&past),
+            Err(Error::InvalidPipelineDesc(
+                PipelineDescError::PushConstantsSize(128)
+            ))
+        ));
+        past.validate(256).unwrap();
+    }
+}
@@ -4,14 +4,14 @@
     clippy::copy_iterator,
     clippy::default_trait_access,
     clippy::doc_markdown,
-    clippy::empty_enum,
+    clippy::empty_enums,
     clippy::enum_glob_use,
     clippy::expl_impl_clone_on_copy,
     clippy::explicit_into_iter_loop,
     clippy::explicit_iter_loop,
-    clippy::filter_map,
+    clippy::manual_filter_map,
     clippy::filter_map_next,
-    clippy::find_map,
+    clippy::manual_find_map,
     clippy::if_not_else,
     clippy::inline_always,
     clippy::items_after_statements,
@@ -25,7 +25,6 @@
     clippy::needless_pass_by_value,
     clippy::non_ascii_literal,
     clippy::map_unwrap_or,
-    clippy::pub_enum_variant_names,
     clippy::redundant_closure_for_method_calls,
     clippy::same_functions_in_if_condition,
     clippy::shadow_unrelated,
@@ -39,30 +38,57 @@
     clippy::used_underscore_binding
 )]
 
+mod adapters;
+mod attachment;
 mod device_data;
+mod draw_list;
+mod frames;
+pub(crate) mod material;
+mod offscreen_data;
 mod pipeline_data;
 mod resources;
+mod settings;
+mod shaders;
 mod swapchain_data;
 
-use crate::error::Error;
+use crate::error::{Error, SwapchainError};
+use crate::geometry::{Camera, Frustum};
+use crate::scene::CullStats;
 use device_data::DeviceData;
-use pipeline_data::PipelineData;
+pub(crate) use draw_list::DrawList;
+use frames::HalFences;
+use material::DrawBatch;
+use offscreen_data::OffscreenData;
 use resources::ResourceManager;
 use swapchain_data::SwapchainData;
 
+pub use adapters::{AdapterDetails, MemoryHeap};
+pub use gfx_hal::adapter::{AdapterInfo, DeviceType};
+pub use gfx_hal::format::Format;
+pub use gfx_hal::image::Filter;
+pub use gfx_hal::window::PresentMode;
+pub use gfx_hal::Features;
+pub use material::{quad_attributes, quad_vertex_buffers, BlendMode, MaterialHandle, PipelineDesc};
+pub use resources::atlas::{AtlasBuilder, AtlasPage, AtlasRegion};
+pub use resources::geometry::UploadStats;
+pub use resources::textures::TextureHandle;
+pub use settings::{AdapterPreference, Choices, Settings};
+pub use shaders::ShaderSource;
+
 use std::mem::{self, ManuallyDrop};
 use std::rc::Rc;
 
-use log::{error, info};
+use log::{error, info, warn};
 
 use gfx_hal::{
     adapter::{Adapter, Gpu, PhysicalDevice},
     device::Device as DeviceTrait,
-    format::{ChannelType, Format},
+    format::ChannelType,
+    image::{Layout, NumSamples},
     pool::{CommandPool, CommandPoolCreateFlags},
     queue::QueueFamily as QueueFamilyTrait,
-    window::{Surface, SwapchainConfig},
-    Backend, Features, Instance,
+    window::{Extent2D, Surface, SwapchainConfig},
+    Backend, Instance,
 };
 
 use raw_window_handle::HasRawWindowHandle;
@@ -97,67 +123,202 @@ impl<B: Backend> CommandData<B> {
 pub struct Context<B: Backend> {
     resources: Vec<ResourceManager<B, B::Device>>,
     instance: ManuallyDrop<B::Instance>,
-    surface: ManuallyDrop<B::Surface>,
+    // headless contexts have no surface, and render to an offscreen target
+    surface: Option<B::Surface>,
     adapters: Vec<Adapter<B>>,
     devices: Vec<DeviceData<B>>,
     command_pools: Vec<CommandData<B>>,
+    // the size passed to `resize`, used when the surface doesn't dictate one
+    extent: Option<Extent2D>,
+    settings: Settings,
+    last_cull: CullStats,
 }
 
 impl<B: Backend> Context<B> {
-    pub fn build<W: HasRawWindowHandle>(window: &W, name: &str) -> Result<Self, Error> {
+    pub fn build<W: HasRawWindowHandle>(
+        window: &W,
+        name: &str,
+        settings: &Settings,
+    ) -> Result<Self, Error> {
         let mut context = Self::from_window(window, name)?;
+        context.settings = settings.clone();
         context.add_device()?;
         context.add_swapchain(0)?;
         context.add_semaphores(0, 0)?;
-        context.devices[0].add_render_pass()?;
+        let format = context.devices[0].swapchains[0].config.format;
+        let depth_format = context.depth_format(0);
+        let samples = context.sample_count(0, depth_format.is_some())?;
+        context.devices[0].add_render_pass(format, Layout::Present, depth_format, samples)?;
         context.devices[0].add_image_views(0)?;
+        context.add_attachment_images(0, 0)?;
         context.devices[0].add_framebuffers(0, 0)?;
         context.add_command_pool(0)?;
-        context.devices[0].add_graphics_pipeline(0, 0)?;
+        context.devices[0].add_graphics_pipeline(0, PipelineDesc::default())?;
+        context.add_resource_manager(0, 0)?;
+        context.resources[0].textures.take_changed();
+        context.write_descriptor_sets(0);
+
+        info!(target: "rmge", "built context: {:?}", context.choices());
+        Ok(context)
+    }
+
+    /// Builds a context without a window, which renders into an offscreen
+    /// image of the given size that can be read back with `read_pixels`.
+    /// Neither side can be zero.
+    pub fn headless(width: u32, height: u32, settings: &Settings) -> Result<Self, Error> {
+        if width == 0 || height == 0 {
+            return Err(Error::EmptyExtent(width, height));
+        }
+        let raw_instance = B::Instance::create("rmge", 1).map_err(Error::InstanceCreationError)?;
+
+        let mut context = Self::from_instance(raw_instance, None);
+        context.settings = settings.clone();
+        let extent = Extent2D { width, height };
+        context.extent = Some(extent);
+        context.add_device()?;
+        let depth_format = context.depth_format(0);
+        let samples = context.sample_count(0, depth_format.is_some())?;
+        context.devices[0].add_render_pass(
+            offscreen_data::FORMAT,
+            Layout::TransferSrcOptimal,
+            depth_format,
+            samples,
+        )?;
+        context.add_offscreen_target(0, extent)?;
+        context.add_command_pool(0)?;
+        context.devices[0].add_graphics_pipeline(0, PipelineDesc::default())?;
         context.add_resource_manager(0, 0)?;
+        context.resources[0].textures.take_changed();
+        context.write_descriptor_sets(0);
 
+        info!(target: "rmge", "built headless context: {:?}", context.choices());
         Ok(context)
     }
 
+    /// Lists the adapters a headless context could render with.
+    pub fn available_adapters() -> Result<Vec<AdapterDetails>, Error> {
+        let raw_instance = B::Instance::create("rmge", 1).map_err(Error::InstanceCreationError)?;
+        Ok(Self::from_instance(raw_instance, None).adapters())
+    }
+
+    /// Lists the adapters that could render to `window`.
+    pub fn available_adapters_for<W: HasRawWindowHandle>(
+        window: &W,
+        name: &str,
+    ) -> Result<Vec<AdapterDetails>, Error> {
+        Ok(Self::from_window(window, name)?.adapters())
+    }
+
+    /// The adapters this context could render with, in the order the
+    /// backend lists them.
+    pub fn adapters(&self) -> Vec<AdapterDetails> {
+        self.adapters
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| !a.queue_families.is_empty())
+            .map(|(index, a)| AdapterDetails::new(index, a))
+            .collect()
+    }
+
+    /// What the context ended up with, given the settings it was built with.
+    pub fn choices(&self) -> Choices {
+        let device_data = &self.devices[0];
+        let swapchain_config = device_data
+            .swapchains
+            .first()
+            .map(|swapchain| &swapchain.config);
+        Choices {
+            adapter: self.adapters[device_data.adapter_index].info.clone(),
+            present_mode: swapchain_config.map(|config| config.present_mode),
+            format: swapchain_config.map_or(offscreen_data::FORMAT, |config| config.format),
+            image_count: swapchain_config.map_or(1, |config| config.image_count),
+            frames_in_flight: device_data.frames_in_flight,
+            depth_format: device_data.depth_format,
+            samples: device_data.samples,
+            features: self.settings.features,
+        }
+    }
+
     pub fn from_window<W: HasRawWindowHandle>(window: &W, name: &str) -> Result<Self, Error> {
-        let raw_instance =
-            B::Instance::create(name, 1).map_err(|e| Error::InstanceCreationError(e))?;
+        let raw_instance = B::Instance::create(name, 1).map_err(Error::InstanceCreationError)?;
 
         let surface = unsafe {
             raw_instance
                 .create_surface(window)
-                .map_err(|e| Error::SurfaceCreationError(e))?
+                .map_err(Error::SurfaceCreationError)?
         };
 
+        Ok(Self::from_instance(raw_instance, Some(surface)))
+    }
+
+    // keeps the queue families that can render, and present to the surface if
+    // there is one. Adapters left without any stay in the list, so indices
+    // match the backend's, but are never picked
+    fn from_instance(raw_instance: B::Instance, surface: Option<B::Surface>) -> Self {
         let adapters = raw_instance
             .enumerate_adapters()
             .into_iter()
             .map(|mut a| {
-                a.queue_families = a
-                    .queue_families
-                    .into_iter()
-                    .filter(|qf| {
-                        qf.queue_type().supports_graphics() && surface.supports_queue_family(qf)
-                    })
-                    .collect();
+                a.queue_families.retain(|qf| {
+                    qf.queue_type().supports_graphics()
+                        && surface
+                            .as_ref()
+                            .is_none_or(|surface| surface.supports_queue_family(qf))
+                });
                 a
             })
-            .filter(|a| !a.queue_families.is_empty())
             .collect::<Vec<_>>();
 
-        Ok(Self {
+        Self {
             instance: ManuallyDrop::new(raw_instance),
-            surface: ManuallyDrop::new(surface),
+            surface,
             adapters,
             resources: vec![],
             devices: vec![],
             command_pools: vec![],
-        })
+            extent: None,
+            settings: Settings::default(),
+            last_cull: CullStats::default(),
+        }
+    }
+
+    // points a new pipeline at the matrix buffer and the textures, which the
+    // draw calls keep up to date from then on
+    fn write_descriptor_sets(&self, pipeline_index: usize) {
+        let pipeline = &self.devices[0].pipelines[pipeline_index];
+        let resources = &self.resources[0];
+
+        pipeline.write_matrix_descriptor(&resources.geometry_buffer.matrix_buffer.buffer);
+        pipeline.write_texture_descriptors(
+            resources.textures.descriptors(),
+            resources.textures.region_buffer(),
+        );
     }
 
     fn add_device(&mut self) -> Result<(), Error> {
         use crate::error::QueueGroupError;
 
+        // the fragment shader indexes the texture array with the texture of
+        // the draw batch, which is the same for the whole draw but not known
+        // when compiling it
+        let features =
+            self.settings.features | Features::SHADER_SAMPLED_IMAGE_ARRAY_DYNAMIC_INDEXING;
+        let preference = &self.settings.adapter;
+        let infos = self.adapters.iter().map(|a| &a.info).collect::<Vec<_>>();
+        let candidates =
+            adapters::candidate_order(&infos, preference, self.settings.adapter_fallback)
+                .into_iter()
+                .filter(|&index| {
+                    self.adapters[index]
+                        .physical_device
+                        .features()
+                        .contains(features)
+                })
+                .collect::<Vec<_>>();
+        if candidates.is_empty() && !self.settings.adapter_fallback {
+            return Err(Error::NoMatchingAdapter);
+        }
+
         let (
             index,
             Gpu {
@@ -165,21 +326,28 @@ impl<B: Backend> Context<B> {
                 queue_groups,
             },
             _family,
-        ) = self
-            .adapters
-            .iter()
-            .enumerate()
-            .find_map(|(index, a)| {
+        ) = candidates
+            .into_iter()
+            .find_map(|index| {
+                let a = &self.adapters[index];
                 a.queue_families.iter().find_map(|qf| unsafe {
                     a.physical_device
-                        .open(&[(&qf, &[1.0; 1])], Features::empty())
+                        .open(&[(qf, &[1.0; 1])], features)
                         .ok()
                         .map(|gpu| (index, gpu, qf))
                 })
             })
-            .ok_or(Error::QueueGroupError(QueueGroupError::QueueGroupNotFound))?;
-
-        info!("Chosen adapter name: {}", self.adapters[index].info.name);
+            .ok_or(if self.settings.adapter_fallback {
+                Error::QueueGroupError(QueueGroupError::QueueGroupNotFound)
+            } else {
+                Error::NoMatchingAdapter
+            })?;
+
+        let info = &self.adapters[index].info;
+        if !preference.matches(index, info) {
+            warn!(target: "rmge", "no adapter matches {:?}, falling back to {}", preference, info.name);
+        }
+        info!("Chosen adapter name: {}", info.name);
 
         // TODO: Make this good
         let queue_group = queue_groups
@@ -191,93 +359,248 @@ impl<B: Backend> Context<B> {
             return Err(Error::QueueGroupError(QueueGroupError::NoCommandQueues));
         };
 
-        self.devices
-            .push(DeviceData::from(index, device, queue_group));
+        let limits = self.adapters[index].physical_device.limits();
+        self.devices.push(DeviceData::from(
+            index,
+            device,
+            queue_group,
+            self.settings.frames_in_flight,
+            limits.max_push_constants_size as u32,
+        ));
 
         Ok(())
     }
 
-    fn add_swapchain(&mut self, device_index: usize) -> Result<(), Error> {
-        let DeviceData {
-            adapter_index,
-            device,
-            ..
-        } = self
+    fn swapchain_config(&self, device_index: usize) -> Result<SwapchainConfig, Error> {
+        let adapter_index = &self
             .devices
             .get(device_index)
-            .ok_or(Error::MissingDevice(device_index))?;
+            .ok_or(Error::MissingDevice(device_index))?
+            .adapter_index;
 
-        let surface_capabilities = self
-            .surface
-            .capabilities(&self.adapters[*adapter_index].physical_device);
+        let surface = self.surface.as_ref().ok_or(Error::MissingSurface)?;
+        let surface_capabilities =
+            surface.capabilities(&self.adapters[*adapter_index].physical_device);
 
-        let &present_mode = {
-            use gfx_hal::window::PresentMode;
+        let present_mode = {
             let present_modes = surface_capabilities.present_modes;
 
-            [
-                PresentMode::MAILBOX,
-                PresentMode::FIFO,
-                PresentMode::RELAXED,
-                PresentMode::IMMEDIATE,
-            ]
-            .iter()
-            .find(|pm| present_modes.contains(**pm))
-            .ok_or(Error::SwapchainError(
-                crate::error::SwapchainError::NoPresentMode,
-            ))?
+            self.settings
+                .present_modes
+                .iter()
+                .chain(&settings::default_present_modes())
+                .copied()
+                .find(|&pm| present_modes.contains(pm))
+                .ok_or(Error::SwapchainError(SwapchainError::NoPresentMode))?
         };
 
         info!("Chosen present mode: {:?}", present_mode);
 
-        let preferred_formats = self
-            .surface
-            .supported_formats(&self.adapters[*adapter_index].physical_device);
+        let preferred_formats =
+            surface.supported_formats(&self.adapters[*adapter_index].physical_device);
 
         let format = match preferred_formats {
-            None => Format::Rgba8Srgb,
-            Some(formats) => match formats
+            None => self
+                .settings
+                .formats
+                .first()
+                .copied()
+                .unwrap_or(Format::Rgba8Srgb),
+            Some(formats) => match self
+                .settings
+                .formats
                 .iter()
-                .find(|format| format.base_format().1 == ChannelType::Srgb)
+                .find(|format| formats.contains(format))
+                .or_else(|| {
+                    formats
+                        .iter()
+                        .find(|format| format.base_format().1 == ChannelType::Srgb)
+                })
                 .cloned()
             {
                 Some(srgb_format) => srgb_format,
-                None => formats.get(0).cloned().ok_or(Error::SwapchainError(
-                    crate::error::SwapchainError::NoPresentMode,
-                ))?,
+                None => formats
+                    .first()
+                    .cloned()
+                    .ok_or(Error::SwapchainError(SwapchainError::NoPresentMode))?,
             },
         };
 
-        let swapchain_config = SwapchainConfig::from_caps(
-            &surface_capabilities,
-            format,
-            *surface_capabilities.extents.end(),
+        // most surfaces dictate the extent, otherwise we go with whatever we
+        // were resized to, as long as the surface supports it
+        let extent = surface_capabilities.current_extent.unwrap_or_else(|| {
+            let (min, max) = (
+                surface_capabilities.extents.start(),
+                surface_capabilities.extents.end(),
+            );
+            match self.extent {
+                Some(Extent2D { width, height }) => Extent2D {
+                    width: width.max(min.width).min(max.width),
+                    height: height.max(min.height).min(max.height),
+                },
+                None => *max,
+            }
+        });
+
+        Ok(
+            SwapchainConfig::from_caps(&surface_capabilities, format, extent)
+                .with_present_mode(present_mode),
         )
-        .with_present_mode(present_mode);
+    }
+
+    fn add_swapchain(&mut self, device_index: usize) -> Result<(), Error> {
+        let swapchain_config = self.swapchain_config(device_index)?;
+        let device = &self.devices[device_index].device;
+        let surface = self.surface.as_mut().ok_or(Error::MissingSurface)?;
 
         let (swapchain, backbuffer) = unsafe {
             device
-                .create_swapchain(&mut self.surface, swapchain_config.clone(), None)
-                .map_err(|e| {
-                    Error::SwapchainError(crate::error::SwapchainError::CreationError(e))
-                })?
+                .create_swapchain(surface, swapchain_config.clone(), None)
+                .map_err(|e| Error::SwapchainError(SwapchainError::CreationError(e)))?
         };
-        let device = self.devices[0].device.clone();
+        let device = device.clone();
 
-        self.devices[0].swapchains.push(SwapchainData::from(
-            device,
-            swapchain,
-            backbuffer,
-            swapchain_config,
-            None,
-            None,
-            None,
-            None,
-            vec![],
-        ));
+        self.devices[device_index]
+            .swapchains
+            .push(SwapchainData::from(
+                device,
+                swapchain,
+                backbuffer,
+                swapchain_config,
+                None,
+                None,
+                None,
+                None,
+                vec![],
+            ));
         Ok(())
     }
 
+    /// Replaces a swapchain with a new one matching the surface, along with
+    /// its image views, framebuffers and sync objects. The command buffers
+    /// belong to the frames in flight, so they're kept.
+    fn recreate_swapchain(
+        &mut self,
+        device_index: usize,
+        swapchain_index: usize,
+        config: SwapchainConfig,
+    ) -> Result<(), Error> {
+        let device_data = self
+            .devices
+            .get_mut(device_index)
+            .ok_or(Error::MissingDevice(device_index))?;
+        if swapchain_index >= device_data.swapchains.len() {
+            return Err(Error::MissingSwapchain(swapchain_index));
+        }
+        let surface = self.surface.as_mut().ok_or(Error::MissingSurface)?;
+
+        // nothing may still be using the old images or sync objects
+        device_data
+            .device
+            .wait_idle()
+            .map_err(|_| Error::FenceError(crate::error::FenceOp::Wait))?;
+
+        let old = device_data.swapchains.remove(swapchain_index);
+        let old_config = old.config.clone();
+        let old_swapchain = unsafe { old.destroy_resources() };
+        let created = unsafe {
+            device_data
+                .device
+                .create_swapchain(surface, config.clone(), Some(old_swapchain))
+        };
+
+        // the old swapchain is retired either way, so if the new one can't be
+        // created, the device gets one like the old one back before the error
+        // is returned, rather than being left without any
+        let (swapchain, backbuffer, config, result) = match created {
+            Ok((swapchain, backbuffer)) => {
+                info!(target: "rmge", "recreated swapchain with extent {:?}", config.extent);
+                (swapchain, backbuffer, config, Ok(()))
+            }
+            Err(error) => {
+                warn!(target: "rmge", "couldn't recreate swapchain: {:?}", error);
+                let (swapchain, backbuffer) = unsafe {
+                    device_data
+                        .device
+                        .create_swapchain(surface, old_config.clone(), None)
+                        .map_err(|e| Error::SwapchainError(SwapchainError::CreationError(e)))?
+                };
+                (
+                    swapchain,
+                    backbuffer,
+                    old_config,
+                    Err(Error::SwapchainError(SwapchainError::CreationError(error))),
+                )
+            }
+        };
+
+        device_data.swapchains.insert(
+            swapchain_index,
+            SwapchainData::from(
+                device_data.device.clone(),
+                swapchain,
+                backbuffer,
+                config,
+                None,
+                None,
+                None,
+                None,
+                vec![],
+            ),
+        );
+        device_data.add_semaphores(swapchain_index)?;
+        device_data.add_image_views(swapchain_index)?;
+        device_data
+            .add_attachment_images(swapchain_index, &self.adapters[device_data.adapter_index])?;
+        device_data.add_framebuffers(swapchain_index, 0)?;
+
+        result
+    }
+
+    /// Sets the size the swapchain should have, it'll be recreated before
+    /// the next frame. A size of zero means there's nothing to draw to,
+    /// e.g. because the window was minimized, and frames will be skipped.
+    /// Headless contexts keep the size they were built with.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.extent = Some(Extent2D { width, height });
+        for swapchain in self
+            .devices
+            .iter_mut()
+            .flat_map(|device_data| device_data.swapchains.iter_mut())
+        {
+            swapchain.needs_recreation = true;
+        }
+    }
+
+    // recreates the swapchain if it was resized or went out of date, returns
+    // false if the frame should be skipped since there's nothing to draw to
+    fn prepare_swapchain(&mut self) -> Result<bool, Error> {
+        if self.surface.is_none() {
+            return Ok(true);
+        }
+
+        let needs_recreation = self
+            .devices
+            .first()
+            .ok_or(Error::MissingDevice(0))?
+            .swapchains
+            .first()
+            .ok_or(Error::MissingSwapchain(0))?
+            .needs_recreation;
+        if !needs_recreation {
+            return Ok(true);
+        }
+
+        let is_empty = |extent: Extent2D| extent.width == 0 || extent.height == 0;
+        let config = self.swapchain_config(0)?;
+        if self.extent.is_some_and(is_empty) || is_empty(config.extent) {
+            return Ok(false);
+        }
+
+        self.recreate_swapchain(0, 0, config)?;
+        Ok(true)
+    }
+
     fn add_resource_manager(
         &mut self,
         device_index: usize,
@@ -293,23 +616,87 @@ impl<B: Backend> Context<B> {
             .get_mut(command_pool_index)
             .ok_or(Error::MissingCommandPool(command_pool_index))?;
 
-        let queue = &mut self
+        let device_data = self
             .devices
             .get_mut(device_index)
-            .ok_or(Error::MissingDevice(device_index))?
-            .queue
-            .queues[0];
+            .ok_or(Error::MissingDevice(device_index))?;
+        let frames_in_flight = device_data.frames_in_flight;
+        let queue = &mut device_data.queue.queues[0];
 
         self.resources.push(ResourceManager::new(
             command_pool.device.clone(),
             adapter,
             &mut command_pool.command_pool,
             queue,
+            self.settings.max_matrices,
+            self.settings.max_quads,
+            self.settings.max_sprites,
+            frames_in_flight,
         )?);
 
         Ok(())
     }
 
+    fn add_offscreen_target(&mut self, device_index: usize, extent: Extent2D) -> Result<(), Error> {
+        let device_data = self
+            .devices
+            .get_mut(device_index)
+            .ok_or(Error::MissingDevice(device_index))?;
+
+        device_data.offscreen = Some(OffscreenData::new(
+            device_data.device.clone(),
+            &self.adapters[device_data.adapter_index],
+            &device_data.render_passes[0],
+            extent,
+            device_data.depth_format,
+            device_data.samples,
+        )?);
+        Ok(())
+    }
+
+    fn add_attachment_images(
+        &mut self,
+        device_index: usize,
+        swapchain_index: usize,
+    ) -> Result<(), Error> {
+        let device_data = self
+            .devices
+            .get_mut(device_index)
+            .ok_or(Error::MissingDevice(device_index))?;
+        if swapchain_index >= device_data.swapchains.len() {
+            return Err(Error::MissingSwapchain(swapchain_index));
+        }
+
+        device_data
+            .add_attachment_images(swapchain_index, &self.adapters[device_data.adapter_index])
+    }
+
+    // the depth format to use on the device's adapter, if the settings ask
+    // for a depth buffer at all
+    fn depth_format(&self, device_index: usize) -> Option<Format> {
+        if !self.settings.depth_buffer {
+            return None;
+        }
+        let adapter = &self.adapters[self.devices[device_index].adapter_index];
+        let format = attachment::depth_format(adapter);
+        if format.is_none() {
+            warn!(target: "rmge", "{} supports no depth format, rendering without depth buffer", adapter.info.name);
+        }
+        format
+    }
+
+    // the samples per pixel the settings ask for, if the device's adapter
+    // supports that many for color and, if `depth` is set, depth attachments
+    fn sample_count(&self, device_index: usize, depth: bool) -> Result<NumSamples, Error> {
+        let adapter = &self.adapters[self.devices[device_index].adapter_index];
+        let samples = self.settings.samples;
+        if attachment::supports_samples(adapter, samples, depth) {
+            Ok(samples)
+        } else {
+            Err(Error::UnsupportedSampleCount(samples))
+        }
+    }
+
     fn add_semaphores(&mut self, device_index: usize, swapchain_index: usize) -> Result<(), Error> {
         self.devices
             .get_mut(device_index)
@@ -328,49 +715,391 @@ impl<B: Backend> Context<B> {
         Ok(())
     }
 
-    pub fn clear(&mut self, color: [f32; 4]) -> Result<(), Error> {
-        self.devices
+    /// Uploads `width` by `height` sRGB RGBA8 pixels, given row by row, as
+    /// a texture quads can refer to. `filter` is used both when magnifying
+    /// and minifying the texture.
+    pub fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        filter: Filter,
+    ) -> Result<TextureHandle, Error> {
+        let device_data = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+        let command_pool = self
+            .command_pools
             .get_mut(0)
-            .ok_or(Error::MissingDevice(0))?
-            .clear_frame(
-                color,
-                &mut self
-                    .command_pools
-                    .get_mut(0)
-                    .ok_or(Error::MissingCommandPool(0))?
-                    .command_buffers,
+            .ok_or(Error::MissingCommandPool(0))?;
+
+        self.resources
+            .get_mut(0)
+            .ok_or(Error::MissingResourceManager(0))?
+            .textures
+            .add_texture(
+                &self.adapters[device_data.adapter_index],
+                &mut command_pool.command_pool,
+                &mut device_data.queue.queues[0],
+                width,
+                height,
+                pixels,
+                filter,
             )
     }
 
-    pub fn draw(scene: &mut crate::scene::SceneTree) {}
-
-    pub fn draw_quad(
+    /// Uploads every page of `atlas` as a texture, returning a handle for
+    /// every region in `atlas.regions()`, in the same order.
+    pub fn add_atlas(
         &mut self,
-        quad: crate::geometry::Quad,
-        clear_color: [f32; 4],
-    ) -> Result<(), Error> {
-        use crate::geometry::{Quad, Vec3};
+        atlas: &AtlasBuilder,
+        filter: Filter,
+    ) -> Result<Vec<TextureHandle>, Error> {
+        let device_data = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+        let command_pool = self
+            .command_pools
+            .get_mut(0)
+            .ok_or(Error::MissingCommandPool(0))?;
 
         self.resources
             .get_mut(0)
             .ok_or(Error::MissingResourceManager(0))?
-            .geometry_buffer
-            .add_quad(0, 0, quad)?;
+            .textures
+            .add_atlas(
+                &self.adapters[device_data.adapter_index],
+                &mut command_pool.command_pool,
+                &mut device_data.queue.queues[0],
+                atlas,
+                filter,
+            )
+    }
+
+    /// The size in pixels of the texture or atlas region `texture` refers
+    /// to.
+    pub fn texture_size(&self, texture: TextureHandle) -> Option<crate::geometry::Vec2> {
+        self.resources.first()?.textures.region_size(texture)
+    }
+
+    // the index in the texture array of the texture `texture` refers to,
+    // which draw batches are split by
+    pub(crate) fn texture_index(&self, texture: TextureHandle) -> u32 {
+        self.resources
+            .first()
+            .map_or(0, |resources| resources.textures.texture_index(texture))
+    }
+
+    // frees the descriptor sets no frame in flight uses anymore, and points
+    // the pipelines' texture arrays at any textures added since the last frame
+    fn update_descriptor_sets(&mut self) -> Result<(), Error> {
+        let textures = &mut self
+            .resources
+            .get_mut(0)
+            .ok_or(Error::MissingResourceManager(0))?
+            .textures;
+        let DeviceData {
+            swapchains,
+            pipelines,
+            ..
+        } = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+
+        let changed = textures.take_changed();
+        for pipeline in pipelines {
+            pipeline.free_retired_sets(frame_fences(swapchains));
+            if changed {
+                pipeline.rebind_textures(textures.descriptors(), textures.region_buffer())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the default material with other shaders, such as SPIR-V
+    /// files compiled from modified copies of the built-in ones.
+    pub fn set_shaders(&mut self, shaders: &ShaderSource) -> Result<(), Error> {
+        let device_data = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+        let pipeline = device_data
+            .pipelines
+            .first()
+            .ok_or(Error::MissingPipeline(0))?;
+        let desc = PipelineDesc {
+            shaders: shaders.clone(),
+            ..pipeline.desc.clone()
+        };
+
+        device_data.replace_graphics_pipeline(0, 0, desc)?;
+        self.write_descriptor_sets(0);
+        Ok(())
+    }
+
+    /// Builds a pipeline from `desc`, which scene nodes can then be drawn
+    /// with by setting their `material` to the returned handle.
+    pub fn add_material(&mut self, desc: PipelineDesc) -> Result<MaterialHandle, Error> {
+        if self.resources.is_empty() {
+            return Err(Error::MissingResourceManager(0));
+        }
+        let device_data = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+        device_data.add_graphics_pipeline(0, desc)?;
 
+        let index = device_data.pipelines.len() - 1;
+        self.write_descriptor_sets(index);
+        Ok(MaterialHandle(index as u32))
+    }
+
+    /// Sets the words pushed into the push constant ranges of `material`'s
+    /// `PipelineDesc` every frame. Words past the end of the ranges are
+    /// ignored.
+    pub fn set_material_constants(
+        &mut self,
+        material: MaterialHandle,
+        constants: &[u32],
+    ) -> Result<(), Error> {
+        let index = material.index() as usize;
         self.devices
             .get_mut(0)
             .ok_or(Error::MissingDevice(0))?
-            .draw(
-                clear_color,
-                &self.resources[0], // at this point we know it exists or we would've returned already
-                &mut self
-                    .command_pools
+            .pipelines
+            .get_mut(index)
+            .ok_or(Error::MissingPipeline(index))?
+            .constants = constants.to_vec();
+        Ok(())
+    }
+
+    pub fn clear(&mut self, color: [f32; 4]) -> Result<(), Error> {
+        self.render(None, &Camera::default(), &DrawList::new(color))
+    }
+
+    pub fn draw(
+        &mut self,
+        scene: &mut crate::scene::SceneTree,
+        camera: &Camera,
+        clear_color: [f32; 4],
+    ) -> Result<(), Error> {
+        let mut list = DrawList::new(clear_color);
+        list.draw_scene();
+        self.render(Some(scene), camera, &list)
+    }
+
+    /// Whether `material` is drawn after the opaque ones, sorted back to
+    /// front.
+    pub(crate) fn is_transparent(&self, material: MaterialHandle) -> bool {
+        self.devices
+            .first()
+            .and_then(|device_data| device_data.pipelines.get(material.index() as usize))
+            .is_some_and(|pipeline| pipeline.desc.blend.is_transparent())
+    }
+
+    /// Draws everything recorded into `list` with a single submission,
+    /// which is presented right away. `scene` is only uploaded, where it
+    /// changed, if `list` draws it.
+    pub(crate) fn render(
+        &mut self,
+        scene: Option<&mut crate::scene::SceneTree>,
+        camera: &Camera,
+        list: &DrawList,
+    ) -> Result<(), Error> {
+        if !self.prepare_swapchain()? {
+            return Ok(());
+        }
+
+        // stays empty unless the scene tree is drawn
+        self.last_cull = CullStats::default();
+        if list.is_empty() {
+            return skip_if_out_of_date(
+                self.devices
                     .get_mut(0)
-                    .ok_or(Error::MissingCommandPool(0))?
-                    .command_buffers,
-            )?;
+                    .ok_or(Error::MissingDevice(0))?
+                    .clear_frame(
+                        list.clear_color,
+                        &mut self
+                            .command_pools
+                            .get_mut(0)
+                            .ok_or(Error::MissingCommandPool(0))?
+                            .command_buffers,
+                    ),
+            );
+        }
 
-        Ok(())
+        self.update_descriptor_sets()?;
+        let mut scene = scene.filter(|_| list.draws_scene());
+        let frustum = self
+            .devices
+            .first()
+            .and_then(DeviceData::target_extent)
+            .map(|extent| camera.frustum(extent.width as f32, extent.height as f32));
+        let (first_scene_quad, first_quad, first_sprite) =
+            self.upload_geometry(scene.as_deref_mut(), frustum.as_ref(), list)?;
+        // the scene is drawn from the current frame's own copy
+        let scene_batches = scene.map_or_else(Vec::new, |scene| {
+            scene
+                .draw_batches(
+                    camera,
+                    |material| self.is_transparent(material),
+                    |texture| self.texture_index(texture),
+                )
+                .into_iter()
+                .map(|batch| DrawBatch {
+                    quads: first_scene_quad + batch.quads.start..first_scene_quad + batch.quads.end,
+                    ..batch
+                })
+                .collect()
+        });
+        let batches = list.batches(&scene_batches, first_quad, first_sprite, |texture| {
+            self.texture_index(texture)
+        });
+
+        skip_if_out_of_date(
+            self.devices
+                .get_mut(0)
+                .ok_or(Error::MissingDevice(0))?
+                .draw(
+                    list.clear_color,
+                    &self.resources[0], // at this point we know it exists or we would've returned already
+                    camera,
+                    &mut self
+                        .command_pools
+                        .get_mut(0)
+                        .ok_or(Error::MissingCommandPool(0))?
+                        .command_buffers,
+                    &batches,
+                ),
+        )
+    }
+
+    // culls `scene` to `frustum` and uploads whatever changed in it that's
+    // in view into the frame's copy of it, followed by the rest of the
+    // geometry in `list`, and writes its sprites, once the frame they're for
+    // is done with its last submission. Returns the slots of the copy's
+    // first quad, of the first quad after the scene and of the first sprite.
+    fn upload_geometry(
+        &mut self,
+        scene: Option<&mut crate::scene::SceneTree>,
+        frustum: Option<&Frustum>,
+        list: &DrawList,
+    ) -> Result<(u32, u32, u32), Error> {
+        let device_data = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+        let frame = device_data.wait_for_frame()?;
+        let DeviceData {
+            adapter_index,
+            device,
+            swapchains,
+            pipelines,
+            ..
+        } = device_data;
+        let ResourceManager {
+            geometry_buffer,
+            sprites,
+            ..
+        } = self
+            .resources
+            .get_mut(0)
+            .ok_or(Error::MissingResourceManager(0))?;
+        let adapter = &self.adapters[*adapter_index];
+
+        geometry_buffer.free_retired(frame_fences(swapchains));
+        sprites.free_retired(frame_fences(swapchains));
+
+        let first_scene_quad = match scene {
+            Some(scene) => {
+                scene.recompute_caches();
+                self.last_cull = scene.cull(frustum);
+                geometry_buffer.upload_changes(adapter, frame, scene, || {
+                    wait_for_frames(&***device, swapchains)
+                })?
+            }
+            None => 0,
+        };
+        let (first_matrix, first_quad) =
+            geometry_buffer.write_immediate(adapter, frame, list.matrices(), list.quads())?;
+
+        if geometry_buffer.take_reallocated() {
+            for pipeline in pipelines {
+                pipeline.rebind_matrix_buffer(&geometry_buffer.matrix_buffer.buffer)?;
+            }
+        }
+
+        // written even when there are none, so the frame's old slots are freed
+        let sprite_matrix = list.sprite_matrix().map_or(0, |index| first_matrix + index);
+        let sprite_slots = sprites.write(adapter, frame, sprite_matrix, list.sprites())?;
+        Ok((first_scene_quad, first_quad, sprite_slots.start))
+    }
+
+    /// How much of the scene tree the last call to `draw` found to be out
+    /// of view, and skipped drawing.
+    pub fn last_cull(&self) -> CullStats {
+        self.last_cull
+    }
+
+    /// How much geometry data the last call to `draw` had to upload.
+    pub fn last_upload(&self) -> UploadStats {
+        self.resources
+            .first()
+            .map(|resources| resources.geometry_buffer.last_upload)
+            .unwrap_or_default()
+    }
+
+    /// Copies what was last rendered back as tightly packed sRGB RGBA8
+    /// pixels, row by row starting at the top. Only headless contexts can be
+    /// read from.
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, Error> {
+        let device_data = self.devices.get_mut(0).ok_or(Error::MissingDevice(0))?;
+        let command_pool = self
+            .command_pools
+            .get_mut(0)
+            .ok_or(Error::MissingCommandPool(0))?;
+
+        device_data
+            .offscreen
+            .as_ref()
+            .ok_or(Error::MissingOffscreenTarget)?
+            .read_pixels(
+                &self.adapters[device_data.adapter_index],
+                &mut command_pool.command_pool,
+                &mut device_data.queue.queues[0],
+            )
+    }
+
+    pub fn draw_quad(
+        &mut self,
+        quad: crate::geometry::Quad,
+        camera: &Camera,
+        clear_color: [f32; 4],
+    ) -> Result<(), Error> {
+        let mut list = DrawList::new(clear_color);
+        list.push_quads(
+            MaterialHandle::DEFAULT,
+            crate::geometry::Mat4::identity(),
+            Some(quad),
+        );
+        self.render(None, camera, &list)
+    }
+}
+
+// the fences of the frames that may still be in flight, offscreen frames are
+// waited on right away so there are none when headless
+fn frame_fences<B: Backend>(swapchains: &[SwapchainData<B>]) -> &[B::Fence] {
+    swapchains
+        .first()
+        .and_then(|swapchain| swapchain.fences.as_deref())
+        .unwrap_or(&[])
+}
+
+// waits until no frame in flight reads the geometry buffer, so the scene's
+// copies in it can be moved
+fn wait_for_frames<B: Backend>(
+    device: &B::Device,
+    swapchains: &[SwapchainData<B>],
+) -> Result<(), Error> {
+    match swapchains.first() {
+        Some(swapchain) => swapchain
+            .frames
+            .wait_for_all(&HalFences::new(device), frame_fences(swapchains)),
+        None => Ok(()),
+    }
+}
+
+// an out of date swapchain isn't an error, the frame is just dropped and the
+// swapchain gets recreated before the next one
+fn skip_if_out_of_date(result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(Error::SwapchainError(SwapchainError::OutOfDate)) => Ok(()),
+        result => result,
     }
 }
 
@@ -394,7 +1123,7 @@ impl<B: Backend> std::ops::Drop for Context<B> {
             }
         }
 
-        for mut resource in self.resources.drain(..) {}
+        self.resources.clear();
 
         info!(target: "rmge", "destroyed all command queues");
 
@@ -402,14 +1131,21 @@ impl<B: Backend> std::ops::Drop for Context<B> {
             mut pipelines,
             device,
             swapchains,
+            offscreen,
             render_passes,
             queue: _,
             adapter_index: _,
+            depth_format: _,
+            samples: _,
+            max_push_constants_size: _,
+            frames_in_flight: _,
         } in self.devices.drain(..)
         {
             for data in pipelines.drain(..) {
                 mem::drop(data);
             }
+            // the framebuffer has to go before the render pass
+            mem::drop(offscreen);
 
             for render_pass in render_passes {
                 unsafe { device.destroy_render_pass(render_pass) };
@@ -417,54 +1153,10 @@ impl<B: Backend> std::ops::Drop for Context<B> {
             info!(target: "rmge", "render passes destroyed");
 
             for swapchain_data in swapchains {
-                let SwapchainData {
-                    swapchain,
-                    backbuffer,
-                    fences,
-                    available_semaphores,
-                    finished_semaphores,
-                    image_views,
-                    framebuffers,
-                    device: _, // we already have the correct device (hopefully)
-                    config: _,
-                    current_frame: _,
-                } = swapchain_data;
                 unsafe {
-                    for fence in fences.unwrap_or_else(Vec::new) {
-                        device.destroy_fence(fence);
-                    }
-
-                    info!(target: "rmge", "fences destroyed");
-
-                    for semaphore in available_semaphores.unwrap_or_else(Vec::new) {
-                        device.destroy_semaphore(semaphore);
-                    }
-
-                    info!(target: "rmge", "available semaphores destroyed");
-
-                    for semaphore in finished_semaphores.unwrap_or_else(Vec::new) {
-                        device.destroy_semaphore(semaphore);
-                    }
+                    let swapchain = swapchain_data.destroy_resources();
 
-                    info!(target: "rmge", "finished semaphores destroyed");
-
-                    for image_view in image_views.unwrap_or_else(Vec::new) {
-                        device.destroy_image_view(image_view);
-                    }
-
-                    info!(target: "rmge", "image views destroyed");
-
-                    //for image in backbuffer {
-                    //    device.destroy_image(image);
-                    //}
-
-                    info!(target: "rmge", "backbuffer images destroyed");
-
-                    for framebuffer in framebuffers {
-                        device.destroy_framebuffer(framebuffer);
-                    }
-
-                    info!(target: "rmge", "framebuffers destroyed");
+                    info!(target: "rmge", "swapchain resources destroyed");
 
                     device.destroy_swapchain(swapchain);
 
@@ -486,8 +1178,9 @@ impl<B: Backend> std::ops::Drop for Context<B> {
             }
         }
         unsafe {
-            self.instance
-                .destroy_surface(ManuallyDrop::into_inner(std::ptr::read(&self.surface)));
+            if let Some(surface) = self.surface.take() {
+                self.instance.destroy_surface(surface);
+            }
 
             ManuallyDrop::drop(&mut self.instance);
         }
@@ -0,0 +1,340 @@
+use core::mem::ManuallyDrop;
+use core::ptr;
+
+use gfx_hal::{
+    adapter::{Adapter, PhysicalDevice},
+    buffer::{Access as BufferAccess, Usage as BufferUsage},
+    command::{BufferImageCopy, CommandBuffer, CommandBufferFlags, Level},
+    device::Device as DeviceTrait,
+    format::{Aspects, Format, Swizzle},
+    image::{
+        Access, Extent, Kind, Layout, NumSamples, Offset, SubresourceLayers, Tiling,
+        Usage as ImageUsage, ViewCapabilities, ViewKind,
+    },
+    memory::{Barrier, Dependencies, Properties, Segment},
+    pool::CommandPool,
+    pso::PipelineStage,
+    queue::CommandQueue,
+    window::Extent2D,
+    Backend,
+};
+
+use super::attachment::AttachmentImage;
+use super::resources::buffer::{Buffer, Memory};
+use super::resources::textures::{color_range, row_pitch, PIXEL_SIZE};
+use crate::error::*;
+
+use std::rc::Rc;
+
+/// The format of the offscreen image, the same as the textures use.
+pub const FORMAT: Format = Format::Rgba8Srgb;
+
+/// An image rendered to instead of a swapchain, which is left in
+/// `TransferSrcOptimal` by the render pass so it can be read back.
+#[derive(Debug)]
+pub struct OffscreenData<B: Backend> {
+    device: Rc<ManuallyDrop<B::Device>>,
+    image: ManuallyDrop<B::Image>,
+    view: ManuallyDrop<B::ImageView>,
+    memory: Memory<B, B::Device>,
+    depth: Option<AttachmentImage<B>>,
+    // drawn to and resolved into the image when multisampling
+    color: Option<AttachmentImage<B>>,
+    pub framebuffer: ManuallyDrop<B::Framebuffer>,
+    // signalled once the last frame rendered to the image has finished
+    pub fence: ManuallyDrop<B::Fence>,
+    pub extent: Extent2D,
+    // whether anything was rendered yet, before that the image has no layout
+    pub rendered: bool,
+}
+
+impl<B: Backend> OffscreenData<B> {
+    pub fn new(
+        device: Rc<ManuallyDrop<B::Device>>,
+        adapter: &Adapter<B>,
+        render_pass: &B::RenderPass,
+        extent: Extent2D,
+        depth_format: Option<Format>,
+        samples: NumSamples,
+    ) -> Result<Self, Error> {
+        unsafe {
+            let mut image = device
+                .create_image(
+                    Kind::D2(extent.width, extent.height, 1, 1),
+                    1,
+                    FORMAT,
+                    Tiling::Optimal,
+                    ImageUsage::COLOR_ATTACHMENT | ImageUsage::TRANSFER_SRC,
+                    ViewCapabilities::empty(),
+                )
+                .map_err(|_| Error::ImageCreationError)?;
+
+            let memory = match Memory::new(
+                device.clone(),
+                adapter,
+                Properties::DEVICE_LOCAL,
+                device.get_image_requirements(&image),
+                MemoryKind::Image,
+            ) {
+                Ok(memory) => memory,
+                Err(e) => {
+                    device.destroy_image(image);
+                    return Err(e);
+                }
+            };
+
+            if device
+                .bind_image_memory(&memory.memory, 0, &mut image)
+                .is_err()
+            {
+                device.destroy_image(image);
+                return Err(Error::ImageBindError);
+            }
+
+            let view = match device
+                .create_image_view(&image, ViewKind::D2, FORMAT, Swizzle::NO, color_range())
+                .map_err(|_| Error::ImageViewCreationError)
+            {
+                Ok(view) => view,
+                Err(e) => {
+                    device.destroy_image(image);
+                    return Err(e);
+                }
+            };
+
+            let (depth, color) =
+                match attachment_images(&device, adapter, extent, depth_format, samples) {
+                    Ok(images) => images,
+                    Err(e) => {
+                        device.destroy_image_view(view);
+                        device.destroy_image(image);
+                        return Err(e);
+                    }
+                };
+
+            // in the same order as the render pass' attachments
+            let attachments = Some(color.as_ref().map_or(&view, |color| &*color.view))
+                .into_iter()
+                .chain(depth.as_ref().map(|depth| &*depth.view))
+                .chain(color.as_ref().map(|_| &view));
+            let framebuffer = match device
+                .create_framebuffer(render_pass, attachments, extent.to_extent())
+                .map_err(|_| Error::FramebufferCreationError)
+            {
+                Ok(framebuffer) => framebuffer,
+                Err(e) => {
+                    device.destroy_image_view(view);
+                    device.destroy_image(image);
+                    return Err(e);
+                }
+            };
+
+            let fence = match device
+                .create_fence(true)
+                .map_err(|_| Error::FenceCreationError)
+            {
+                Ok(fence) => fence,
+                Err(e) => {
+                    device.destroy_framebuffer(framebuffer);
+                    device.destroy_image_view(view);
+                    device.destroy_image(image);
+                    return Err(e);
+                }
+            };
+
+            Ok(OffscreenData {
+                device,
+                image: ManuallyDrop::new(image),
+                view: ManuallyDrop::new(view),
+                memory,
+                depth,
+                color,
+                framebuffer: ManuallyDrop::new(framebuffer),
+                fence: ManuallyDrop::new(fence),
+                extent,
+                rendered: false,
+            })
+        }
+    }
+
+    /// Copies the image back into tightly packed sRGB RGBA8 pixels, row by
+    /// row starting at the top. Waits for the last frame to finish first.
+    pub fn read_pixels<C: CommandPool<B>>(
+        &self,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+    ) -> Result<Vec<u8>, Error> {
+        let device = &self.device;
+        let Extent2D { width, height } = self.extent;
+
+        let pitch_alignment = adapter
+            .physical_device
+            .limits()
+            .optimal_buffer_copy_pitch_alignment
+            .max(1);
+        let row_size = width as usize * PIXEL_SIZE;
+        let row_pitch = row_pitch(width, pitch_alignment);
+
+        let mut staging_buffer = Buffer::new(
+            device.clone(),
+            row_pitch * u64::from(height),
+            BufferUsage::TRANSFER_DST,
+        )
+        .map_err(|e| Error::BufferError(BufferOp::Create(e), BufferKind::Staging))?;
+
+        unsafe {
+            let staging_memory = Memory::new(
+                device.clone(),
+                adapter,
+                Properties::CPU_VISIBLE | Properties::COHERENT,
+                device.get_buffer_requirements(&staging_buffer.buffer),
+                MemoryKind::Staging,
+            )?;
+
+            staging_buffer
+                .bind_to_memory(&staging_memory, 0)
+                .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Staging))?;
+
+            device
+                .wait_for_fence(&self.fence, u64::MAX)
+                .map_err(|_| Error::FenceError(FenceOp::Wait))?;
+
+            let fence = device
+                .create_fence(false)
+                .map_err(|_| Error::FenceCreationError)?;
+
+            let mut buffer = command_pool.allocate_one(Level::Primary);
+            device.set_command_buffer_name(&mut buffer, "readback buffer");
+            self.record_readback(&mut buffer, &staging_buffer.buffer, row_pitch);
+
+            command_queue.submit_without_semaphores(Some(&buffer), Some(&fence));
+            let waited = device
+                .wait_for_fence(&fence, u64::MAX)
+                .map_err(|_| Error::FenceError(FenceOp::Wait));
+            device.destroy_fence(fence);
+            command_pool.free(Some(buffer));
+            waited?;
+
+            let mapped = device
+                .map_memory(&staging_memory.memory, Segment::ALL)
+                .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Staging))?;
+            let mut pixels = vec![0; row_size * height as usize];
+            for (row, row_pixels) in pixels.chunks_exact_mut(row_size).enumerate() {
+                ptr::copy_nonoverlapping(
+                    mapped.add(row * row_pitch as usize),
+                    row_pixels.as_mut_ptr(),
+                    row_size,
+                );
+            }
+            device.unmap_memory(&staging_memory.memory);
+
+            Ok(pixels)
+        }
+    }
+
+    // records copying the image into `staging_buffer`, whose rows are
+    // `row_pitch` bytes apart, and making the copy visible to the host
+    unsafe fn record_readback(
+        &self,
+        buffer: &mut B::CommandBuffer,
+        staging_buffer: &B::Buffer,
+        row_pitch: u64,
+    ) {
+        let Extent2D { width, height } = self.extent;
+
+        // before anything is rendered the contents are undefined anyway
+        let old_state = if self.rendered {
+            (Access::COLOR_ATTACHMENT_WRITE, Layout::TransferSrcOptimal)
+        } else {
+            (Access::empty(), Layout::Undefined)
+        };
+
+        buffer.begin_primary(CommandBufferFlags::ONE_TIME_SUBMIT);
+        buffer.pipeline_barrier(
+            PipelineStage::COLOR_ATTACHMENT_OUTPUT..PipelineStage::TRANSFER,
+            Dependencies::empty(),
+            &[Barrier::Image {
+                states: old_state..(Access::TRANSFER_READ, Layout::TransferSrcOptimal),
+                target: &*self.image,
+                families: None,
+                range: color_range(),
+            }],
+        );
+        buffer.copy_image_to_buffer(
+            &self.image,
+            Layout::TransferSrcOptimal,
+            staging_buffer,
+            &[BufferImageCopy {
+                buffer_offset: 0,
+                buffer_width: (row_pitch / PIXEL_SIZE as u64) as u32,
+                buffer_height: height,
+                image_layers: SubresourceLayers {
+                    aspects: Aspects::COLOR,
+                    level: 0,
+                    layers: 0..1,
+                },
+                image_offset: Offset::ZERO,
+                image_extent: Extent {
+                    width,
+                    height,
+                    depth: 1,
+                },
+            }],
+        );
+        buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::HOST,
+            Dependencies::empty(),
+            &[Barrier::whole_buffer(
+                staging_buffer,
+                BufferAccess::TRANSFER_WRITE..BufferAccess::HOST_READ,
+            )],
+        );
+        buffer.finish();
+    }
+}
+
+// the depth and multisampled color images the render pass needs besides the
+// image itself, if any
+type AttachmentImages<B> = (Option<AttachmentImage<B>>, Option<AttachmentImage<B>>);
+
+fn attachment_images<B: Backend>(
+    device: &Rc<ManuallyDrop<B::Device>>,
+    adapter: &Adapter<B>,
+    extent: Extent2D,
+    depth_format: Option<Format>,
+    samples: NumSamples,
+) -> Result<AttachmentImages<B>, Error> {
+    let depth = depth_format
+        .map(|format| AttachmentImage::depth(device.clone(), adapter, extent, format, samples))
+        .transpose()?;
+    let color = if samples > 1 {
+        Some(AttachmentImage::multisampled_color(
+            device.clone(),
+            adapter,
+            extent,
+            FORMAT,
+            samples,
+        )?)
+    } else {
+        None
+    };
+    Ok((depth, color))
+}
+
+impl<B: Backend> Drop for OffscreenData<B> {
+    fn drop(&mut self) {
+        unsafe {
+            use std::ptr::read;
+
+            self.device
+                .destroy_fence(ManuallyDrop::into_inner(read(&self.fence)));
+            self.device
+                .destroy_framebuffer(ManuallyDrop::into_inner(read(&self.framebuffer)));
+            self.device
+                .destroy_image_view(ManuallyDrop::into_inner(read(&self.view)));
+            self.device
+                .destroy_image(ManuallyDrop::into_inner(read(&self.image)));
+        }
+    }
+}
@@ -1,50 +1,227 @@
 use std::{
-    fs::File,
-    io::{self, Read},
     mem::{self, ManuallyDrop},
     rc::Rc,
 };
 
-use byteorder::{BigEndian, LittleEndian, NativeEndian, ReadBytesExt};
+use arrayvec::ArrayVec;
 
 use gfx_hal::{
+    buffer::SubRange,
+    command::CommandBuffer,
     device::Device,
-    image::Extent,
+    image::NumSamples,
     pass::Subpass,
     pso::{
-        AttributeDesc, BakedStates, BasePipeline, BlendDesc, BlendOp, BlendState, ColorBlendDesc,
-        ColorMask, DepthStencilDesc, DepthTest, DescriptorSetLayoutBinding, EntryPoint, Face,
-        Factor, FrontFace, GraphicsPipelineDesc, GraphicsShaderSet, InputAssemblerDesc, LogicOp,
+        BakedStates, BasePipeline, BlendDesc, BufferDescriptorFormat, BufferDescriptorType,
+        ColorBlendDesc, ColorMask, CreationError, Descriptor, DescriptorPool,
+        DescriptorPoolCreateFlags, DescriptorRangeDesc, DescriptorSetLayoutBinding,
+        DescriptorSetWrite, DescriptorType, EntryPoint, Face, FrontFace, GraphicsPipelineDesc,
+        GraphicsShaderSet, ImageDescriptorType, InputAssemblerDesc, Multisampling,
         PipelineCreationFlags, PolygonMode, Primitive, Rasterizer, ShaderStageFlags,
-        Specialization, State, StencilTest, VertexBufferDesc, Viewport,
+        Specialization, State,
     },
     Backend,
 };
 
+use super::material::PipelineDesc;
+use super::resources::{textures::MAX_TEXTURES, Retired};
 use crate::error::{Error, ShaderKind};
-
-use log::error;
+use crate::geometry::Mat4;
 
 // helper function
 fn snd<T, U>(a: (T, U)) -> U {
     a.1
 }
 
-static VERT_SHADER_FILENAME: &'static str = "vert.spv";
-static FRAG_SHADER_FILENAME: &'static str = "frag.spv";
+// the node transforms, indexed in the vertex shader by the quad's node index
+const MATRIX_SET: usize = 0;
+const MATRIX_BINDING: u32 = 0;
+const MATRIX_DESCRIPTOR: DescriptorType = DescriptorType::Buffer {
+    ty: BufferDescriptorType::Storage { read_only: true },
+    format: BufferDescriptorFormat::Structured {
+        dynamic_offset: false,
+    },
+};
 
-fn read_shader_data(mut f: File) -> Result<Vec<u32>, io::Error> {
-    let file_len = f.metadata()?.len();
+// the textures, indexed in the fragment shader by the texture of the quad's
+// region, and the regions, indexed in the vertex shader by the quad's texture
+const TEXTURE_SET: usize = 1;
+const TEXTURE_BINDING: u32 = 0;
+const TEXTURE_DESCRIPTOR: DescriptorType = DescriptorType::Image {
+    ty: ImageDescriptorType::Sampled { with_sampler: true },
+};
+const REGION_BINDING: u32 = 1;
+const REGION_DESCRIPTOR: DescriptorType = MATRIX_DESCRIPTOR;
+
+// the camera's view-projection matrix, pushed when recording each frame,
+// any push constants of a material come after it
+pub(crate) const CAMERA_PUSH_CONSTANTS_SIZE: u32 = mem::size_of::<Mat4>() as u32;
+
+// the layouts of the sets the renderer writes to, see `MATRIX_SET` and
+// `TEXTURE_SET`
+pub(crate) fn builtin_descriptor_sets() -> Vec<Vec<DescriptorSetLayoutBinding>> {
+    vec![
+        vec![DescriptorSetLayoutBinding {
+            binding: MATRIX_BINDING,
+            ty: MATRIX_DESCRIPTOR,
+            count: 1,
+            stage_flags: ShaderStageFlags::VERTEX,
+            immutable_samplers: false,
+        }],
+        vec![
+            DescriptorSetLayoutBinding {
+                binding: TEXTURE_BINDING,
+                ty: TEXTURE_DESCRIPTOR,
+                count: MAX_TEXTURES,
+                stage_flags: ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            },
+            DescriptorSetLayoutBinding {
+                binding: REGION_BINDING,
+                ty: REGION_DESCRIPTOR,
+                count: 1,
+                stage_flags: ShaderStageFlags::VERTEX,
+                immutable_samplers: false,
+            },
+        ],
+    ]
+}
 
-    if file_len % 4 != 0 {
-        error!(target: "rmge", "shader file length is not divisible by 4!");
+// how many sets of each layout can be alive at once. Old sets stay alive
+// until the frames using them are done, and since the matrix buffer doubles in
+// size every time it grows, it shouldn't grow more than once per frame or so
+const MAX_SETS_PER_LAYOUT: usize = 4;
+
+// allocates a set of each of `layouts`, which were created from `bindings`,
+// from a pool with room for the sets replacing them
+fn create_descriptor_sets<B: Backend, D: Device<B>>(
+    device: &D,
+    layouts: &[B::DescriptorSetLayout],
+    bindings: &[Vec<DescriptorSetLayoutBinding>],
+) -> Result<(B::DescriptorPool, Vec<B::DescriptorSet>), Error> {
+    let ranges = bindings
+        .iter()
+        .flatten()
+        .filter(|binding| binding.count > 0)
+        .map(|binding| DescriptorRangeDesc {
+            ty: binding.ty,
+            count: MAX_SETS_PER_LAYOUT * binding.count,
+        })
+        .collect::<Vec<_>>();
+    unsafe {
+        let mut pool = device
+            .create_descriptor_pool(
+                MAX_SETS_PER_LAYOUT * layouts.len(),
+                &ranges,
+                DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
+            )
+            .map_err(|_| Error::DescriptorPoolCreation)?;
+
+        let mut sets = Vec::with_capacity(layouts.len());
+        if pool.allocate(layouts, &mut sets).is_err() {
+            device.destroy_descriptor_pool(pool);
+            return Err(Error::DescriptorSetAllocation);
+        }
+        Ok((pool, sets))
     }
+}
 
-    let mut v = Vec::new();
-    v.resize_with(file_len as usize / 4, || 0);
-    unsafe {}
-    f.read_u32_into::<LittleEndian>(&mut v[..])?;
-    Ok(v)
+// builds the pipeline itself out of the shaders and the fixed function
+// state `desc` asks for
+fn create_graphics_pipeline<B: Backend, D: Device<B>>(
+    device: &D,
+    render_pass: &B::RenderPass,
+    layout: &B::PipelineLayout,
+    desc: &PipelineDesc,
+    samples: NumSamples,
+    (vert_shader_module, frag_shader_module): (&B::ShaderModule, &B::ShaderModule),
+) -> Result<B::GraphicsPipeline, CreationError> {
+    let (vs_entry, fs_entry): (EntryPoint<'_, B>, EntryPoint<'_, B>) = (
+        EntryPoint {
+            entry: "main",
+            module: vert_shader_module,
+            specialization: Specialization::EMPTY,
+        },
+        EntryPoint {
+            entry: "main",
+            module: frag_shader_module,
+            specialization: Specialization::EMPTY,
+        },
+    );
+
+    let shaders = GraphicsShaderSet {
+        vertex: vs_entry,
+        hull: None,
+        domain: None,
+        geometry: None,
+        fragment: Some(fs_entry),
+    };
+
+    let rasterizer = Rasterizer {
+        depth_clamping: false,
+        polygon_mode: PolygonMode::Fill,
+        cull_face: Face::NONE,
+        front_face: FrontFace::Clockwise,
+        depth_bias: None,
+        conservative: false,
+        line_width: State::Static(1.0),
+    };
+
+    let depth_stencil = desc.depth_stencil();
+
+    // a logic op would disable blending altogether
+    let blender = BlendDesc {
+        logic_op: None,
+        targets: vec![ColorBlendDesc {
+            mask: ColorMask::ALL,
+            blend: desc.blend.blend_state(),
+        }],
+    };
+
+    // the viewport and scissor are left dynamic, they're set when
+    // recording each frame so the pipeline doesn't depend on the extent
+    let baked_states = BakedStates {
+        viewport: None,
+        scissor: None,
+        blend_color: None,
+        depth_bounds: None,
+    };
+
+    let input_assembler = InputAssemblerDesc::new(Primitive::TriangleList);
+
+    // has to match the render pass' attachments
+    let multisampling = if samples > 1 {
+        Some(Multisampling {
+            rasterization_samples: samples,
+            sample_shading: None,
+            sample_mask: !0,
+            alpha_coverage: false,
+            alpha_to_one: false,
+        })
+    } else {
+        None
+    };
+
+    let pipeline_desc = GraphicsPipelineDesc {
+        shaders,
+        rasterizer,
+        vertex_buffers: desc.vertex_buffers.clone(),
+        attributes: desc.attributes.clone(),
+        input_assembler,
+        blender,
+        depth_stencil,
+        multisampling,
+        baked_states,
+        layout,
+        subpass: Subpass {
+            index: 0,
+            main_pass: render_pass,
+        },
+        flags: PipelineCreationFlags::empty(),
+        parent: BasePipeline::None,
+    };
+
+    unsafe { device.create_graphics_pipeline(&pipeline_desc, None) }
 }
 
 #[derive(Debug)]
@@ -53,156 +230,210 @@ pub struct PipelineData<B: Backend, D: Device<B>> {
     pub pipeline_layout: ManuallyDrop<B::PipelineLayout>,
     pub graphics_pipeline: ManuallyDrop<B::GraphicsPipeline>,
     pub descriptor_set_layouts: Vec<B::DescriptorSetLayout>,
+    pub descriptor_pool: ManuallyDrop<B::DescriptorPool>,
+    pub descriptor_sets: Vec<B::DescriptorSet>,
+    // sets replaced by the `rebind_*` methods, which in-flight frames might still use
+    retired_sets: Retired<B::DescriptorSet>,
+    pub desc: PipelineDesc,
+    // pushed into the ranges of `desc.push_constants`, in order
+    pub constants: Vec<u32>,
 }
 
 impl<B: Backend, D: Device<B>> PipelineData<B, D> {
     pub fn new(
         device: Rc<ManuallyDrop<D>>,
-        extent: Extent,
         render_pass: &B::RenderPass,
-        vertex_buffers: Vec<VertexBufferDesc>,
-        attributes: Vec<AttributeDesc>,
+        desc: PipelineDesc,
+        samples: NumSamples,
+        max_push_constants_size: u32,
     ) -> Result<Self, Error> {
-        let frag_shader_file = File::open("frag.spv").map_err(|e| Error::IOError(e))?;
-        let vert_shader_file = File::open("vert.spv").map_err(|e| Error::IOError(e))?;
-        let vert_data = read_shader_data(vert_shader_file).map_err(|e| Error::IOError(e))?;
-        let frag_data = read_shader_data(frag_shader_file).map_err(|e| Error::IOError(e))?;
+        desc.validate(max_push_constants_size)?;
+        let (vert_data, frag_data) = desc.shaders.load()?;
 
-        let bindings: &'static [DescriptorSetLayoutBinding] = &[];
         let immutable_samplers: &'static [B::Sampler] = &[];
-        let push_constants: &'static [(ShaderStageFlags, core::ops::Range<u32>)] = &[];
-
-        let mut pipeline_builder = PipelineBuilder::new(device.clone());
+        let push_constants = Some((ShaderStageFlags::VERTEX, 0..CAMERA_PUSH_CONSTANTS_SIZE))
+            .into_iter()
+            .chain(desc.push_constants.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let mut pipeline_builder = PipelineBuilder::new(device.clone())
+            .add_vert_shader(&vert_data)
+            .map_err(snd)?
+            .add_frag_shader(&frag_data)
+            .map_err(snd)?;
+        for bindings in &desc.descriptor_sets {
+            pipeline_builder = pipeline_builder
+                .add_descriptor_set_layout(bindings, immutable_samplers)
+                .map_err(snd)?;
+        }
         let (vert_shader_module, frag_shader_module, descriptor_set_layouts, layout) =
             pipeline_builder
-                .add_vert_shader(&vert_data)
-                .map_err(snd)?
-                .add_frag_shader(&frag_data)
-                .map_err(snd)?
-                .add_descriptor_set_layout(&bindings, &immutable_samplers)
-                .map_err(snd)?
                 .add_pipeline_layout(&push_constants)
                 .map_err(snd)?
                 .into_data()
                 .map_err(|_| Error::PipelineCreation)?;
 
-        let (vs_entry, fs_entry): (EntryPoint<'_, B>, EntryPoint<'_, B>) = (
-            EntryPoint {
-                entry: "main",
-                module: &vert_shader_module,
-                specialization: Specialization::EMPTY,
-            },
-            EntryPoint {
-                entry: "main",
-                module: &frag_shader_module,
-                specialization: Specialization::EMPTY,
-            },
+        let gfx_pipeline = create_graphics_pipeline::<B, D>(
+            &device,
+            render_pass,
+            &layout,
+            &desc,
+            samples,
+            (&vert_shader_module, &frag_shader_module),
         );
 
-        let shaders = GraphicsShaderSet {
-            vertex: vs_entry,
-            hull: None,
-            domain: None,
-            geometry: None,
-            fragment: Some(fs_entry),
-        };
-
-        let rasterizer = Rasterizer {
-            depth_clamping: false,
-            polygon_mode: PolygonMode::Fill,
-            cull_face: Face::NONE,
-            front_face: FrontFace::Clockwise,
-            depth_bias: None,
-            conservative: false,
-            line_width: State::Static(1.0),
-        };
-
-        let depth_stencil = DepthStencilDesc {
-            depth: None,
-            depth_bounds: false,
-            stencil: None,
-        };
-
-        let blender = {
-            let blend_state = BlendState {
-                color: BlendOp::Add {
-                    src: Factor::One,
-                    dst: Factor::Zero,
-                },
-                alpha: BlendOp::Add {
-                    src: Factor::One,
-                    dst: Factor::Zero,
-                },
-            };
-            BlendDesc {
-                logic_op: Some(LogicOp::Copy),
-                targets: vec![ColorBlendDesc {
-                    mask: ColorMask::ALL,
-                    blend: Some(blend_state),
-                }],
-            }
-        };
-
-        let baked_states = BakedStates {
-            viewport: Some(Viewport {
-                rect: extent.rect(),
-                depth: (0.0..1.0),
-            }),
-            scissor: Some(extent.rect()),
-            blend_color: None,
-            depth_bounds: None,
-        };
-
-        let input_assembler = InputAssemblerDesc::new(Primitive::TriangleList);
-
-        let gfx_pipeline = {
-            let desc = GraphicsPipelineDesc {
-                shaders,
-                rasterizer,
-                vertex_buffers,
-                attributes,
-                input_assembler,
-                blender,
-                depth_stencil,
-                multisampling: None,
-                baked_states,
-                layout: &layout,
-                subpass: Subpass {
-                    index: 0,
-                    main_pass: &render_pass,
-                },
-                flags: PipelineCreationFlags::empty(),
-                parent: BasePipeline::None,
-            };
-
-            unsafe { device.create_graphics_pipeline(&desc, None) }
-        };
-
         unsafe {
             device.destroy_shader_module(vert_shader_module);
             device.destroy_shader_module(frag_shader_module);
         }
 
-        match gfx_pipeline {
-            Ok(graphics_pipeline) => Ok(Self {
+        let descriptor_sets =
+            create_descriptor_sets::<B, D>(&device, &descriptor_set_layouts, &desc.descriptor_sets);
+
+        match (gfx_pipeline, descriptor_sets) {
+            (Ok(graphics_pipeline), Ok((descriptor_pool, descriptor_sets))) => Ok(Self {
                 device,
                 pipeline_layout: ManuallyDrop::new(layout),
                 graphics_pipeline: ManuallyDrop::new(graphics_pipeline),
                 descriptor_set_layouts,
+                descriptor_pool: ManuallyDrop::new(descriptor_pool),
+                descriptor_sets,
+                retired_sets: Retired::new(),
+                desc,
+                constants: vec![],
             }),
-            Err(_) => {
-                unsafe {
-                    device.destroy_pipeline_layout(layout);
-                    for d_layout in descriptor_set_layouts {
-                        device.destroy_descriptor_set_layout(d_layout);
+            (gfx_pipeline, descriptor_sets) => unsafe {
+                if let Ok(pipeline) = gfx_pipeline {
+                    device.destroy_graphics_pipeline(pipeline);
+                }
+                device.destroy_pipeline_layout(layout);
+                for d_layout in descriptor_set_layouts {
+                    device.destroy_descriptor_set_layout(d_layout);
+                }
+                match descriptor_sets {
+                    Ok((pool, _)) => {
+                        device.destroy_descriptor_pool(pool);
+                        Err(Error::PipelineCreation)
                     }
+                    Err(e) => Err(e),
                 }
-                Err(Error::PipelineCreation)
+            },
+        }
+    }
+
+    /// Points the matrix descriptor of this pipeline at `buffer`. Has to be
+    /// called again whenever the matrix buffer is recreated.
+    pub fn write_matrix_descriptor(&self, buffer: &B::Buffer) {
+        unsafe {
+            self.device.write_descriptor_sets(Some(DescriptorSetWrite {
+                set: &self.descriptor_sets[MATRIX_SET],
+                binding: MATRIX_BINDING,
+                array_offset: 0,
+                descriptors: Some(Descriptor::Buffer(buffer, SubRange::WHOLE)),
+            }));
+        }
+    }
+
+    /// Fills the texture array of this pipeline with `descriptors`, which
+    /// has to provide one for every slot, and points the region descriptor
+    /// at `region_buffer`.
+    pub fn write_texture_descriptors<'a>(
+        &'a self,
+        descriptors: impl IntoIterator<Item = Descriptor<'a, B>>,
+        region_buffer: &'a B::Buffer,
+    ) {
+        unsafe {
+            self.device.write_descriptor_sets(Some(DescriptorSetWrite {
+                set: &self.descriptor_sets[TEXTURE_SET],
+                binding: TEXTURE_BINDING,
+                array_offset: 0,
+                descriptors,
+            }));
+            self.device.write_descriptor_sets(Some(DescriptorSetWrite {
+                set: &self.descriptor_sets[TEXTURE_SET],
+                binding: REGION_BINDING,
+                array_offset: 0,
+                descriptors: Some(Descriptor::Buffer(region_buffer, SubRange::WHOLE)),
+            }));
+        }
+    }
+
+    // since the current set might still be used by frames in flight, it
+    // can't be written to, so it's replaced by a new set and freed by
+    // `free_retired_sets` later
+    fn replace_set(&mut self, index: usize) -> Result<(), Error> {
+        let set = unsafe {
+            self.descriptor_pool
+                .allocate_set(&self.descriptor_set_layouts[index])
+                .map_err(|_| Error::DescriptorSetAllocation)?
+        };
+        let old = mem::replace(&mut self.descriptor_sets[index], set);
+        self.retired_sets.push(old);
+        Ok(())
+    }
+
+    /// Points the matrix descriptor at a new `buffer`, in a new set.
+    pub fn rebind_matrix_buffer(&mut self, buffer: &B::Buffer) -> Result<(), Error> {
+        self.replace_set(MATRIX_SET)?;
+        self.write_matrix_descriptor(buffer);
+        Ok(())
+    }
+
+    /// Fills the texture array with `descriptors`, in a new set.
+    pub fn rebind_textures<'a>(
+        &'a mut self,
+        descriptors: impl IntoIterator<Item = Descriptor<'a, B>>,
+        region_buffer: &'a B::Buffer,
+    ) -> Result<(), Error> {
+        self.replace_set(TEXTURE_SET)?;
+        self.write_texture_descriptors(descriptors, region_buffer);
+        Ok(())
+    }
+
+    /// Records pushing the camera's `view_projection` matrix, followed by
+    /// the constants of the material, as far as they were set.
+    pub unsafe fn push_constants(&self, buffer: &mut B::CommandBuffer, view_projection: &Mat4) {
+        let camera = view_projection
+            .iter()
+            .map(|x| x.to_bits())
+            .collect::<ArrayVec<[u32; 16]>>();
+        buffer.push_graphics_constants(&self.pipeline_layout, ShaderStageFlags::VERTEX, 0, &camera);
+
+        for (stages, range) in &self.desc.push_constants {
+            let word = |offset: u32| ((offset - CAMERA_PUSH_CONSTANTS_SIZE) / 4) as usize;
+            let words = word(range.start)..word(range.end).min(self.constants.len());
+            if !words.is_empty() {
+                buffer.push_graphics_constants(
+                    &self.pipeline_layout,
+                    *stages,
+                    range.start,
+                    &self.constants[words],
+                );
+            }
+        }
+    }
+
+    /// Frees the sets replaced by the `rebind_*` methods once every frame
+    /// that was in flight when they were replaced has finished.
+    pub fn free_retired_sets(&mut self, fences: &[B::Fence]) {
+        let unused = self.retired_sets.take_unused::<B, D>(&self.device, fences);
+        if !unused.is_empty() {
+            unsafe {
+                self.descriptor_pool.free_sets(unused);
             }
         }
     }
 }
 
+// the shaders, descriptor set layouts and pipeline layout a pipeline is
+// built from
+type PipelineParts<B> = (
+    <B as Backend>::ShaderModule,
+    <B as Backend>::ShaderModule,
+    Vec<<B as Backend>::DescriptorSetLayout>,
+    <B as Backend>::PipelineLayout,
+);
+
 pub struct PipelineBuilder<'a, B: Backend, D: Device<B>> {
     device: Rc<ManuallyDrop<D>>,
     frag_shader: Option<B::ShaderModule>,
@@ -225,15 +456,11 @@ impl<'a, B: Backend, D: Device<B>> PipelineBuilder<'a, B, D> {
     }
 
     pub fn add_vert_shader(mut self, vert_data: &[u32]) -> Result<Self, (Self, Error)> {
-        if let Some(old) = mem::replace(
-            &mut self.vert_shader,
-            Some(unsafe {
-                match self.device.create_shader_module(vert_data) {
-                    Ok(m) => m,
-                    Err(e) => return Err((self, Error::ShaderCreation(ShaderKind::Vertex, e))),
-                }
-            }),
-        ) {
+        let module = match unsafe { self.device.create_shader_module(vert_data) } {
+            Ok(module) => module,
+            Err(e) => return Err((self, Error::ShaderCreation(ShaderKind::Vertex, e))),
+        };
+        if let Some(old) = self.vert_shader.replace(module) {
             unsafe {
                 self.device.destroy_shader_module(old);
             }
@@ -243,15 +470,11 @@ impl<'a, B: Backend, D: Device<B>> PipelineBuilder<'a, B, D> {
     }
 
     pub fn add_frag_shader(mut self, frag_data: &[u32]) -> Result<Self, (Self, Error)> {
-        if let Some(old) = mem::replace(
-            &mut self.frag_shader,
-            Some(unsafe {
-                match self.device.create_shader_module(frag_data) {
-                    Ok(m) => m,
-                    Err(e) => return Err((self, Error::ShaderCreation(ShaderKind::Fragment, e))),
-                }
-            }),
-        ) {
+        let module = match unsafe { self.device.create_shader_module(frag_data) } {
+            Ok(module) => module,
+            Err(e) => return Err((self, Error::ShaderCreation(ShaderKind::Fragment, e))),
+        };
+        if let Some(old) = self.frag_shader.replace(module) {
             unsafe {
                 self.device.destroy_shader_module(old);
             }
@@ -281,18 +504,14 @@ impl<'a, B: Backend, D: Device<B>> PipelineBuilder<'a, B, D> {
         mut self,
         push_constants: &'a [(ShaderStageFlags, core::ops::Range<u32>)],
     ) -> Result<Self, (Self, Error)> {
-        if let Some(old) = mem::replace(
-            &mut self.pipeline_layout,
-            Some(unsafe {
-                match self
-                    .device
-                    .create_pipeline_layout(&self.descriptor_set_layouts, push_constants)
-                {
-                    Ok(layout) => layout,
-                    Err(_) => return Err((self, Error::PipelineLayoutCreation)),
-                }
-            }),
-        ) {
+        let layout = match unsafe {
+            self.device
+                .create_pipeline_layout(&self.descriptor_set_layouts, push_constants)
+        } {
+            Ok(layout) => layout,
+            Err(_) => return Err((self, Error::PipelineLayoutCreation)),
+        };
+        if let Some(old) = self.pipeline_layout.replace(layout) {
             unsafe {
                 self.device.destroy_pipeline_layout(old);
             }
@@ -301,17 +520,7 @@ impl<'a, B: Backend, D: Device<B>> PipelineBuilder<'a, B, D> {
         Ok(self)
     }
 
-    pub fn into_data(
-        mut self,
-    ) -> Result<
-        (
-            B::ShaderModule,
-            B::ShaderModule,
-            Vec<B::DescriptorSetLayout>,
-            B::PipelineLayout,
-        ),
-        Self,
-    > {
+    pub fn into_data(mut self) -> Result<PipelineParts<B>, Self> {
         if self.vert_shader.is_some()
             && self.frag_shader.is_some()
             && self.pipeline_layout.is_some()
@@ -322,10 +531,10 @@ impl<'a, B: Backend, D: Device<B>> PipelineBuilder<'a, B, D> {
                 descriptor_set_layouts,
                 Some(pipeline_layout),
             ) = (
-                mem::replace(&mut self.vert_shader, None),
-                mem::replace(&mut self.frag_shader, None),
-                mem::replace(&mut self.descriptor_set_layouts, Vec::new()),
-                mem::replace(&mut self.pipeline_layout, None),
+                self.vert_shader.take(),
+                self.frag_shader.take(),
+                mem::take(&mut self.descriptor_set_layouts),
+                self.pipeline_layout.take(),
             ) {
                 Ok((
                     vert_shader,
@@ -353,6 +562,9 @@ impl<B: Backend, D: Device<B>> std::ops::Drop for PipelineData<B, D> {
             for layout in self.descriptor_set_layouts.drain(..) {
                 self.device.destroy_descriptor_set_layout(layout);
             }
+            self.descriptor_sets.clear();
+            self.device
+                .destroy_descriptor_pool(ManuallyDrop::into_inner(read(&self.descriptor_pool)));
         }
     }
 }
@@ -360,11 +572,11 @@ impl<B: Backend, D: Device<B>> std::ops::Drop for PipelineData<B, D> {
 impl<'a, B: Backend, D: Device<B>> std::ops::Drop for PipelineBuilder<'a, B, D> {
     fn drop(&mut self) {
         unsafe {
-            if let Some(module) = mem::replace(&mut self.vert_shader, None) {
+            if let Some(module) = self.vert_shader.take() {
                 self.device.destroy_shader_module(module);
             }
 
-            if let Some(module) = mem::replace(&mut self.frag_shader, None) {
+            if let Some(module) = self.frag_shader.take() {
                 self.device.destroy_shader_module(module);
             }
 
@@ -373,7 +585,7 @@ impl<'a, B: Backend, D: Device<B>> std::ops::Drop for PipelineBuilder<'a, B, D>
                     .destroy_descriptor_set_layout(descriptor_set_layout);
             }
 
-            if let Some(layout) = mem::replace(&mut self.pipeline_layout, None) {
+            if let Some(layout) = self.pipeline_layout.take() {
                 self.device.destroy_pipeline_layout(layout);
             }
         }
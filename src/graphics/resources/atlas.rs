@@ -0,0 +1,302 @@
+use crate::error::Error;
+use crate::geometry::Vec2;
+
+use super::textures::PIXEL_SIZE;
+
+/// Where an image ended up in an atlas. `x`, `y`, `width` and `height` are
+/// in pixels and exclude the padding, the UVs cover exactly the image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AtlasRegion {
+    pub page: usize,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub uv_min: Vec2,
+    pub uv_max: Vec2,
+}
+
+// a row of images in a page, as tall as the tallest image that opened it
+#[derive(Debug)]
+struct Shelf {
+    y: u32,
+    height: u32,
+    // where the next image in this shelf goes
+    x: u32,
+}
+
+#[derive(Debug)]
+pub struct AtlasPage {
+    pub pixels: Vec<u8>,
+    shelves: Vec<Shelf>,
+}
+
+impl AtlasPage {
+    fn new(width: u32, height: u32) -> Self {
+        AtlasPage {
+            pixels: vec![0; width as usize * height as usize * PIXEL_SIZE],
+            shelves: vec![],
+        }
+    }
+
+    // the y coordinate below the last shelf
+    fn free_y(&self) -> u32 {
+        self.shelves
+            .last()
+            .map_or(0, |shelf| shelf.y + shelf.height)
+    }
+}
+
+/// Packs many small images into a few large pages, which are then uploaded
+/// as textures with `Renderer::add_atlas`. Images are packed into shelves,
+/// and a new page is started whenever an image doesn't fit in any of the
+/// existing ones.
+///
+/// Every image is surrounded by `padding` pixels repeating its edges, so
+/// that filtering near the edges doesn't bleed in neighbouring images.
+#[derive(Debug)]
+pub struct AtlasBuilder {
+    page_width: u32,
+    page_height: u32,
+    padding: u32,
+    pages: Vec<AtlasPage>,
+    regions: Vec<AtlasRegion>,
+}
+
+impl AtlasBuilder {
+    pub fn new(page_width: u32, page_height: u32, padding: u32) -> Self {
+        AtlasBuilder {
+            page_width,
+            page_height,
+            padding,
+            pages: vec![],
+            regions: vec![],
+        }
+    }
+
+    /// Copies `width` by `height` RGBA8 pixels, given row by row, into the
+    /// atlas.
+    pub fn add_image(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<AtlasRegion, Error> {
+        // checked first, so the image's size can't overflow anything after
+        let padded = |size: u32| {
+            self.padding
+                .checked_mul(2)
+                .and_then(|padding| size.checked_add(padding))
+        };
+        let (padded_width, padded_height) = match (padded(width), padded(height)) {
+            (Some(padded_width), Some(padded_height))
+                if padded_width <= self.page_width && padded_height <= self.page_height =>
+            {
+                (padded_width, padded_height)
+            }
+            _ => return Err(Error::AtlasImageTooLarge(width, height)),
+        };
+
+        let expected_len = width as usize * height as usize * PIXEL_SIZE;
+        if pixels.len() != expected_len {
+            return Err(Error::TextureDataLength(expected_len, pixels.len()));
+        }
+
+        let (page, x, y) = if let Some(space) = self.find_space(padded_width, padded_height) {
+            space
+        } else {
+            self.pages
+                .push(AtlasPage::new(self.page_width, self.page_height));
+            let page = self.pages.len() - 1;
+            self.open_shelf(page, padded_width, padded_height)
+                .expect("an empty page fits any image that fits in a page")
+        };
+
+        self.blit(page, x, y, width, height, pixels);
+
+        let (x, y) = (x + self.padding, y + self.padding);
+        let page_size = Vec2::new(self.page_width as f32, self.page_height as f32);
+        let region = AtlasRegion {
+            page,
+            x,
+            y,
+            width,
+            height,
+            uv_min: Vec2::new(x as f32, y as f32).component_div(&page_size),
+            uv_max: Vec2::new((x + width) as f32, (y + height) as f32).component_div(&page_size),
+        };
+        self.regions.push(region);
+
+        Ok(region)
+    }
+
+    // finds the spot for a padded image, first by looking for the shelf that
+    // fits it most snugly, and then by opening a new shelf in any page
+    fn find_space(&mut self, width: u32, height: u32) -> Option<(usize, u32, u32)> {
+        let page_width = self.page_width;
+        let best_fit = self
+            .pages
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(page, atlas_page)| {
+                atlas_page
+                    .shelves
+                    .iter_mut()
+                    .map(move |shelf| (page, shelf))
+            })
+            .filter(|(_, shelf)| shelf.height >= height && page_width - shelf.x >= width)
+            .min_by_key(|(_, shelf)| shelf.height - height);
+
+        if let Some((page, shelf)) = best_fit {
+            let x = shelf.x;
+            shelf.x += width;
+            return Some((page, x, shelf.y));
+        }
+
+        (0..self.pages.len()).find_map(|page| self.open_shelf(page, width, height))
+    }
+
+    fn open_shelf(&mut self, page: usize, width: u32, height: u32) -> Option<(usize, u32, u32)> {
+        let atlas_page = &mut self.pages[page];
+        let y = atlas_page.free_y();
+        if self.page_height - y < height {
+            return None;
+        }
+
+        atlas_page.shelves.push(Shelf {
+            y,
+            height,
+            x: width,
+        });
+        Some((page, 0, y))
+    }
+
+    // copies the image to (x, y) plus the padding, clamping to the edges of
+    // the image to fill the padding around it
+    fn blit(&mut self, page: usize, x: u32, y: u32, width: u32, height: u32, pixels: &[u8]) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let padding = self.padding;
+        let page_width = self.page_width as usize;
+        let page_pixels = &mut self.pages[page].pixels;
+
+        for row in 0..height + 2 * padding {
+            let src_row = row.saturating_sub(padding).min(height - 1) as usize;
+            for column in 0..width + 2 * padding {
+                let src_column = column.saturating_sub(padding).min(width - 1) as usize;
+
+                let src = (src_row * width as usize + src_column) * PIXEL_SIZE;
+                let dst = ((y + row) as usize * page_width + (x + column) as usize) * PIXEL_SIZE;
+                page_pixels[dst..dst + PIXEL_SIZE].copy_from_slice(&pixels[src..src + PIXEL_SIZE]);
+            }
+        }
+    }
+
+    pub fn page_size(&self) -> (u32, u32) {
+        (self.page_width, self.page_height)
+    }
+
+    pub fn pages(&self) -> &[AtlasPage] {
+        &self.pages
+    }
+
+    /// Every region handed out by `add_image`, in the order they were added.
+    pub fn regions(&self) -> &[AtlasRegion] {
+        &self.regions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, color: u8) -> Vec<u8> {
+        vec![color; width as usize * height as usize * PIXEL_SIZE]
+    }
+
+    fn pixel(atlas: &AtlasBuilder, page: usize, x: u32, y: u32) -> &[u8] {
+        let start = ((y * atlas.page_width + x) as usize) * PIXEL_SIZE;
+        &atlas.pages[page].pixels[start..start + PIXEL_SIZE]
+    }
+
+    #[test]
+    fn images_share_a_shelf() {
+        let mut atlas = AtlasBuilder::new(64, 64, 0);
+        let a = atlas.add_image(16, 16, &image(16, 16, 1)).unwrap();
+        let b = atlas.add_image(16, 8, &image(16, 8, 2)).unwrap();
+
+        assert_eq!((a.page, a.x, a.y), (0, 0, 0));
+        assert_eq!((b.page, b.x, b.y), (0, 16, 0));
+        assert_eq!(a.uv_min, Vec2::new(0.0, 0.0));
+        assert_eq!(a.uv_max, Vec2::new(0.25, 0.25));
+        assert_eq!(pixel(&atlas, 0, 20, 4), &[2; 4]);
+    }
+
+    #[test]
+    fn padding_repeats_the_edges() {
+        let mut atlas = AtlasBuilder::new(16, 16, 2);
+        // a 2x1 image, red on the left and blue on the right
+        let region = atlas
+            .add_image(2, 1, &[0xff, 0, 0, 0xff, 0, 0, 0xff, 0xff])
+            .unwrap();
+
+        assert_eq!((region.x, region.y), (2, 2));
+        // the corners and sides of the padding take the closest pixel
+        assert_eq!(pixel(&atlas, 0, 0, 0), &[0xff, 0, 0, 0xff]);
+        assert_eq!(pixel(&atlas, 0, 5, 4), &[0, 0, 0xff, 0xff]);
+        assert_eq!(pixel(&atlas, 0, 2, 4), &[0xff, 0, 0, 0xff]);
+        // and nothing is written past it
+        assert_eq!(pixel(&atlas, 0, 6, 0), &[0; 4]);
+    }
+
+    #[test]
+    fn full_pages_open_new_pages() {
+        let mut atlas = AtlasBuilder::new(32, 32, 0);
+        for _ in 0..4 {
+            assert_eq!(atlas.add_image(16, 16, &image(16, 16, 1)).unwrap().page, 0);
+        }
+
+        let region = atlas.add_image(16, 16, &image(16, 16, 1)).unwrap();
+        assert_eq!((region.page, region.x, region.y), (1, 0, 0));
+        assert_eq!(atlas.pages().len(), 2);
+    }
+
+    #[test]
+    fn small_images_fill_the_gaps_in_earlier_pages() {
+        let mut atlas = AtlasBuilder::new(32, 32, 0);
+        // a tall shelf with room left on its right, and a full one below it
+        atlas.add_image(16, 24, &image(16, 24, 1)).unwrap();
+        atlas.add_image(32, 8, &image(32, 8, 1)).unwrap();
+        assert_eq!(atlas.add_image(32, 16, &image(32, 16, 1)).unwrap().page, 1);
+
+        let small = atlas.add_image(8, 8, &image(8, 8, 2)).unwrap();
+        assert_eq!((small.page, small.x, small.y), (0, 16, 0));
+        assert_eq!(pixel(&atlas, 0, 20, 4), &[2; 4]);
+    }
+
+    #[test]
+    fn images_larger_than_a_page_are_rejected() {
+        let mut atlas = AtlasBuilder::new(32, 32, 1);
+        assert!(matches!(
+            atlas.add_image(31, 8, &image(31, 8, 1)),
+            Err(Error::AtlasImageTooLarge(31, 8))
+        ));
+        assert!(matches!(
+            atlas.add_image(2, 2, &image(1, 1, 1)),
+            Err(Error::TextureDataLength(16, 4))
+        ));
+
+        // the padding would overflow
+        let mut padded = AtlasBuilder::new(32, 32, u32::MAX / 2);
+        assert!(matches!(
+            padded.add_image(2, 2, &image(2, 2, 1)),
+            Err(Error::AtlasImageTooLarge(2, 2))
+        ));
+        assert!(matches!(
+            atlas.add_image(u32::MAX, 1, &[]),
+            Err(Error::AtlasImageTooLarge(u32::MAX, 1))
+        ));
+    }
+}
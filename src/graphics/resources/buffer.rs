@@ -5,12 +5,11 @@ use gfx_hal::{
     adapter::{Adapter, PhysicalDevice},
     buffer::{CreationError as BufferCreationError, Usage as BufferUsage},
     device::Device,
-    memory::{Properties, Requirements, Segment},
-    pool::CommandPool,
+    memory::{Properties, Requirements},
     Backend, MemoryTypeId,
 };
 
-use crate::error::{BufferKind, BufferOp, Error, MemoryError, MemoryKind};
+use crate::error::{Error, MemoryError, MemoryKind};
 
 #[derive(Debug)]
 pub struct Memory<B: Backend, D: Device<B>> {
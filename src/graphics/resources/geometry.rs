@@ -1,7 +1,11 @@
 use core::mem::{self, ManuallyDrop};
+use core::ops::Range;
+use core::ptr;
 
 use std::rc::Rc;
 
+use arrayvec::ArrayVec;
+
 use gfx_hal::{
     adapter::{Adapter, PhysicalDevice},
     buffer::Usage as BufferUsage,
@@ -10,19 +14,34 @@ use gfx_hal::{
     memory::{Properties, Segment},
     pool::CommandPool,
     queue::CommandQueue,
-    Backend, MemoryTypeId,
+    Backend,
 };
 
 use crate::error::*;
 use crate::geometry::{Mat4, Quad as Quad3d};
+use crate::scene::SceneTree;
+
+use log::info;
 
 use super::buffer::{Buffer, Memory};
+use super::FrameRing;
 
 static QUAD_INDICES: [u16; 6] = [0, 1, 2, 2, 3, 0];
 
+// a buffer together with the memory it's bound to
+type MemoryAndBuffer<B, D> = (Memory<B, D>, Buffer<B, D>);
+
 // chosen arbitrarily, subject to change.
-const DEFAULT_NUM_MATRICES: u64 = 32;
-const DEFAULT_NUM_QUADS: u64 = 1024;
+pub(crate) const DEFAULT_NUM_MATRICES: u64 = 32;
+pub(crate) const DEFAULT_NUM_QUADS: u64 = 1024;
+
+/// How much data the last call to `GeometryBuffer::upload_changes` wrote,
+/// and into how many separate ranges of memory.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct UploadStats {
+    pub bytes_uploaded: u64,
+    pub ranges_flushed: usize,
+}
 
 #[derive(Debug)]
 pub struct GeometryBuffer<B: Backend, D: Device<B>> {
@@ -30,6 +49,18 @@ pub struct GeometryBuffer<B: Backend, D: Device<B>> {
     max_matrices: u64,
     max_quads: u64,
     allocated_mem: u64,
+    non_coherent_atom_size: u64,
+    // every frame in flight draws the scene from its own copy, so changing
+    // it only has to wait for the frame writing them. The slots after the
+    // copies are shared by the frames for geometry that's written every frame
+    scene: SceneCopies,
+    frames: usize,
+    immediate_matrices: FrameRing,
+    immediate_quads: FrameRing,
+    reallocated: bool,
+    // allocations replaced by a bigger one, which in-flight frames might still use
+    retired: super::Retired<GeometryAllocation<B, D>>,
+    pub last_upload: UploadStats,
     pub geometry_memory: Memory<B, D>,
     pub index_memory: Memory<B, D>,
     pub matrix_buffer: Buffer<B, D>,
@@ -44,6 +75,7 @@ impl<B: Backend, D: Device<B>> GeometryBuffer<B, D> {
         adapter: &Adapter<B>,
         command_pool: &mut C,
         command_queue: &mut B::CommandQueue,
+        frames: usize,
     ) -> Result<Self, Error> {
         Self::with_size(
             device,
@@ -52,6 +84,7 @@ impl<B: Backend, D: Device<B>> GeometryBuffer<B, D> {
             command_queue,
             DEFAULT_NUM_MATRICES,
             DEFAULT_NUM_QUADS,
+            frames,
         )
     }
 
@@ -62,12 +95,58 @@ impl<B: Backend, D: Device<B>> GeometryBuffer<B, D> {
         command_queue: &mut B::CommandQueue,
         num_matrices: u64,
         num_quads: u64,
+        frames: usize,
     ) -> Result<Self, Error> {
+        // zero-sized buffers can't be created
+        let (num_matrices, num_quads) = (num_matrices.max(1), num_quads.max(1));
+        let GeometryAllocation {
+            matrix_buffer,
+            quad_instance_buffer,
+            quad_buffer,
+            memory: geometry_memory,
+        } = Self::allocate(&device, adapter, num_matrices, num_quads)?;
+
+        let (index_memory, quad_index_buffer) =
+            Self::create_index_memory_and_buffer(&device, adapter, command_pool, command_queue)?;
+
+        Ok(Self {
+            device,
+            max_matrices: num_matrices,
+            max_quads: num_quads,
+            allocated_mem: Self::allocation_size(num_matrices, num_quads),
+            non_coherent_atom_size: adapter.physical_device.limits().non_coherent_atom_size as u64,
+            scene: SceneCopies::new(frames),
+            frames,
+            immediate_matrices: FrameRing::new(num_matrices as u32, frames),
+            immediate_quads: FrameRing::new(num_quads as u32, frames),
+            reallocated: false,
+            retired: super::Retired::new(),
+            last_upload: UploadStats::default(),
+            geometry_memory,
+            index_memory,
+            matrix_buffer,
+            quad_instance_buffer,
+            quad_buffer,
+            quad_index_buffer,
+        })
+    }
+
+    fn allocation_size(num_matrices: u64, num_quads: u64) -> u64 {
+        mem::size_of::<Mat4>() as u64 * num_matrices
+            + (mem::size_of::<u32>() + mem::size_of::<Quad3d>()) as u64 * num_quads
+    }
+
+    fn allocate(
+        device: &Rc<ManuallyDrop<D>>,
+        adapter: &Adapter<B>,
+        num_matrices: u64,
+        num_quads: u64,
+    ) -> Result<GeometryAllocation<B, D>, Error> {
         unsafe {
             let mut matrix_buffer = Buffer::new(
                 device.clone(),
                 num_matrices * mem::size_of::<Mat4>() as u64,
-                BufferUsage::VERTEX,
+                BufferUsage::STORAGE,
             )
             .map_err(|e| Error::BufferError(BufferOp::Create(e), BufferKind::Matrix))?;
 
@@ -92,7 +171,7 @@ impl<B: Backend, D: Device<B>> GeometryBuffer<B, D> {
 
             requirements.size += quad_instance_requirements.size + quad_requirements.size;
 
-            let geometry_memory = Memory::new(
+            let memory = Memory::new(
                 device.clone(),
                 adapter,
                 // CPU_VISIBLE | DEVICE_LOCAL might not always be available, but we hope it is
@@ -101,55 +180,145 @@ impl<B: Backend, D: Device<B>> GeometryBuffer<B, D> {
                 MemoryKind::Geometry,
             )?;
 
+            let layout = Layout {
+                max_matrices: num_matrices,
+                max_quads: num_quads,
+            };
+
             matrix_buffer
-                .bind_to_memory(&geometry_memory, 0)
+                .bind_to_memory(&memory, 0)
                 .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Matrix))?;
 
             quad_instance_buffer
-                .bind_to_memory(
-                    &geometry_memory,
-                    num_matrices * mem::size_of::<Mat4>() as u64,
-                )
+                .bind_to_memory(&memory, layout.instance_offset())
                 .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Quad))?;
 
             quad_buffer
-                .bind_to_memory(
-                    &geometry_memory,
-                    num_quads * mem::size_of::<u32>() as u64
-                        + num_matrices * mem::size_of::<Mat4>() as u64,
-                )
+                .bind_to_memory(&memory, layout.quad_offset())
                 .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Quad))?;
 
-            let (index_memory, quad_index_buffer) = Self::create_index_memory_and_buffer(
-                device.clone(),
-                adapter,
-                command_pool,
-                command_queue,
-            )?;
-
-            Ok(Self {
-                device,
-                max_matrices: num_matrices,
-                max_quads: num_quads,
-                allocated_mem: mem::size_of::<Mat4>() as u64 * num_matrices
-                    + (mem::size_of::<u32>() + mem::size_of::<Quad3d>()) as u64 * num_quads,
-                geometry_memory,
-                index_memory,
+            Ok(GeometryAllocation {
                 matrix_buffer,
                 quad_instance_buffer,
                 quad_buffer,
-                quad_index_buffer,
+                memory,
             })
         }
     }
 
+    /// Makes sure there's room for `num_matrices` matrices and `num_quads`
+    /// quads, moving everything into a bigger allocation if there isn't.
+    /// The old allocation is kept around until `free_retired` sees that no
+    /// frame can still be using it.
+    fn reserve(
+        &mut self,
+        adapter: &Adapter<B>,
+        num_matrices: u64,
+        num_quads: u64,
+    ) -> Result<(), Error> {
+        if num_matrices <= self.max_matrices && num_quads <= self.max_quads {
+            return Ok(());
+        }
+
+        let max_matrices = grown_capacity(self.max_matrices, num_matrices);
+        let max_quads = grown_capacity(self.max_quads, num_quads);
+
+        info!(
+            target: "rmge",
+            "growing geometry buffer to {} matrices and {} quads", max_matrices, max_quads
+        );
+
+        let new = Self::allocate(&self.device, adapter, max_matrices, max_quads)?;
+        let new_layout = Layout {
+            max_matrices,
+            max_quads,
+        };
+
+        unsafe {
+            self.copy_contents(&new.memory, new_layout)?;
+        }
+
+        let GeometryAllocation {
+            matrix_buffer,
+            quad_instance_buffer,
+            quad_buffer,
+            memory,
+        } = new;
+
+        self.retired.push(GeometryAllocation {
+            matrix_buffer: mem::replace(&mut self.matrix_buffer, matrix_buffer),
+            quad_instance_buffer: mem::replace(
+                &mut self.quad_instance_buffer,
+                quad_instance_buffer,
+            ),
+            quad_buffer: mem::replace(&mut self.quad_buffer, quad_buffer),
+            memory: mem::replace(&mut self.geometry_memory, memory),
+        });
+
+        self.max_matrices = max_matrices;
+        self.max_quads = max_quads;
+        self.allocated_mem = Self::allocation_size(max_matrices, max_quads);
+        self.reallocated = true;
+        // the frames in flight keep reading their slots from the old memory
+        self.reset_immediate();
+
+        Ok(())
+    }
+
+    // copies the matrices, node indices and quads into where they go in `new`
+    unsafe fn copy_contents(&self, new: &Memory<B, D>, new_layout: Layout) -> Result<(), Error> {
+        let old_layout = self.layout();
+
+        let src = self.map()?;
+        let dst = self
+            .device
+            .map_memory(&new.memory, Segment::ALL)
+            .map_err(|_| {
+                self.device.unmap_memory(&self.geometry_memory.memory);
+                Error::MemoryError(MemoryError::MappingError, MemoryKind::Geometry)
+            })?;
+
+        ptr::copy_nonoverlapping(src, dst, old_layout.instance_offset() as usize);
+        ptr::copy_nonoverlapping(
+            src.add(old_layout.instance_offset() as usize),
+            dst.add(new_layout.instance_offset() as usize),
+            (old_layout.quad_offset() - old_layout.instance_offset()) as usize,
+        );
+        ptr::copy_nonoverlapping(
+            src.add(old_layout.quad_offset() as usize),
+            dst.add(new_layout.quad_offset() as usize),
+            mem::size_of::<Quad3d>() * old_layout.max_quads as usize,
+        );
+
+        let flushed = self
+            .device
+            .flush_mapped_memory_ranges(Some((&*new.memory, Segment::ALL)))
+            .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Geometry));
+        self.device.unmap_memory(&new.memory);
+        self.device.unmap_memory(&self.geometry_memory.memory);
+        flushed
+    }
+
+    /// Returns whether the buffers were reallocated since the last call,
+    /// meaning anything referring to them (like descriptor sets) has to be
+    /// updated.
+    pub fn take_reallocated(&mut self) -> bool {
+        mem::replace(&mut self.reallocated, false)
+    }
+
+    /// Frees the allocations left behind by reallocating, once every frame
+    /// that was in flight when they were replaced has finished.
+    pub fn free_retired(&mut self, fences: &[B::Fence]) {
+        self.retired.take_unused::<B, D>(&self.device, fences);
+    }
+
     // separate function to ease the error handling a little bit
     fn create_index_memory_and_buffer<C: CommandPool<B>>(
-        device: Rc<ManuallyDrop<D>>,
+        device: &Rc<ManuallyDrop<D>>,
         adapter: &Adapter<B>,
         command_pool: &mut C,
         command_queue: &mut B::CommandQueue,
-    ) -> Result<(Memory<B, D>, Buffer<B, D>), Error> {
+    ) -> Result<MemoryAndBuffer<B, D>, Error> {
         unsafe {
             let mut index_buffer = Buffer::new(
                 device.clone(),
@@ -197,38 +366,799 @@ impl<B: Backend, D: Device<B>> GeometryBuffer<B, D> {
         }
     }
 
-    pub fn add_matrix(&mut self, trans: Mat4, index: usize) -> Result<(), Error> {
-        unimplemented!()
+    fn layout(&self) -> Layout {
+        Layout {
+            max_matrices: self.max_matrices,
+            max_quads: self.max_quads,
+        }
     }
 
-    pub fn add_quad(
-        &mut self,
+    unsafe fn map(&self) -> Result<*mut u8, Error> {
+        self.device
+            .map_memory(&self.geometry_memory.memory, Segment::ALL)
+            .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Geometry))
+    }
+
+    unsafe fn write_matrix(&self, ptr: *mut u8, index: usize, trans: &Mat4) -> Result<(), Error> {
+        if index as u64 >= self.max_matrices {
+            return Err(Error::CapacityExceeded(BufferKind::Matrix));
+        }
+        ptr::write((ptr as *mut Mat4).add(index), *trans);
+        Ok(())
+    }
+
+    unsafe fn write_quad(
+        &self,
+        ptr: *mut u8,
         index: usize,
-        texture_index: u32,
-        quad: Quad3d,
+        node_index: u32,
+        quad: &Quad3d,
     ) -> Result<(), Error> {
+        if index as u64 >= self.max_quads {
+            return Err(Error::CapacityExceeded(BufferKind::Quad));
+        }
+        ptr::write(
+            (ptr.add(self.layout().instance_offset() as usize) as *mut u32).add(index),
+            node_index,
+        );
+        ptr::write(
+            (ptr.add(self.layout().quad_offset() as usize) as *mut Quad3d).add(index),
+            *quad,
+        );
+        Ok(())
+    }
+
+    unsafe fn write_and_flush(
+        &self,
+        writes: &[GeometryWrite],
+        ranges: &[Range<u64>],
+    ) -> Result<(), Error> {
+        let ptr = self.map()?;
+        let written = writes.iter().try_for_each(|write| match *write {
+            GeometryWrite::Matrix { index, matrix } => self.write_matrix(ptr, index, matrix),
+            GeometryWrite::Quad {
+                index,
+                node_index,
+                quad,
+            } => self.write_quad(ptr, index, node_index, quad),
+        });
+        let flushed = self
+            .device
+            .flush_mapped_memory_ranges(ranges.iter().map(|range| {
+                (
+                    &*self.geometry_memory.memory,
+                    Segment {
+                        offset: range.start,
+                        size: Some(range.end - range.start),
+                    },
+                )
+            }))
+            .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Geometry));
+        self.device.unmap_memory(&self.geometry_memory.memory);
+        written?;
+        flushed
+    }
+
+    /// Writes geometry that isn't part of the uploaded scene into slots
+    /// after its copies, which only `frame` uses until its next submission
+    /// has finished, growing the buffers if there isn't room. The quads'
+    /// node indices are relative to the first of `matrices`. Returns the
+    /// slots of the first matrix and quad.
+    pub fn write_immediate(
+        &mut self,
+        adapter: &Adapter<B>,
+        frame: usize,
+        matrices: &[Mat4],
+        quads: &[(u32, Quad3d)],
+    ) -> Result<(u32, u32), Error> {
+        let (num_matrices, num_quads) = (matrices.len() as u32, quads.len() as u32);
+        // allocated even when there's nothing to write, so the frame's old
+        // slots are freed
+        let (first_matrix, first_quad) =
+            if let Some(first) = self.allocate_immediate(frame, num_matrices, num_quads) {
+                first
+            } else {
+                let matrix_slots = (self.immediate_matrices.capacity() * 2).max(num_matrices);
+                let quad_slots = (self.immediate_quads.capacity() * 2).max(num_quads);
+                let (scene_matrices, scene_quads) = self.scene.slots();
+                self.reserve(
+                    adapter,
+                    scene_matrices + u64::from(matrix_slots),
+                    scene_quads + u64::from(quad_slots),
+                )?;
+                self.allocate_immediate(frame, num_matrices, num_quads)
+                    .ok_or(Error::CapacityExceeded(BufferKind::Quad))?
+            };
+        if matrices.is_empty() && quads.is_empty() {
+            return Ok((first_matrix, first_quad));
+        }
+
+        let layout = self.layout();
+        let writes = matrices
+            .iter()
+            .enumerate()
+            .map(|(index, matrix)| GeometryWrite::Matrix {
+                index: first_matrix as usize + index,
+                matrix,
+            })
+            .chain(quads.iter().enumerate().map(|(index, (node_index, quad))| {
+                GeometryWrite::Quad {
+                    index: first_quad as usize + index,
+                    node_index: first_matrix + node_index,
+                    quad,
+                }
+            }))
+            .collect::<Vec<_>>();
+        let mut ranges = writes
+            .iter()
+            .flat_map(|write| layout.ranges(write))
+            .collect::<Vec<_>>();
+        let ranges = merge_ranges(
+            &mut ranges,
+            self.non_coherent_atom_size,
+            self.geometry_memory.size,
+        );
+
         unsafe {
-            let mapped_segment = Segment {
-                offset: (mem::size_of::<Mat4>() as u64 * self.max_matrices
-                    + mem::size_of::<u32>() as u64 * self.max_quads),
-                size: None,
+            self.write_and_flush(&writes, &ranges)?;
+        }
+        Ok((first_matrix, first_quad))
+    }
+
+    // the first matrix and quad slot of `frame`'s immediate geometry, if
+    // there's room for it
+    fn allocate_immediate(
+        &mut self,
+        frame: usize,
+        num_matrices: u32,
+        num_quads: u32,
+    ) -> Option<(u32, u32)> {
+        let matrices = self.immediate_matrices.allocate(frame, num_matrices)?;
+        let quads = self.immediate_quads.allocate(frame, num_quads)?;
+        let (scene_matrices, scene_quads) = self.scene.slots();
+        Some((
+            scene_matrices as u32 + matrices.start,
+            scene_quads as u32 + quads.start,
+        ))
+    }
+
+    // hands the slots after the scene's copies out to the frames from
+    // scratch, which is only safe once no frame in flight reads from them
+    // anymore, or when they're in new memory
+    fn reset_immediate(&mut self) {
+        let (scene_matrices, scene_quads) = self.scene.slots();
+        self.immediate_matrices =
+            FrameRing::new((self.max_matrices - scene_matrices) as u32, self.frames);
+        self.immediate_quads = FrameRing::new((self.max_quads - scene_quads) as u32, self.frames);
+    }
+
+    /// Brings `frame`'s copy of `scene` up to date, writing only the
+    /// caches and quads that were modified since it was last written, and
+    /// flushing only the memory they occupy. The other copies are brought up
+    /// to date once their frames come around. If a child or quad was added
+    /// anywhere in the tree, everything is written again, and if `scene`
+    /// doesn't fit anymore, the buffers are reallocated first. Otherwise
+    /// changes to culled nodes are left for when they're back in view.
+    /// Afterwards the modifications that were uploaded are unset.
+    ///
+    /// `frame`'s last submission has to have finished. The other frames are
+    /// only waited for with `wait_for_frames` when the copies have to grow,
+    /// which moves all of them along with the slots after them.
+    ///
+    /// Caches go in the slot given by their node's depth-first index, and
+    /// quads are laid out in depth-first order, tagged with the depth-first
+    /// index of the node they belong to, both counted from the start of the
+    /// copy. Returns the slot of the copy's first quad.
+    pub fn upload_changes(
+        &mut self,
+        adapter: &Adapter<B>,
+        frame: usize,
+        scene: &mut SceneTree,
+        wait_for_frames: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<u32, Error> {
+        let (stale, moved) = self.scene.update(frame, scene);
+        // culled changes can only be unset if every copy gets them anyway
+        let unset_all = stale.everything && self.scene.all_stale(frame);
+        let first_quad =
+            match self.write_copy(adapter, frame, scene, &stale, moved, wait_for_frames) {
+                Ok(first_quad) => first_quad,
+                Err(e) => {
+                    // the copy might be missing anything now
+                    self.scene.invalidate();
+                    return Err(e);
+                }
             };
 
-            let quad_ptr = self
-                .device
-                .map_memory(&self.geometry_memory.memory, mapped_segment.clone())
-                .expect("this is bad");
+        if unset_all {
+            scene.unset_modifications();
+        } else {
+            scene.unset_visible_modifications();
+        }
+
+        Ok(first_quad)
+    }
+
+    // writes the `stale` parts of `frame`'s copy of `scene`, after making
+    // room for the copies if they `moved`
+    fn write_copy(
+        &mut self,
+        adapter: &Adapter<B>,
+        frame: usize,
+        scene: &SceneTree,
+        stale: &StaleSlots,
+        moved: bool,
+        wait_for_frames: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<u32, Error> {
+        if moved {
+            let (scene_matrices, scene_quads) = self.scene.slots();
+            self.reserve(adapter, scene_matrices, scene_quads)?;
+            wait_for_frames()?;
+            self.reset_immediate();
+        }
+
+        let (first_matrix, first_quad) = self.scene.first_slots(frame);
+        let plan = UploadPlan::new(
+            scene,
+            stale,
+            (first_matrix, first_quad),
+            self.layout(),
+            self.non_coherent_atom_size,
+            self.geometry_memory.size,
+        )?;
+        if !plan.writes.is_empty() {
+            unsafe {
+                self.write_and_flush(&plan.writes, &plan.ranges)?;
+            }
+        }
+        self.last_upload = plan.stats;
+
+        Ok(first_quad as u32)
+    }
+}
+
+/// Keeps track of where each frame in flight's copy of the scene goes, and
+/// of what every copy is missing.
+#[derive(Debug)]
+pub(crate) struct SceneCopies {
+    // how many matrix and quad slots each copy has room for
+    matrix_slots: u64,
+    quad_slots: u64,
+    // per frame in flight
+    stale: Vec<StaleSlots>,
+    // set when writing a copy failed, so room is made for all of them again
+    // and they're written from scratch
+    invalid: bool,
+}
+
+impl SceneCopies {
+    pub fn new(frames: usize) -> Self {
+        SceneCopies {
+            matrix_slots: 0,
+            quad_slots: 0,
+            stale: vec![StaleSlots::everything(); frames.max(1)],
+            invalid: false,
+        }
+    }
+
+    /// How many matrix and quad slots all of the copies take up together.
+    pub fn slots(&self) -> (u64, u64) {
+        let frames = self.stale.len() as u64;
+        (frames * self.matrix_slots, frames * self.quad_slots)
+    }
+
+    /// The first matrix and quad slot of `frame`'s copy.
+    pub fn first_slots(&self, frame: usize) -> (u64, u64) {
+        (
+            frame as u64 * self.matrix_slots,
+            frame as u64 * self.quad_slots,
+        )
+    }
+
+    /// Makes room for `scene` in every copy, and records the changes made
+    /// to it in all of them. Returns what `frame`'s copy is missing, and
+    /// whether the copies had to grow, in which case every copy is written
+    /// from scratch and no frame may be reading any of them anymore.
+    pub fn update(&mut self, frame: usize, scene: &SceneTree) -> (StaleSlots, bool) {
+        let (num_matrices, num_quads) = required_capacity(scene);
+        let moved = mem::take(&mut self.invalid)
+            || num_matrices > self.matrix_slots
+            || num_quads > self.quad_slots;
+        if moved {
+            self.matrix_slots = grown_capacity(self.matrix_slots, num_matrices);
+            self.quad_slots = grown_capacity(self.quad_slots, num_quads);
+        }
+
+        let changes = if moved {
+            StaleSlots::everything()
+        } else {
+            StaleSlots::changed(scene)
+        };
+        for stale in &mut self.stale {
+            stale.add(&changes);
+        }
+
+        let mut stale = mem::take(&mut self.stale[frame]);
+        stale.matrices.sort_unstable();
+        stale.matrices.dedup();
+        stale.quads.sort_unstable();
+        stale.quads.dedup();
+        (stale, moved)
+    }
 
-            use std::ptr;
+    pub fn invalidate(&mut self) {
+        self.invalid = true;
+    }
 
-            ptr::write((quad_ptr as *mut Quad3d).offset(index as isize), quad);
+    /// Whether every copy but `frame`'s is going to be written from scratch.
+    pub fn all_stale(&self, frame: usize) -> bool {
+        self.stale
+            .iter()
+            .enumerate()
+            .all(|(other, stale)| other == frame || stale.everything)
+    }
+}
+
+/// The caches and quads a copy of the scene is missing, by their position
+/// in `SceneTree::get_cache_and_quad_array`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct StaleSlots {
+    everything: bool,
+    matrices: Vec<usize>,
+    quads: Vec<usize>,
+}
+
+impl StaleSlots {
+    fn everything() -> Self {
+        StaleSlots {
+            everything: true,
+            ..StaleSlots::default()
+        }
+    }
 
-            self.device
-                .flush_mapped_memory_ranges(Some((&*self.geometry_memory.memory, mapped_segment)))
-                .expect("failed flush");
-            self.device.unmap_memory(&self.geometry_memory.memory);
+    // what was modified in `scene` since its modifications were last unset.
+    // A node whose depth-first index changed needs its cache moved and its
+    // quads retagged, even if they weren't modified themselves. Changes to
+    // culled nodes wait until they're back in view
+    fn changed(scene: &SceneTree) -> Self {
+        if scene.structure_changed() {
+            return StaleSlots::everything();
+        }
+
+        let (caches, quads) = scene.get_cache_and_quad_array();
+        let (culled_nodes, culled_quads) = scene.culled();
+        let matrices = caches
+            .iter()
+            .zip(culled_nodes)
+            .enumerate()
+            .filter(|(_, ((index, cache), culled))| {
+                !culled && (index.is_modified() || cache.is_modified())
+            })
+            .map(|(position, _)| position)
+            .collect();
+        let quads = quads
+            .iter()
+            .zip(culled_quads)
+            .enumerate()
+            .filter(|(_, ((index, quad), culled))| {
+                !culled && (index.is_modified() || quad.is_modified())
+            })
+            .map(|(position, _)| position)
+            .collect();
+
+        StaleSlots {
+            everything: false,
+            matrices,
+            quads,
+        }
+    }
+
+    fn add(&mut self, changes: &StaleSlots) {
+        if changes.everything {
+            *self = StaleSlots::everything();
+        } else if !self.everything {
+            self.matrices.extend_from_slice(&changes.matrices);
+            self.quads.extend_from_slice(&changes.quads);
+        }
+    }
+}
+
+// buffers are declared before the memory so they're destroyed before it's freed
+#[derive(Debug)]
+struct GeometryAllocation<B: Backend, D: Device<B>> {
+    matrix_buffer: Buffer<B, D>,
+    quad_instance_buffer: Buffer<B, D>,
+    quad_buffer: Buffer<B, D>,
+    memory: Memory<B, D>,
+}
+
+fn grown_capacity(current: u64, required: u64) -> u64 {
+    required.max(current * 2).next_power_of_two()
+}
+
+// how many matrix and quad slots uploading `scene` needs
+fn required_capacity(scene: &SceneTree) -> (u64, u64) {
+    let (caches, quads) = scene.get_cache_and_quad_array();
+    let num_matrices = caches
+        .iter()
+        .map(|&(index, _)| **index as u64 + 1)
+        .max()
+        .unwrap_or(0);
+    (num_matrices, quads.len() as u64)
+}
+
+// where everything lives inside the geometry memory
+#[derive(Debug, Copy, Clone)]
+struct Layout {
+    max_matrices: u64,
+    max_quads: u64,
+}
+
+impl Layout {
+    fn instance_offset(self) -> u64 {
+        mem::size_of::<Mat4>() as u64 * self.max_matrices
+    }
+
+    fn quad_offset(self) -> u64 {
+        self.instance_offset() + mem::size_of::<u32>() as u64 * self.max_quads
+    }
+
+    fn ranges(self, write: &GeometryWrite) -> ArrayVec<[Range<u64>; 2]> {
+        let mut ranges = ArrayVec::new();
+        match *write {
+            GeometryWrite::Matrix { index, .. } => {
+                let matrix_size = mem::size_of::<Mat4>() as u64;
+                let matrix_start = index as u64 * matrix_size;
+                ranges.push(matrix_start..matrix_start + matrix_size);
+            }
+            GeometryWrite::Quad { index, .. } => {
+                let instance_size = mem::size_of::<u32>() as u64;
+                let instance_start = self.instance_offset() + index as u64 * instance_size;
+                ranges.push(instance_start..instance_start + instance_size);
+
+                let quad_size = mem::size_of::<Quad3d>() as u64;
+                let quad_start = self.quad_offset() + index as u64 * quad_size;
+                ranges.push(quad_start..quad_start + quad_size);
+            }
+        }
+        ranges
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum GeometryWrite<'a> {
+    Matrix {
+        index: usize,
+        matrix: &'a Mat4,
+    },
+    Quad {
+        index: usize,
+        node_index: u32,
+        quad: &'a Quad3d,
+    },
+}
+
+// everything `upload_changes` needs to write and flush, worked out before
+// touching any memory
+#[derive(Debug)]
+struct UploadPlan<'a> {
+    writes: Vec<GeometryWrite<'a>>,
+    ranges: Vec<Range<u64>>,
+    stats: UploadStats,
+}
+
+impl<'a> UploadPlan<'a> {
+    // writes the `stale` parts of the copy of `scene` starting at the
+    // `first` matrix and quad slot
+    fn new(
+        scene: &'a SceneTree,
+        stale: &StaleSlots,
+        first: (u64, u64),
+        layout: Layout,
+        atom_size: u64,
+        memory_size: u64,
+    ) -> Result<Self, Error> {
+        let (caches, quads) = scene.get_cache_and_quad_array();
+        let (first_matrix, first_quad) = first;
 
-            Ok(())
+        if first_quad + quads.len() as u64 > layout.max_quads {
+            return Err(Error::CapacityExceeded(BufferKind::Quad));
         }
+        if caches
+            .iter()
+            .any(|&(index, _)| first_matrix + **index as u64 >= layout.max_matrices)
+        {
+            return Err(Error::CapacityExceeded(BufferKind::Matrix));
+        }
+
+        let (stale_matrices, stale_quads) = if stale.everything {
+            ((0..caches.len()).collect(), (0..quads.len()).collect())
+        } else {
+            (stale.matrices.clone(), stale.quads.clone())
+        };
+        let matrices = stale_matrices
+            .into_iter()
+            .filter_map(|position| caches.get(position))
+            .map(|&(index, cache)| GeometryWrite::Matrix {
+                index: first_matrix as usize + **index,
+                matrix: cache,
+            });
+        let quad_writes = stale_quads.into_iter().filter_map(|position| {
+            quads
+                .get(position)
+                .map(|&(index, quad)| GeometryWrite::Quad {
+                    index: first_quad as usize + position,
+                    node_index: (first_matrix as usize + **index) as u32,
+                    quad,
+                })
+        });
+        let writes = matrices.chain(quad_writes).collect::<Vec<_>>();
+
+        let mut raw_ranges = writes
+            .iter()
+            .flat_map(|write| layout.ranges(write))
+            .collect::<Vec<_>>();
+        let bytes_uploaded = raw_ranges.iter().map(|range| range.end - range.start).sum();
+
+        let ranges = merge_ranges(&mut raw_ranges, atom_size, memory_size);
+        let stats = UploadStats {
+            bytes_uploaded,
+            ranges_flushed: ranges.len(),
+        };
+
+        Ok(UploadPlan {
+            writes,
+            ranges,
+            stats,
+        })
+    }
+}
+
+// widens every range to a multiple of `atom_size` (as required for flushing
+// non-coherent memory), then merges all overlapping or touching ranges
+fn merge_ranges(ranges: &mut [Range<u64>], atom_size: u64, memory_size: u64) -> Vec<Range<u64>> {
+    let atom_size = atom_size.max(1);
+    for range in ranges.iter_mut() {
+        range.start -= range.start % atom_size;
+        range.end += (atom_size - range.end % atom_size) % atom_size;
+        range.end = range.end.min(memory_size);
+    }
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range.clone()),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Camera, Vec3};
+    use crate::scene::{CullStats, SceneNode};
+
+    const LAYOUT: Layout = Layout {
+        max_matrices: 32,
+        max_quads: 1024,
+    };
+    const MATRIX_SIZE: u64 = mem::size_of::<Mat4>() as u64;
+    const QUAD_SIZE: u64 = (mem::size_of::<u32>() + mem::size_of::<Quad3d>()) as u64;
+    const MEMORY_SIZE: u64 = 32 * MATRIX_SIZE + 1024 * QUAD_SIZE;
+
+    fn quad() -> Quad3d {
+        Quad3d::new([
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ])
+    }
+
+    fn scene() -> SceneTree {
+        let mut root = SceneNode::new(Mat4::identity());
+        root.add_quad(quad());
+        let mut child = SceneNode::new(2.0 * Mat4::identity());
+        child.add_quad(quad());
+        child.add_quad(quad());
+        root.add_child(child);
+        SceneTree::new(root)
+    }
+
+    // what uploading `scene` to `frame`'s copy writes, like `upload_changes`
+    fn upload(copies: &mut SceneCopies, frame: usize, scene: &mut SceneTree) -> UploadStats {
+        scene.recompute_caches();
+        let (stale, _) = copies.update(frame, scene);
+        let unset_all = stale.everything && copies.all_stale(frame);
+        let stats = UploadPlan::new(
+            scene,
+            &stale,
+            copies.first_slots(frame),
+            LAYOUT,
+            1,
+            MEMORY_SIZE,
+        )
+        .unwrap()
+        .stats;
+        if unset_all {
+            scene.unset_modifications();
+        } else {
+            scene.unset_visible_modifications();
+        }
+        stats
+    }
+
+    #[test]
+    fn first_frame_uploads_everything() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(1);
+        assert_eq!(
+            upload(&mut copies, 0, &mut scene).bytes_uploaded,
+            2 * MATRIX_SIZE + 3 * QUAD_SIZE
+        );
+    }
+
+    #[test]
+    fn unchanged_frame_uploads_nothing() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(1);
+        upload(&mut copies, 0, &mut scene);
+        assert_eq!(upload(&mut copies, 0, &mut scene), UploadStats::default());
+    }
+
+    #[test]
+    fn modified_quad_uploads_only_that_quad() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(1);
+        upload(&mut copies, 0, &mut scene);
+
+        scene.root_mut().get_children_mut()[0].get_quads_mut()[1].points[0].x = 0.5;
+
+        assert_eq!(
+            upload(&mut copies, 0, &mut scene),
+            UploadStats {
+                bytes_uploaded: QUAD_SIZE,
+                ranges_flushed: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn modified_transform_uploads_only_affected_caches() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(1);
+        upload(&mut copies, 0, &mut scene);
+
+        *scene.root_mut().get_children_mut()[0].transform = 3.0 * Mat4::identity();
+
+        assert_eq!(
+            upload(&mut copies, 0, &mut scene),
+            UploadStats {
+                bytes_uploaded: MATRIX_SIZE,
+                ranges_flushed: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn culled_changes_wait_until_back_in_view() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(1);
+        let child = scene.root().get_children()[0].id();
+        scene
+            .get_mut(child)
+            .unwrap()
+            .set_translation(Vec3::new(100.0, 0.0, 0.0));
+        upload(&mut copies, 0, &mut scene);
+
+        let frustum = Camera::orthographic().frustum(10.0, 10.0);
+        assert_eq!(
+            scene.cull(Some(&frustum)),
+            CullStats {
+                nodes_culled: 1,
+                quads_culled: 2,
+            }
+        );
+        scene.get_mut(child).unwrap().get_quads_mut()[0].points[0].x = 0.5;
+        assert_eq!(upload(&mut copies, 0, &mut scene), UploadStats::default());
+
+        scene.cull(None);
+        assert_eq!(upload(&mut copies, 0, &mut scene).bytes_uploaded, QUAD_SIZE);
+    }
+
+    #[test]
+    fn capacity_grows_to_fit_the_scene() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(1);
+        for _ in 0..3000 {
+            scene.root_mut().add_quad(quad());
+        }
+        scene.recompute_caches();
+        let (num_matrices, num_quads) = required_capacity(&scene);
+
+        assert_eq!(num_quads, 3003);
+        assert!(num_matrices <= LAYOUT.max_matrices);
+        assert_eq!(grown_capacity(LAYOUT.max_quads, num_quads), 4096);
+        assert_eq!(grown_capacity(LAYOUT.max_quads, 1025), 2048);
+
+        let (stale, moved) = copies.update(0, &scene);
+        assert!(moved && stale.everything);
+        assert_eq!(copies.slots(), (2, 4096));
+        // there's room now
+        assert!(!copies.update(0, &scene).1);
+    }
+
+    #[test]
+    fn every_frame_catches_up_on_changes_to_its_own_copy() {
+        let mut scene = scene();
+        let mut copies = SceneCopies::new(2);
+        // each copy is written in full the first time its frame comes up
+        upload(&mut copies, 0, &mut scene);
+        assert_eq!(copies.first_slots(1), (2, 4));
+        assert_eq!(
+            upload(&mut copies, 1, &mut scene).bytes_uploaded,
+            2 * MATRIX_SIZE + 3 * QUAD_SIZE
+        );
+
+        scene.root_mut().get_quads_mut()[0].points[0].x = 0.5;
+        assert_eq!(upload(&mut copies, 0, &mut scene).bytes_uploaded, QUAD_SIZE);
+        *scene.root_mut().get_children_mut()[0].transform = 3.0 * Mat4::identity();
+        // frame 1 missed the quad change while frame 0 wrote it
+        assert_eq!(
+            upload(&mut copies, 1, &mut scene).bytes_uploaded,
+            MATRIX_SIZE + QUAD_SIZE
+        );
+        assert_eq!(
+            upload(&mut copies, 0, &mut scene).bytes_uploaded,
+            MATRIX_SIZE
+        );
+        assert_eq!(upload(&mut copies, 1, &mut scene), UploadStats::default());
+    }
+
+    #[test]
+    fn copies_point_at_their_own_matrices() {
+        let mut scene = scene();
+        scene.recompute_caches();
+        let mut copies = SceneCopies::new(2);
+        copies.update(0, &scene);
+        let (stale, _) = copies.update(1, &scene);
+        let plan = UploadPlan::new(
+            &scene,
+            &stale,
+            copies.first_slots(1),
+            LAYOUT,
+            1,
+            MEMORY_SIZE,
+        )
+        .unwrap();
+
+        let slots = plan
+            .writes
+            .iter()
+            .map(|write| match *write {
+                GeometryWrite::Matrix { index, .. } => (index, None),
+                GeometryWrite::Quad {
+                    index, node_index, ..
+                } => (index, Some(node_index)),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            slots,
+            vec![
+                (2, None),
+                (3, None),
+                (4, Some(2)),
+                (5, Some(3)),
+                (6, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_are_aligned_and_merged() {
+        let mut ranges = vec![70..80, 0..10, 12..20, 200..210];
+        assert_eq!(merge_ranges(&mut ranges, 64, 205), vec![0..128, 192..205]);
     }
 }
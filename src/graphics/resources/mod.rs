@@ -1,26 +1,190 @@
-use std::mem::ManuallyDrop;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Range;
 use std::rc::Rc;
 
 use gfx_hal::{adapter::Adapter, device::Device, pool::CommandPool, Backend};
 
+pub mod atlas;
 pub mod buffer;
 pub mod geometry;
+pub mod sprites;
 pub mod textures;
 
 #[derive(Debug)]
 pub struct ResourceManager<B: Backend, D: Device<B>> {
     pub geometry_buffer: geometry::GeometryBuffer<B, D>,
+    pub sprites: sprites::SpriteBatch<B, D>,
+    pub textures: textures::TextureManager<B, D>,
 }
 
 impl<B: Backend, D: Device<B>> ResourceManager<B, D> {
+    /// Creates the geometry buffer with room for `max_matrices` node
+    /// matrices and `max_quads` quads to begin with, the sprite ring buffer
+    /// with room for `max_sprites` sprites shared by `frames` frames in
+    /// flight, and the textures.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: Rc<ManuallyDrop<D>>,
         adapter: &Adapter<B>,
         pool: &mut impl CommandPool<B>,
         queue: &mut B::CommandQueue,
+        max_matrices: u64,
+        max_quads: u64,
+        max_sprites: u32,
+        frames: usize,
     ) -> Result<Self, crate::error::Error> {
         Ok(Self {
-            geometry_buffer: geometry::GeometryBuffer::new(device, adapter, pool, queue)?,
+            geometry_buffer: geometry::GeometryBuffer::with_size(
+                device.clone(),
+                adapter,
+                pool,
+                queue,
+                max_matrices,
+                max_quads,
+                frames,
+            )?,
+            sprites: sprites::SpriteBatch::new(device.clone(), adapter, max_sprites, frames)?,
+            textures: textures::TextureManager::new(device, adapter, pool, queue)?,
         })
     }
 }
+
+/// Resources that were replaced while frames in flight might still use
+/// them. Each is kept until the fence of every frame has been seen
+/// signalled after it was retired, since a fence only signals again once
+/// the submission it guarded has finished.
+#[derive(Debug)]
+pub(crate) struct Retired<T> {
+    // each resource, with which frames' fences were seen signalled since
+    entries: Vec<(T, Vec<bool>)>,
+}
+
+impl<T> Retired<T> {
+    pub fn new() -> Self {
+        Retired { entries: vec![] }
+    }
+
+    pub fn push(&mut self, resource: T) {
+        self.entries.push((resource, vec![]));
+    }
+
+    /// Returns the resources no frame can be using anymore, going by which
+    /// of the per-frame `fences` have signalled by now.
+    pub fn take_unused<B: Backend, D: Device<B>>(
+        &mut self,
+        device: &D,
+        fences: &[B::Fence],
+    ) -> Vec<T> {
+        if self.entries.is_empty() {
+            return vec![];
+        }
+        let signalled = fences
+            .iter()
+            .map(|fence| unsafe { device.get_fence_status(fence) }.unwrap_or(false))
+            .collect::<Vec<_>>();
+        self.take_signalled(&signalled)
+    }
+
+    fn take_signalled(&mut self, signalled: &[bool]) -> Vec<T> {
+        let mut unused = vec![];
+        for (resource, seen) in mem::take(&mut self.entries) {
+            let seen = if seen.is_empty() {
+                signalled.to_vec()
+            } else {
+                seen.iter().zip(signalled).map(|(a, b)| *a || *b).collect()
+            };
+            if seen.iter().all(|&seen| seen) {
+                unused.push(resource);
+            } else {
+                self.entries.push((resource, seen));
+            }
+        }
+        unused
+    }
+}
+
+/// Hands out the slots of a ring buffer shared by the frames in flight,
+/// making sure a frame never gets slots another one may still be reading.
+#[derive(Debug)]
+pub(crate) struct FrameRing {
+    capacity: u32,
+    head: u32,
+    // per frame in flight, the slots its last submission reads from
+    in_use: Vec<Range<u32>>,
+}
+
+impl FrameRing {
+    pub fn new(capacity: u32, frames: usize) -> Self {
+        FrameRing {
+            capacity,
+            head: 0,
+            in_use: vec![0..0; frames.max(1)],
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Finds `count` consecutive slots for `frame`, whose last submission
+    /// has to have finished, wrapping around to the start if they don't fit
+    /// at the end. Returns `None` if they'd overlap another frame's slots.
+    pub fn allocate(&mut self, frame: usize, count: u32) -> Option<Range<u32>> {
+        self.in_use[frame] = 0..0;
+
+        let start = if self.head + count <= self.capacity {
+            self.head
+        } else {
+            0
+        };
+        let slots = start..start + count;
+        let overlaps = |used: &Range<u32>| used.start < slots.end && slots.start < used.end;
+        if slots.end > self.capacity || self.in_use.iter().any(overlaps) {
+            return None;
+        }
+
+        self.head = slots.end;
+        self.in_use[frame] = slots.clone();
+        Some(slots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retired_resources_wait_for_every_frame_once() {
+        let mut retired = Retired::new();
+        retired.push(0);
+        // frame 1 is still in flight, then frame 0 is while frame 1 isn't
+        assert!(retired.take_signalled(&[true, false]).is_empty());
+        retired.push(1);
+        assert_eq!(retired.take_signalled(&[false, true]), vec![0]);
+        assert!(retired.take_signalled(&[false, true]).is_empty());
+        assert_eq!(retired.take_signalled(&[true, false]), vec![1]);
+    }
+
+    #[test]
+    fn frames_never_share_slots() {
+        let mut ring = FrameRing::new(10, 2);
+
+        assert_eq!(ring.allocate(0, 4), Some(0..4));
+        assert_eq!(ring.allocate(1, 4), Some(4..8));
+        // doesn't fit at the end, but frame 1 only uses 4..8
+        assert_eq!(ring.allocate(0, 4), Some(0..4));
+        // frame 1 is done with its slots, but frame 0 isn't
+        assert_eq!(ring.allocate(1, 7), None);
+        assert_eq!(ring.allocate(1, 6), Some(4..10));
+    }
+
+    #[test]
+    fn empty_frames_release_their_slots() {
+        let mut ring = FrameRing::new(8, 2);
+
+        assert_eq!(ring.allocate(0, 8), Some(0..8));
+        assert_eq!(ring.allocate(1, 1), None);
+        assert_eq!(ring.allocate(0, 0), Some(8..8));
+        assert_eq!(ring.allocate(1, 8), Some(0..8));
+    }
+}
@@ -0,0 +1,203 @@
+use core::mem::{self, ManuallyDrop};
+use core::ops::Range;
+use core::ptr;
+
+use std::rc::Rc;
+
+use gfx_hal::{
+    adapter::Adapter, buffer::Usage as BufferUsage, device::Device, memory::Properties,
+    memory::Segment, Backend,
+};
+
+use crate::error::*;
+use crate::geometry::Quad;
+
+use log::info;
+
+use super::buffer::{Buffer, Memory};
+use super::FrameRing;
+
+pub(crate) const DEFAULT_NUM_SPRITES: u32 = 4096;
+
+/// The sprites drawn each frame, written into a ring buffer that stays
+/// mapped for as long as it lives. Each sprite is an instance read through
+/// the same vertex buffers as the geometry buffer's quads, so every
+/// material can draw them.
+#[derive(Debug)]
+pub struct SpriteBatch<B: Backend, D: Device<B>> {
+    device: Rc<ManuallyDrop<D>>,
+    ring: FrameRing,
+    frames: usize,
+    allocation: SpriteAllocation<B, D>,
+    // allocations replaced by a bigger one, which in-flight frames might still use
+    retired: super::Retired<SpriteAllocation<B, D>>,
+}
+
+impl<B: Backend, D: Device<B>> SpriteBatch<B, D> {
+    /// Creates a ring buffer with room for `num_sprites` sprites, shared by
+    /// `frames` frames in flight.
+    pub fn new(
+        device: Rc<ManuallyDrop<D>>,
+        adapter: &Adapter<B>,
+        num_sprites: u32,
+        frames: usize,
+    ) -> Result<Self, Error> {
+        let num_sprites = num_sprites.max(1);
+        let allocation = SpriteAllocation::new(&device, adapter, num_sprites)?;
+
+        Ok(SpriteBatch {
+            device,
+            ring: FrameRing::new(num_sprites, frames),
+            frames,
+            allocation,
+            retired: super::Retired::new(),
+        })
+    }
+
+    pub fn quad_buffer(&self) -> &B::Buffer {
+        &self.allocation.quad_buffer.buffer
+    }
+
+    pub fn instance_buffer(&self) -> &B::Buffer {
+        &self.allocation.instance_buffer.buffer
+    }
+
+    /// Writes `quads` into slots `frame` can use, all tagged with
+    /// `node_index`, growing the ring buffer if there isn't enough room.
+    /// Returns the slots, which are the instances to draw.
+    pub fn write(
+        &mut self,
+        adapter: &Adapter<B>,
+        frame: usize,
+        node_index: u32,
+        quads: &[Quad],
+    ) -> Result<Range<u32>, Error> {
+        let count = quads.len() as u32;
+        let slots = if let Some(slots) = self.ring.allocate(frame, count) {
+            slots
+        } else {
+            self.grow(adapter, count)?;
+            self.ring
+                .allocate(frame, count)
+                .ok_or(Error::CapacityExceeded(BufferKind::Sprite))?
+        };
+
+        let allocation = &self.allocation;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                quads.as_ptr(),
+                (allocation.mapped.add(allocation.quad_offset) as *mut Quad)
+                    .add(slots.start as usize),
+                quads.len(),
+            );
+            let indices = (allocation.mapped as *mut u32).add(slots.start as usize);
+            for index in 0..quads.len() {
+                ptr::write(indices.add(index), node_index);
+            }
+        }
+
+        Ok(slots)
+    }
+
+    // moves on to a ring buffer with room for at least `count` sprites on
+    // top of the ones in flight, which are left in the old one
+    fn grow(&mut self, adapter: &Adapter<B>, count: u32) -> Result<(), Error> {
+        let capacity = (self.ring.capacity() * 2).max(count).next_power_of_two();
+        info!(target: "rmge", "growing sprite buffer to {} sprites", capacity);
+
+        let allocation = SpriteAllocation::new(&self.device, adapter, capacity)?;
+        self.retired
+            .push(mem::replace(&mut self.allocation, allocation));
+        self.ring = FrameRing::new(capacity, self.frames);
+        Ok(())
+    }
+
+    /// Frees the ring buffers left behind by growing, once every frame that
+    /// was in flight when they were replaced has finished.
+    pub fn free_retired(&mut self, fences: &[B::Fence]) {
+        self.retired.take_unused::<B, D>(&self.device, fences);
+    }
+}
+
+// the memory stays mapped until it's freed, and the buffers are declared
+// before it so they're destroyed before it's freed
+#[derive(Debug)]
+struct SpriteAllocation<B: Backend, D: Device<B>> {
+    mapped: *mut u8,
+    quad_offset: usize,
+    instance_buffer: Buffer<B, D>,
+    quad_buffer: Buffer<B, D>,
+    memory: Memory<B, D>,
+}
+
+impl<B: Backend, D: Device<B>> SpriteAllocation<B, D> {
+    fn new(
+        device: &Rc<ManuallyDrop<D>>,
+        adapter: &Adapter<B>,
+        capacity: u32,
+    ) -> Result<Self, Error> {
+        unsafe {
+            let mut instance_buffer = Buffer::new(
+                device.clone(),
+                u64::from(capacity) * mem::size_of::<u32>() as u64,
+                BufferUsage::VERTEX,
+            )
+            .map_err(|e| Error::BufferError(BufferOp::Create(e), BufferKind::Sprite))?;
+            let mut quad_buffer = Buffer::new(
+                device.clone(),
+                u64::from(capacity) * mem::size_of::<Quad>() as u64,
+                BufferUsage::VERTEX,
+            )
+            .map_err(|e| Error::BufferError(BufferOp::Create(e), BufferKind::Sprite))?;
+
+            let mut requirements = device.get_buffer_requirements(&instance_buffer.buffer);
+            let quad_requirements = device.get_buffer_requirements(&quad_buffer.buffer);
+            let quad_offset = Self::quad_offset_for(capacity, quad_requirements.alignment);
+            requirements.size = quad_offset as u64 + quad_requirements.size;
+            requirements.type_mask &= quad_requirements.type_mask;
+
+            // coherent, so writes don't have to be flushed
+            let memory = Memory::new(
+                device.clone(),
+                adapter,
+                Properties::CPU_VISIBLE | Properties::COHERENT,
+                requirements,
+                MemoryKind::Sprite,
+            )?;
+
+            instance_buffer
+                .bind_to_memory(&memory, 0)
+                .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Sprite))?;
+            quad_buffer
+                .bind_to_memory(&memory, quad_offset as u64)
+                .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Sprite))?;
+
+            let mapped = device
+                .map_memory(&memory.memory, Segment::ALL)
+                .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Sprite))?;
+
+            Ok(SpriteAllocation {
+                mapped,
+                quad_offset,
+                instance_buffer,
+                quad_buffer,
+                memory,
+            })
+        }
+    }
+
+    // the indices are followed by the quads, at the next multiple of `alignment`
+    fn quad_offset_for(capacity: u32, alignment: u64) -> usize {
+        let indices_size = u64::from(capacity) * mem::size_of::<u32>() as u64;
+        let alignment = alignment.max(1);
+        (indices_size.div_ceil(alignment) * alignment) as usize
+    }
+}
+
+impl<B: Backend, D: Device<B>> Drop for SpriteAllocation<B, D> {
+    fn drop(&mut self) {
+        unsafe {
+            self.memory.device.unmap_memory(&self.memory.memory);
+        }
+    }
+}
@@ -1,8 +1,599 @@
-use core::mem::ManuallyDrop;
-use gfx_hal::{device::Device, Backend};
+use core::mem::{self, ManuallyDrop};
+use core::ptr;
+
 use std::rc::Rc;
 
-pub struct SpriteBatch<B: Backend, D: Device<B>> {
-    pub device: Rc<ManuallyDrop<D>>,
-    pub memory: B::Memory,
+use gfx_hal::{
+    adapter::{Adapter, PhysicalDevice},
+    buffer::Usage as BufferUsage,
+    command::{BufferImageCopy, CommandBuffer, CommandBufferFlags, Level},
+    device::Device,
+    format::{Aspects, Format, Swizzle},
+    image::{
+        Access, Extent, Filter, Kind, Layout, Offset, SamplerDesc, SubresourceLayers,
+        SubresourceRange, Tiling, Usage as ImageUsage, ViewCapabilities, ViewKind, WrapMode,
+    },
+    memory::{Barrier, Dependencies, Properties, Segment},
+    pool::CommandPool,
+    pso::{Descriptor, PipelineStage},
+    queue::CommandQueue,
+    Backend,
+};
+
+use crate::error::*;
+use crate::geometry::Vec2;
+
+use super::atlas::AtlasBuilder;
+use super::buffer::{Buffer, Memory};
+
+// how many textures can be alive at once, has to match the size of the
+// `textures` array in the fragment shader
+pub const MAX_TEXTURES: usize = 64;
+// how many regions, i.e. whole textures plus atlas regions, can be alive at once
+pub const MAX_REGIONS: usize = 4096;
+
+const FORMAT: Format = Format::Rgba8Srgb;
+pub(crate) const PIXEL_SIZE: usize = 4;
+
+/// Identifies a region of a texture, either a whole texture or an image in
+/// an atlas, by its index in the region buffer the vertex shader reads.
+#[repr(transparent)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TextureHandle(u32);
+
+impl TextureHandle {
+    /// A single white pixel, which is what untextured quads sample.
+    pub const WHITE: TextureHandle = TextureHandle(0);
+
+    pub fn index(self) -> u32 {
+        self.0
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new(index: u32) -> Self {
+        TextureHandle(index)
+    }
+}
+
+pub(crate) fn color_range() -> SubresourceRange {
+    SubresourceRange {
+        aspects: Aspects::COLOR,
+        levels: 0..1,
+        layers: 0..1,
+    }
+}
+
+/// The size of a row of `width` pixels in a buffer copied to or from an
+/// image, rounded up to `pitch_alignment`.
+pub(crate) fn row_pitch(width: u32, pitch_alignment: u64) -> u64 {
+    let row_size = u64::from(width) * PIXEL_SIZE as u64;
+    row_size + (pitch_alignment - row_size % pitch_alignment) % pitch_alignment
+}
+
+#[derive(Debug)]
+pub struct Texture<B: Backend, D: Device<B>> {
+    device: Rc<ManuallyDrop<D>>,
+    image: ManuallyDrop<B::Image>,
+    view: ManuallyDrop<B::ImageView>,
+    sampler: ManuallyDrop<B::Sampler>,
+    memory: Memory<B, D>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl<B: Backend, D: Device<B>> Texture<B, D> {
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn new<C: CommandPool<B>>(
+        device: Rc<ManuallyDrop<D>>,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        filter: Filter,
+    ) -> Result<Self, Error> {
+        let mut image = device
+            .create_image(
+                Kind::D2(width, height, 1, 1),
+                1,
+                FORMAT,
+                Tiling::Optimal,
+                ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
+                ViewCapabilities::empty(),
+            )
+            .map_err(|_| Error::ImageCreationError)?;
+
+        let memory = match Memory::new(
+            device.clone(),
+            adapter,
+            Properties::DEVICE_LOCAL,
+            device.get_image_requirements(&image),
+            MemoryKind::Image,
+        ) {
+            Ok(memory) => memory,
+            Err(e) => {
+                device.destroy_image(image);
+                return Err(e);
+            }
+        };
+
+        if device
+            .bind_image_memory(&memory.memory, 0, &mut image)
+            .is_err()
+        {
+            device.destroy_image(image);
+            return Err(Error::ImageBindError);
+        }
+
+        let view = match device
+            .create_image_view(&image, ViewKind::D2, FORMAT, Swizzle::NO, color_range())
+            .map_err(|_| Error::ImageViewCreationError)
+        {
+            Ok(view) => view,
+            Err(e) => {
+                device.destroy_image(image);
+                return Err(e);
+            }
+        };
+
+        let sampler = match device
+            .create_sampler(&SamplerDesc::new(filter, WrapMode::Clamp))
+            .map_err(|_| Error::SamplerCreationError)
+        {
+            Ok(sampler) => sampler,
+            Err(e) => {
+                device.destroy_image_view(view);
+                device.destroy_image(image);
+                return Err(e);
+            }
+        };
+
+        // from here on out, dropping the texture cleans up after us
+        let texture = Texture {
+            device,
+            image: ManuallyDrop::new(image),
+            view: ManuallyDrop::new(view),
+            sampler: ManuallyDrop::new(sampler),
+            memory,
+            width,
+            height,
+        };
+        texture.upload(adapter, command_pool, command_queue, pixels)?;
+
+        Ok(texture)
+    }
+
+    // copies `pixels` into a staging buffer and from there into the image,
+    // leaving the image ready to be sampled from
+    unsafe fn upload<C: CommandPool<B>>(
+        &self,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+        pixels: &[u8],
+    ) -> Result<(), Error> {
+        let device = &self.device;
+
+        // each row in the staging buffer has to start at a multiple of this
+        let pitch_alignment = adapter
+            .physical_device
+            .limits()
+            .optimal_buffer_copy_pitch_alignment
+            .max(1);
+        let row_size = u64::from(self.width) * PIXEL_SIZE as u64;
+        let row_pitch = row_pitch(self.width, pitch_alignment);
+
+        let mut staging_buffer = Buffer::new(
+            device.clone(),
+            row_pitch * u64::from(self.height),
+            BufferUsage::TRANSFER_SRC,
+        )
+        .map_err(|e| Error::BufferError(BufferOp::Create(e), BufferKind::Staging))?;
+
+        let staging_memory = Memory::new(
+            device.clone(),
+            adapter,
+            Properties::CPU_VISIBLE | Properties::COHERENT,
+            device.get_buffer_requirements(&staging_buffer.buffer),
+            MemoryKind::Staging,
+        )?;
+
+        staging_buffer
+            .bind_to_memory(&staging_memory, 0)
+            .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Staging))?;
+
+        let mapped = device
+            .map_memory(&staging_memory.memory, Segment::ALL)
+            .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Staging))?;
+        for (row, row_pixels) in pixels.chunks_exact(row_size as usize).enumerate() {
+            ptr::copy_nonoverlapping(
+                row_pixels.as_ptr(),
+                mapped.add(row * row_pitch as usize),
+                row_pixels.len(),
+            );
+        }
+        device.unmap_memory(&staging_memory.memory);
+
+        let fence = device
+            .create_fence(false)
+            .map_err(|_| Error::FenceCreationError)?;
+
+        let mut buffer = command_pool.allocate_one(Level::Primary);
+        device.set_command_buffer_name(&mut buffer, "texture upload buffer");
+        buffer.begin_primary(CommandBufferFlags::ONE_TIME_SUBMIT);
+        buffer.pipeline_barrier(
+            PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
+            Dependencies::empty(),
+            &[Barrier::Image {
+                states: (Access::empty(), Layout::Undefined)
+                    ..(Access::TRANSFER_WRITE, Layout::TransferDstOptimal),
+                target: &*self.image,
+                families: None,
+                range: color_range(),
+            }],
+        );
+        buffer.copy_buffer_to_image(
+            &staging_buffer.buffer,
+            &self.image,
+            Layout::TransferDstOptimal,
+            &[BufferImageCopy {
+                buffer_offset: 0,
+                buffer_width: (row_pitch / PIXEL_SIZE as u64) as u32,
+                buffer_height: self.height,
+                image_layers: SubresourceLayers {
+                    aspects: Aspects::COLOR,
+                    level: 0,
+                    layers: 0..1,
+                },
+                image_offset: Offset::ZERO,
+                image_extent: Extent {
+                    width: self.width,
+                    height: self.height,
+                    depth: 1,
+                },
+            }],
+        );
+        buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+            Dependencies::empty(),
+            &[Barrier::Image {
+                states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
+                    ..(Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+                target: &*self.image,
+                families: None,
+                range: color_range(),
+            }],
+        );
+        buffer.finish();
+
+        command_queue.submit_without_semaphores(Some(&buffer), Some(&fence));
+        let waited = device
+            .wait_for_fence(&fence, u64::MAX)
+            .map_err(|_| Error::FenceError(FenceOp::Wait));
+        device.destroy_fence(fence);
+        command_pool.free(Some(buffer));
+
+        waited.map(|_| ())
+    }
+}
+
+impl<B: Backend, D: Device<B>> Drop for Texture<B, D> {
+    fn drop(&mut self) {
+        unsafe {
+            use std::ptr::read;
+
+            self.device
+                .destroy_sampler(ManuallyDrop::into_inner(read(&self.sampler)));
+            self.device
+                .destroy_image_view(ManuallyDrop::into_inner(read(&self.view)));
+            self.device
+                .destroy_image(ManuallyDrop::into_inner(read(&self.image)));
+        }
+    }
+}
+
+// the uv rectangle of a texture a `TextureHandle` refers to, laid out the
+// way the vertex shader expects it in the region buffer
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Region {
+    uv_min: [f32; 2],
+    uv_max: [f32; 2],
+    texture: u32,
+    // std430 aligns the struct to the alignment of a vec2
+    _padding: u32,
+}
+
+impl Region {
+    fn new(texture: u32, uv_min: Vec2, uv_max: Vec2) -> Self {
+        Region {
+            uv_min: [uv_min.x, uv_min.y],
+            uv_max: [uv_max.x, uv_max.y],
+            texture,
+            _padding: 0,
+        }
+    }
+
+    fn whole(texture: u32) -> Self {
+        Region::new(texture, Vec2::new(0.0, 0.0), Vec2::new(1.0, 1.0))
+    }
+}
+
+/// Owns every texture along with the region buffer, and keeps track of
+/// whether the descriptors pointing at the textures have to be rewritten.
+#[derive(Debug)]
+pub struct TextureManager<B: Backend, D: Device<B>> {
+    device: Rc<ManuallyDrop<D>>,
+    textures: Vec<Texture<B, D>>,
+    regions: Vec<Region>,
+    region_buffer: Buffer<B, D>,
+    region_memory: Memory<B, D>,
+    changed: bool,
+}
+
+impl<B: Backend, D: Device<B>> TextureManager<B, D> {
+    pub fn new<C: CommandPool<B>>(
+        device: Rc<ManuallyDrop<D>>,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+    ) -> Result<Self, Error> {
+        let mut region_buffer = Buffer::new(
+            device.clone(),
+            (MAX_REGIONS * mem::size_of::<Region>()) as u64,
+            BufferUsage::STORAGE,
+        )
+        .map_err(|e| Error::BufferError(BufferOp::Create(e), BufferKind::Region))?;
+
+        // regions are only ever appended, so they can be written while frames
+        // using the ones before them are in flight
+        let region_memory = unsafe {
+            Memory::new(
+                device.clone(),
+                adapter,
+                Properties::CPU_VISIBLE | Properties::COHERENT,
+                device.get_buffer_requirements(&region_buffer.buffer),
+                MemoryKind::Region,
+            )?
+        };
+        region_buffer
+            .bind_to_memory(&region_memory, 0)
+            .map_err(|e| Error::BufferError(BufferOp::Bind(e), BufferKind::Region))?;
+
+        let mut manager = TextureManager {
+            device,
+            textures: vec![],
+            regions: vec![],
+            region_buffer,
+            region_memory,
+            changed: true,
+        };
+
+        // this ends up at `TextureHandle::WHITE`
+        manager.add_texture(
+            adapter,
+            command_pool,
+            command_queue,
+            1,
+            1,
+            &[0xff; PIXEL_SIZE],
+            Filter::Nearest,
+        )?;
+
+        Ok(manager)
+    }
+
+    /// Uploads `width` by `height` sRGB RGBA8 pixels, given row by row,
+    /// into a new device-local image.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_texture<C: CommandPool<B>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        filter: Filter,
+    ) -> Result<TextureHandle, Error> {
+        self.check_capacity(1, 1)?;
+
+        let changed = self.changed;
+        let texture = self.upload_texture(
+            adapter,
+            command_pool,
+            command_queue,
+            width,
+            height,
+            pixels,
+            filter,
+        )?;
+        match self.add_regions(&[Region::whole(texture)]) {
+            Ok(handles) => Ok(handles[0]),
+            Err(e) => {
+                self.textures.pop();
+                self.changed = changed;
+                Err(e)
+            }
+        }
+    }
+
+    /// Uploads every page of `atlas` as a texture, returning a handle for
+    /// every region in `atlas.regions()`, in the same order. If any of it
+    /// fails, none of the pages are kept.
+    pub fn add_atlas<C: CommandPool<B>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+        atlas: &AtlasBuilder,
+        filter: Filter,
+    ) -> Result<Vec<TextureHandle>, Error> {
+        self.check_capacity(atlas.pages().len(), atlas.regions().len())?;
+
+        let first_texture = self.textures.len();
+        let changed = self.changed;
+        let handles = self.upload_atlas(adapter, command_pool, command_queue, atlas, filter);
+        if handles.is_err() {
+            // no region or descriptor refers to the pages uploaded so far, so
+            // they're dropped right away instead of keeping their slots
+            self.textures.truncate(first_texture);
+            self.changed = changed;
+        }
+        handles
+    }
+
+    // uploads the pages of `atlas` and adds its regions, leaving whatever
+    // was uploaded before an error in place
+    fn upload_atlas<C: CommandPool<B>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+        atlas: &AtlasBuilder,
+        filter: Filter,
+    ) -> Result<Vec<TextureHandle>, Error> {
+        let (width, height) = atlas.page_size();
+        let pages = atlas
+            .pages()
+            .iter()
+            .map(|page| {
+                self.upload_texture(
+                    adapter,
+                    command_pool,
+                    command_queue,
+                    width,
+                    height,
+                    &page.pixels,
+                    filter,
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let regions = atlas
+            .regions()
+            .iter()
+            .map(|region| Region::new(pages[region.page], region.uv_min, region.uv_max))
+            .collect::<Vec<_>>();
+        self.add_regions(&regions)
+    }
+
+    fn check_capacity(&self, num_textures: usize, num_regions: usize) -> Result<(), Error> {
+        if self.textures.len() + num_textures > MAX_TEXTURES {
+            Err(Error::CapacityExceeded(BufferKind::Image))
+        } else if self.regions.len() + num_regions > MAX_REGIONS {
+            Err(Error::CapacityExceeded(BufferKind::Region))
+        } else {
+            Ok(())
+        }
+    }
+
+    // uploads a texture into the next free slot of the texture array,
+    // returning that slot
+    #[allow(clippy::too_many_arguments)]
+    fn upload_texture<C: CommandPool<B>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut C,
+        command_queue: &mut B::CommandQueue,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        filter: Filter,
+    ) -> Result<u32, Error> {
+        if width == 0 || height == 0 {
+            return Err(Error::EmptyTexture(width, height));
+        }
+        let expected_len = width as usize * height as usize * PIXEL_SIZE;
+        if pixels.len() != expected_len {
+            return Err(Error::TextureDataLength(expected_len, pixels.len()));
+        }
+
+        let texture = unsafe {
+            Texture::new(
+                self.device.clone(),
+                adapter,
+                command_pool,
+                command_queue,
+                width,
+                height,
+                pixels,
+                filter,
+            )?
+        };
+        self.textures.push(texture);
+        self.changed = true;
+
+        Ok(self.textures.len() as u32 - 1)
+    }
+
+    // appends `regions` to the region buffer, returning their handles
+    fn add_regions(&mut self, regions: &[Region]) -> Result<Vec<TextureHandle>, Error> {
+        let first = self.regions.len();
+        unsafe {
+            let mapped = self
+                .device
+                .map_memory(&self.region_memory.memory, Segment::ALL)
+                .map_err(|_| Error::MemoryError(MemoryError::MappingError, MemoryKind::Region))?;
+            ptr::copy_nonoverlapping(
+                regions.as_ptr(),
+                (mapped as *mut Region).add(first),
+                regions.len(),
+            );
+            self.device.unmap_memory(&self.region_memory.memory);
+        }
+        self.regions.extend_from_slice(regions);
+
+        Ok((first..self.regions.len())
+            .map(|index| TextureHandle(index as u32))
+            .collect())
+    }
+
+    /// The texture `handle` refers to a region of.
+    pub fn get(&self, handle: TextureHandle) -> Option<&Texture<B, D>> {
+        let region = self.regions.get(handle.0 as usize)?;
+        self.textures.get(region.texture as usize)
+    }
+
+    /// The index in the texture array of the texture `handle` refers to a
+    /// region of, with unknown handles counting as the white texture.
+    pub fn texture_index(&self, handle: TextureHandle) -> u32 {
+        self.regions
+            .get(handle.0 as usize)
+            .map_or(0, |region| region.texture)
+    }
+
+    /// The size in pixels of the region `handle` refers to.
+    pub fn region_size(&self, handle: TextureHandle) -> Option<Vec2> {
+        let region = self.regions.get(handle.0 as usize)?;
+        let texture = self.textures.get(region.texture as usize)?;
+        Some(Vec2::new(
+            (region.uv_max[0] - region.uv_min[0]) * texture.width as f32,
+            (region.uv_max[1] - region.uv_min[1]) * texture.height as f32,
+        ))
+    }
+
+    /// Returns whether textures were added since the last call, meaning the
+    /// texture descriptors have to be written again.
+    pub fn take_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.changed, false)
+    }
+
+    /// A descriptor for every slot in the texture array, slots without a
+    /// texture get the white one so that every descriptor is valid.
+    pub fn descriptors(&self) -> impl Iterator<Item = Descriptor<'_, B>> {
+        (0..MAX_TEXTURES).map(move |slot| {
+            let texture = self.textures.get(slot).unwrap_or(&self.textures[0]);
+            Descriptor::CombinedImageSampler(
+                &*texture.view,
+                Layout::ShaderReadOnlyOptimal,
+                &*texture.sampler,
+            )
+        })
+    }
+
+    pub fn region_buffer(&self) -> &B::Buffer {
+        &self.region_buffer.buffer
+    }
 }
@@ -0,0 +1,163 @@
+use gfx_hal::{
+    adapter::{AdapterInfo, DeviceType},
+    format::Format,
+    window::PresentMode,
+    Features,
+};
+
+use super::resources::geometry::{DEFAULT_NUM_MATRICES, DEFAULT_NUM_QUADS};
+use super::resources::sprites::DEFAULT_NUM_SPRITES;
+
+/// Which adapter to render with, if there's a choice. Unless told not to,
+/// the context falls back to the others when none of the matching ones
+/// work, trying discrete GPUs first and software ones last.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum AdapterPreference {
+    /// Whichever works, in the same order as the fallback.
+    #[default]
+    Any,
+    Discrete,
+    Integrated,
+    /// A CPU implementation, like lavapipe.
+    Software,
+    /// An adapter whose name contains the given string, ignoring case.
+    Named(String),
+    /// The adapter at `AdapterDetails::index`, e.g. picked by the user from
+    /// `Context::adapters`.
+    Index(usize),
+}
+
+impl AdapterPreference {
+    /// Whether the adapter at `index` in the backend's list matches.
+    pub fn matches(&self, index: usize, info: &AdapterInfo) -> bool {
+        match self {
+            AdapterPreference::Any => true,
+            AdapterPreference::Discrete => info.device_type == DeviceType::DiscreteGpu,
+            AdapterPreference::Integrated => info.device_type == DeviceType::IntegratedGpu,
+            AdapterPreference::Software => info.device_type == DeviceType::Cpu,
+            AdapterPreference::Named(name) => {
+                info.name.to_lowercase().contains(&name.to_lowercase())
+            }
+            AdapterPreference::Index(preferred) => index == *preferred,
+        }
+    }
+}
+
+/// Choices made once, when building a context. Most of them are only
+/// preferences, `Context::choices` tells what was actually picked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub adapter: AdapterPreference,
+    /// Whether to try other adapters when none matching `adapter` work,
+    /// rather than failing with `Error::NoMatchingAdapter`.
+    pub adapter_fallback: bool,
+    /// The present modes to try, in order. If the surface supports none of
+    /// them, whichever of mailbox, FIFO, relaxed and immediate it supports
+    /// first is used instead.
+    pub present_modes: Vec<PresentMode>,
+    /// How many frames may be recorded while the GPU is still busy with
+    /// earlier ones. More lets the CPU get further ahead, at the cost of
+    /// latency. Headless contexts wait for every frame right away.
+    pub frames_in_flight: usize,
+    /// The surface formats to try, in order, before falling back to the
+    /// first sRGB one. Headless contexts always render to RGBA8 sRGB.
+    pub formats: Vec<Format>,
+    /// How many node matrices and quads the geometry buffer has room for at
+    /// first, which holds a copy of the scene for every frame in flight. It
+    /// grows when a scene doesn't fit, which stalls that frame.
+    pub max_matrices: u64,
+    pub max_quads: u64,
+    /// How many sprites the ring buffer they're written into has room for
+    /// at first, shared by the frames in flight. It grows like the geometry
+    /// buffer does.
+    pub max_sprites: u32,
+    /// Device features to enable on top of the ones the renderer needs,
+    /// adapters lacking any of them are skipped.
+    pub features: Features,
+    /// Whether to render with a depth buffer, so quads are layered by their
+    /// z coordinate instead of by the order they're drawn in. Left out if
+    /// the adapter can't render to any depth format.
+    pub depth_buffer: bool,
+    /// How many samples to take per pixel, smoothing the edges of quads.
+    /// Has to be a power of two the adapter supports, 1 turns multisampling
+    /// off.
+    pub samples: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            adapter: AdapterPreference::Any,
+            adapter_fallback: true,
+            present_modes: default_present_modes(),
+            frames_in_flight: 2,
+            formats: vec![],
+            max_matrices: DEFAULT_NUM_MATRICES,
+            max_quads: DEFAULT_NUM_QUADS,
+            max_sprites: DEFAULT_NUM_SPRITES,
+            features: Features::empty(),
+            depth_buffer: true,
+            samples: 1,
+        }
+    }
+}
+
+impl Settings {
+    /// The present modes to try for `vsync`. Without it, frames are shown
+    /// as soon as they're done, tearing if the surface can't avoid that.
+    pub fn vsync_present_modes(vsync: bool) -> Vec<PresentMode> {
+        if vsync {
+            vec![PresentMode::FIFO, PresentMode::RELAXED]
+        } else {
+            vec![PresentMode::MAILBOX, PresentMode::IMMEDIATE]
+        }
+    }
+}
+
+// tried when the surface supports none of the preferred present modes
+pub(crate) fn default_present_modes() -> Vec<PresentMode> {
+    vec![
+        PresentMode::MAILBOX,
+        PresentMode::FIFO,
+        PresentMode::RELAXED,
+        PresentMode::IMMEDIATE,
+    ]
+}
+
+/// What a context ended up with, given the settings it was built with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Choices {
+    pub adapter: AdapterInfo,
+    /// `None` when headless, since nothing is presented.
+    pub present_mode: Option<PresentMode>,
+    pub format: Format,
+    /// The number of swapchain images, or 1 when headless.
+    pub image_count: u32,
+    pub frames_in_flight: usize,
+    pub depth_format: Option<Format>,
+    pub samples: u8,
+    pub features: Features,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapter_preferences_match_type_or_name() {
+        let info = AdapterInfo {
+            name: "llvmpipe (LLVM 12.0.0, 256 bits)".to_string(),
+            vendor: 0x10005,
+            device: 0,
+            device_type: DeviceType::Cpu,
+        };
+
+        assert!(AdapterPreference::Any.matches(0, &info));
+        assert!(AdapterPreference::Software.matches(0, &info));
+        assert!(!AdapterPreference::Discrete.matches(0, &info));
+        assert!(AdapterPreference::Named("LLVMpipe".to_string()).matches(0, &info));
+        assert!(!AdapterPreference::Named("radeon".to_string()).matches(0, &info));
+        assert!(AdapterPreference::Index(2).matches(2, &info));
+        assert!(!AdapterPreference::Index(2).matches(0, &info));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fs, path::PathBuf};
+
+use crate::error::{Error, ShaderKind, SpirvError};
+
+// the first word of every SPIR-V module
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+// magic, version, generator, bound and schema
+const SPIRV_HEADER_WORDS: usize = 5;
+
+// the GLSL sources next to this file are what the shaders are written in,
+// the SPIR-V next to them is compiled from them with
+// `glslangValidator -V -o vert.spv vertex_shader.vert` (or `glslc`), so the
+// crate builds without a shader compiler. With the `compile-shaders`
+// feature, build.rs compiles the sources instead
+#[cfg(not(feature = "compile-shaders"))]
+static EMBEDDED_VERT: &[u8] = include_bytes!("vert.spv");
+#[cfg(not(feature = "compile-shaders"))]
+static EMBEDDED_FRAG: &[u8] = include_bytes!("frag.spv");
+#[cfg(feature = "compile-shaders")]
+static EMBEDDED_VERT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/vert.spv"));
+#[cfg(feature = "compile-shaders")]
+static EMBEDDED_FRAG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/frag.spv"));
+
+/// Where the pipeline gets its shaders from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ShaderSource {
+    /// The built-in shaders, compiled into the crate.
+    #[default]
+    Embedded,
+    /// SPIR-V files to use instead, which have to keep the inputs, push
+    /// constants and descriptor sets of the built-in ones.
+    Files { vertex: PathBuf, fragment: PathBuf },
+}
+
+impl ShaderSource {
+    /// Reads and validates the vertex and fragment shaders.
+    pub(crate) fn load(&self) -> Result<(Vec<u32>, Vec<u32>), Error> {
+        match self {
+            ShaderSource::Embedded => Ok((
+                spirv_words(EMBEDDED_VERT, ShaderKind::Vertex)?,
+                spirv_words(EMBEDDED_FRAG, ShaderKind::Fragment)?,
+            )),
+            ShaderSource::Files { vertex, fragment } => {
+                let vertex = fs::read(vertex).map_err(Error::IOError)?;
+                let fragment = fs::read(fragment).map_err(Error::IOError)?;
+                Ok((
+                    spirv_words(&vertex, ShaderKind::Vertex)?,
+                    spirv_words(&fragment, ShaderKind::Fragment)?,
+                ))
+            }
+        }
+    }
+}
+
+// splits `bytes` into words after checking it at least looks like SPIR-V,
+// swapping the bytes of every word if it was written with the other endianness
+fn spirv_words(bytes: &[u8], kind: ShaderKind) -> Result<Vec<u32>, Error> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(Error::InvalidSpirv(
+            kind,
+            SpirvError::Unaligned(bytes.len()),
+        ));
+    }
+    if bytes.len() < SPIRV_HEADER_WORDS * 4 {
+        return Err(Error::InvalidSpirv(kind, SpirvError::MissingHeader));
+    }
+
+    let words = bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect::<Vec<_>>();
+
+    match words[0] {
+        SPIRV_MAGIC => Ok(words),
+        magic if magic.swap_bytes() == SPIRV_MAGIC => {
+            Ok(words.into_iter().map(u32::swap_bytes).collect())
+        }
+        magic => Err(Error::InvalidSpirv(kind, SpirvError::BadMagic(magic))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Vec<u8> {
+        [SPIRV_MAGIC, 0x0001_0000, 0, 1, 0]
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn spirv_error(bytes: &[u8]) -> Option<SpirvError> {
+        match spirv_words(bytes, ShaderKind::Vertex) {
+            Err(Error::InvalidSpirv(ShaderKind::Vertex, e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn embedded_shaders_are_valid() {
+        ShaderSource::Embedded.load().unwrap();
+    }
+
+    #[test]
+    fn reads_either_endianness() {
+        let words = spirv_words(&header(), ShaderKind::Vertex).unwrap();
+        let swapped = header()
+            .chunks_exact(4)
+            .flat_map(|word| word.iter().rev().copied().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(words[0], SPIRV_MAGIC);
+        assert_eq!(spirv_words(&swapped, ShaderKind::Vertex).unwrap(), words);
+    }
+
+    #[test]
+    fn rejects_invalid_spirv() {
+        let mut unaligned = header();
+        unaligned.push(0);
+        assert_eq!(spirv_error(&unaligned), Some(SpirvError::Unaligned(21)));
+        assert_eq!(spirv_error(&header()[..8]), Some(SpirvError::MissingHeader));
+
+        let mut glsl = b"#version 450\n\nvoid main() {}\n\n\n\n".to_vec();
+        glsl.truncate(20);
+        assert_eq!(
+            spirv_error(&glsl),
+            Some(SpirvError::BadMagic(u32::from_le_bytes(*b"#ver")))
+        );
+    }
+}
@@ -3,11 +3,13 @@ use core::mem::ManuallyDrop;
 use gfx_hal::{
     device::Device as DeviceTrait,
     image::Extent,
-    window::{Swapchain, SwapchainConfig},
+    window::{AcquireError, PresentError, Suboptimal, Swapchain, SwapchainConfig},
     Backend,
 };
 
-use crate::error::Error;
+use super::attachment::AttachmentImage;
+use super::frames::FrameTracker;
+use crate::error::{Error, SwapchainError};
 
 use std::rc::Rc;
 
@@ -17,12 +19,20 @@ pub struct SwapchainData<B: Backend> {
     pub swapchain: B::Swapchain,
     pub backbuffer: Vec<B::Image>,
     pub config: SwapchainConfig,
+    // one of each per frame in flight
     pub fences: Option<Vec<B::Fence>>,
     pub available_semaphores: Option<Vec<B::Semaphore>>,
     pub finished_semaphores: Option<Vec<B::Semaphore>>,
-    pub current_frame: usize,
+    pub frames: FrameTracker,
     pub image_views: Option<Vec<B::ImageView>>,
     pub framebuffers: Vec<B::Framebuffer>,
+    // one per image when the render pass has a depth attachment
+    pub depth_images: Vec<AttachmentImage<B>>,
+    // one per image when multisampling, drawn to and resolved into the image
+    pub color_images: Vec<AttachmentImage<B>>,
+    // set when the swapchain no longer matches the surface, either because
+    // acquiring or presenting told us so or because the window was resized
+    pub needs_recreation: bool,
 }
 
 impl<B: Backend> SwapchainData<B> {
@@ -46,33 +56,102 @@ impl<B: Backend> SwapchainData<B> {
             fences,
             available_semaphores,
             finished_semaphores,
-            current_frame: 0,
+            frames: FrameTracker::new(1, 0),
             image_views,
             framebuffers,
+            depth_images: vec![],
+            color_images: vec![],
+            needs_recreation: false,
         }
     }
 
-    pub unsafe fn get_current_image(&mut self) -> Result<(u32, usize), Error> {
-        let (image_index, _check_if_you_need_this) = self
-            .swapchain
-            .acquire_image(
-                u64::max_value(),
-                Some(
-                    &self
-                        .available_semaphores
-                        .as_ref()
-                        .ok_or(Error::SemaphoreError)?[self.current_frame],
-                ),
-                Some(
-                    &self
-                        .fences
-                        .as_ref()
-                        .ok_or(Error::FenceError(crate::error::FenceOp::Acquire))?
-                        [self.current_frame],
-                ),
-            )
-            .map_err(|e| Error::SwapchainError(crate::error::SwapchainError::ImageAcquireError))?;
-        Ok((image_index, image_index as usize))
+    /// Destroys the fences, semaphores, image views, attachment images and
+    /// framebuffers belonging to this swapchain, returning the swapchain
+    /// itself so it can either be destroyed or passed on as the old
+    /// swapchain when recreating it.
+    pub unsafe fn destroy_resources(self) -> B::Swapchain {
+        let SwapchainData {
+            device,
+            swapchain,
+            fences,
+            available_semaphores,
+            finished_semaphores,
+            image_views,
+            framebuffers,
+            depth_images,
+            color_images,
+            ..
+        } = self;
+
+        for fence in fences.unwrap_or_else(Vec::new) {
+            device.destroy_fence(fence);
+        }
+        for semaphore in available_semaphores
+            .unwrap_or_else(Vec::new)
+            .into_iter()
+            .chain(finished_semaphores.unwrap_or_else(Vec::new))
+        {
+            device.destroy_semaphore(semaphore);
+        }
+        // the framebuffers reference the image views, so they go first
+        for framebuffer in framebuffers {
+            device.destroy_framebuffer(framebuffer);
+        }
+        for image_view in image_views.unwrap_or_else(Vec::new) {
+            device.destroy_image_view(image_view);
+        }
+        drop(depth_images);
+        drop(color_images);
+
+        swapchain
+    }
+
+    /// Acquires the next image to render to, signalling the current frame's
+    /// available semaphore once it's ready.
+    pub unsafe fn acquire_image(&mut self) -> Result<u32, Error> {
+        let acquired = self.swapchain.acquire_image(
+            u64::MAX,
+            Some(
+                &self
+                    .available_semaphores
+                    .as_ref()
+                    .ok_or(Error::SemaphoreError)?[self.frames.current()],
+            ),
+            None,
+        );
+
+        match acquired {
+            Ok((image_index, suboptimal)) => {
+                // a suboptimal image can still be presented, so we only
+                // recreate the swapchain before the next frame
+                self.needs_recreation |= suboptimal.is_some();
+                Ok(image_index)
+            }
+            Err(AcquireError::OutOfDate) => {
+                self.needs_recreation = true;
+                Err(Error::SwapchainError(SwapchainError::OutOfDate))
+            }
+            Err(_) => Err(Error::SwapchainError(SwapchainError::ImageAcquireError)),
+        }
+    }
+
+    /// Checks the result of presenting an image, an out of date swapchain
+    /// isn't an error since it'll be recreated before the next frame anyway
+    pub fn check_present(
+        &mut self,
+        presented: Result<Option<Suboptimal>, PresentError>,
+    ) -> Result<(), Error> {
+        match presented {
+            Ok(suboptimal) => {
+                self.needs_recreation |= suboptimal.is_some();
+                Ok(())
+            }
+            Err(PresentError::OutOfDate) => {
+                self.needs_recreation = true;
+                Ok(())
+            }
+            Err(_) => Err(Error::SubmissionError),
+        }
     }
 
     pub unsafe fn create_framebuffers(
@@ -87,27 +166,30 @@ impl<B: Backend> SwapchainData<B> {
                 crate::error::SwapchainError::NoImageViews,
             ))?
             .iter()
-            .map(|image_view| {
+            .enumerate()
+            .map(|(i, image_view)| {
+                let depth_view = self.depth_images.get(i).map(|depth| &*depth.view);
+                // when multisampling, the image is only resolved into
+                let color_image = self.color_images.get(i);
+                let color_view = color_image.map_or(image_view, |color| &*color.view);
+                let resolve_view = color_image.map(|_| image_view);
+                let attachments = Some(color_view)
+                    .into_iter()
+                    .chain(depth_view)
+                    .chain(resolve_view);
                 device
                     .create_framebuffer(
                         render_pass,
-                        vec![image_view],
+                        attachments,
                         Extent {
-                            width: self.config.extent.width as u32,
-                            height: self.config.extent.height as u32,
+                            width: self.config.extent.width,
+                            height: self.config.extent.height,
                             depth: 1,
                         },
                     )
-                    .map_err(|e| Error::FramebufferCreationError)
+                    .map_err(|_| Error::FramebufferCreationError)
             })
             .collect::<Result<Vec<_>, Error>>()?;
         Ok(())
     }
-
-    pub fn advance_frame(&mut self) {
-        self.current_frame = match &self.available_semaphores {
-            Some(semaphores) => (self.current_frame + 1) % semaphores.len(),
-            None => 0,
-        };
-    }
 }
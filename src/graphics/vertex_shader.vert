@@ -1,13 +1,55 @@
 #version 450
 
-layout (location = 0) in vec3 vert_pos;
-layout (location = 0) out gl_PerVertex {
+layout (location = 0) in vec3 corner0;
+layout (location = 1) in vec3 corner1;
+layout (location = 2) in vec3 corner2;
+layout (location = 3) in vec3 corner3;
+layout (location = 4) in uint node_index;
+layout (location = 5) in uint tex_index;
+layout (location = 6) in vec2 uv0;
+layout (location = 7) in vec2 uv1;
+layout (location = 8) in vec2 uv2;
+layout (location = 9) in vec2 uv3;
+layout (location = 10) in vec4 color0;
+layout (location = 11) in vec4 color1;
+layout (location = 12) in vec4 color2;
+layout (location = 13) in vec4 color3;
+
+layout (push_constant) uniform Camera {
+  mat4 view_projection;
+};
+
+layout (set = 0, binding = 0) readonly buffer Matrices {
+  mat4 matrices[];
+};
+
+// which part of which texture a tex_index refers to
+struct Region {
+  vec2 uv_min;
+  vec2 uv_max;
+  uint texture;
+};
+
+layout (set = 1, binding = 1) readonly buffer Regions {
+  Region regions[];
+};
+
+layout (location = 0) out vec2 uv;
+layout (location = 1) flat out uint texture_index;
+layout (location = 2) out vec4 color;
+
+out gl_PerVertex {
   vec4 gl_Position;
 };
-layout (location = 1) in uint tex_index;
-layout (location = 1) flat out uint tex_index_out;
 
 void main() {
-  gl_Position = vec4(vert_pos, 0);
-  tex_index_out = tex_index;
+  vec3 corners[4] = vec3[](corner0, corner1, corner2, corner3);
+  // the uvs of the quad are relative to the region
+  vec2 uvs[4] = vec2[](uv0, uv1, uv2, uv3);
+  vec4 colors[4] = vec4[](color0, color1, color2, color3);
+  Region region = regions[tex_index];
+  gl_Position = view_projection * matrices[node_index] * vec4(corners[gl_VertexIndex], 1.0);
+  uv = mix(region.uv_min, region.uv_max, uvs[gl_VertexIndex]);
+  texture_index = region.texture;
+  color = colors[gl_VertexIndex];
 }
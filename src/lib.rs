@@ -10,43 +10,328 @@ use gfx_backend_vulkan as back;
 use gfx_backend_vulkan as back;
 
 pub mod error;
+pub mod frame;
 pub mod geometry;
 pub mod graphics;
 pub mod scene;
 pub mod tracker;
 
-use graphics::Context;
+#[cfg(test)]
+mod golden;
+
+use frame::Frame;
+use graphics::{
+    AdapterDetails, AdapterPreference, Choices, Context, Features, Format, PresentMode, Settings,
+};
 use raw_window_handle::HasRawWindowHandle;
 use scene::{SceneNode, SceneTree};
 
 pub struct Renderer {
     context: Context<back::Backend>,
     scenetree: SceneTree,
+    camera: geometry::Camera,
 }
 
 impl Renderer {
     pub fn new<W: HasRawWindowHandle>(window: &W, name: &str) -> Result<Renderer, error::Error> {
-        Ok(Renderer {
-            context: Context::build(window, name)?,
+        RendererBuilder::new().build(window, name)
+    }
+
+    /// Builds a renderer without a window, rendering into an offscreen image
+    /// of the given size instead, e.g. for tests or thumbnails.
+    pub fn headless(width: u32, height: u32) -> Result<Renderer, error::Error> {
+        RendererBuilder::new().build_headless(width, height)
+    }
+
+    /// Lists the adapters a headless renderer could use, for picking one
+    /// with `AdapterPreference::Index`.
+    pub fn adapters() -> Result<Vec<AdapterDetails>, error::Error> {
+        Context::<back::Backend>::available_adapters()
+    }
+
+    /// Lists the adapters that could render to `window`.
+    pub fn adapters_for_window<W: HasRawWindowHandle>(
+        window: &W,
+        name: &str,
+    ) -> Result<Vec<AdapterDetails>, error::Error> {
+        Context::<back::Backend>::available_adapters_for(window, name)
+    }
+
+    /// Starts configuring a renderer, for anything `new` and `headless`
+    /// don't let you choose.
+    pub fn builder() -> RendererBuilder {
+        RendererBuilder::new()
+    }
+
+    fn from_context(context: Context<back::Backend>) -> Renderer {
+        Renderer {
+            context,
             scenetree: SceneTree::new(SceneNode::new(geometry::Mat4::identity())),
-        })
+            camera: geometry::Camera::default(),
+        }
+    }
+
+    /// The adapter, present mode, format and so on the renderer ended up
+    /// with, which may differ from what its builder preferred.
+    pub fn choices(&self) -> Choices {
+        self.context.choices()
+    }
+
+    /// Copies the last frame of a headless renderer back as sRGB RGBA8
+    /// pixels, row by row starting at the top.
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, error::Error> {
+        self.context.read_pixels()
+    }
+
+    /// Starts recording a frame cleared to `clear_color`, into which any
+    /// number of things can be drawn before presenting it with
+    /// `Frame::end_frame`.
+    pub fn begin_frame(&mut self, clear_color: [f32; 4]) -> Frame<'_> {
+        Frame::new(self, clear_color)
+    }
+
+    /// Clears the screen to `clear_color` and draws a single quad through
+    /// the camera, with its colors multiplied by `color`.
+    pub fn draw_quad(
+        &mut self,
+        quad: geometry::Quad,
+        color: [f32; 4],
+        clear_color: [f32; 4],
+    ) -> Result<(), error::Error> {
+        let mut frame = self.begin_frame(clear_color);
+        frame.draw_quad(quad, color);
+        frame.end_frame()
+    }
+
+    /// Should be called whenever the window is resized, the swapchain is
+    /// recreated with the new size before the next frame.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.context.resize(width, height)
+    }
+
+    /// Uploads `width` by `height` sRGB RGBA8 pixels, given row by row, as
+    /// a texture which quads can then refer to through their `texture` field.
+    pub fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        filter: graphics::Filter,
+    ) -> Result<graphics::TextureHandle, error::Error> {
+        self.context.add_texture(width, height, pixels, filter)
     }
 
-    pub fn draw_quad(&mut self, quad: geometry::Quad, color: [f32; 4]) -> Result<(), error::Error> {
-        self.context.draw_quad(quad, color)
+    /// Uploads every page of `atlas` as a texture, returning a handle for
+    /// every image added to it, in the order they were added.
+    pub fn add_atlas(
+        &mut self,
+        atlas: &graphics::AtlasBuilder,
+        filter: graphics::Filter,
+    ) -> Result<Vec<graphics::TextureHandle>, error::Error> {
+        self.context.add_atlas(atlas, filter)
+    }
+
+    /// Builds a custom pipeline, which scene nodes select by setting their
+    /// `material` to the returned handle.
+    pub fn add_material(
+        &mut self,
+        desc: graphics::PipelineDesc,
+    ) -> Result<graphics::MaterialHandle, error::Error> {
+        self.context.add_material(desc)
+    }
+
+    /// Sets the words pushed into the push constant ranges of a material
+    /// every frame, see `PipelineDesc::push_constants`.
+    pub fn set_material_constants(
+        &mut self,
+        material: graphics::MaterialHandle,
+        constants: &[u32],
+    ) -> Result<(), error::Error> {
+        self.context.set_material_constants(material, constants)
+    }
+
+    /// Swaps the built-in shaders for others, e.g. SPIR-V files loaded with
+    /// `ShaderSource::Files`. On failure the current shaders are kept.
+    pub fn set_shaders(&mut self, shaders: &graphics::ShaderSource) -> Result<(), error::Error> {
+        self.context.set_shaders(shaders)
     }
 
     pub fn clear(&mut self, color: [f32; 4]) -> Result<(), error::Error> {
-        self.context.clear(color)
+        self.begin_frame(color).end_frame()
+    }
+
+    /// Draws every quad in the scene tree, transformed by the cache of the
+    /// node it belongs to and then seen through the camera.
+    pub fn draw_scene(&mut self, clear_color: [f32; 4]) -> Result<(), error::Error> {
+        let mut frame = self.begin_frame(clear_color);
+        frame.draw_scene();
+        frame.end_frame()
+    }
+
+    /// How much geometry data the last call to `draw_scene` had to upload.
+    pub fn last_upload(&self) -> graphics::UploadStats {
+        self.context.last_upload()
+    }
+
+    /// How many nodes and quads of the scene tree the last call to
+    /// `draw_scene` skipped for being out of the camera's view.
+    pub fn last_cull(&self) -> scene::CullStats {
+        self.context.last_cull()
+    }
+
+    /// The camera, which starts out orthographic, showing the scene in
+    /// pixel coordinates.
+    pub fn camera(&self) -> &geometry::Camera {
+        &self.camera
+    }
+
+    pub fn camera_mut(&mut self) -> &mut geometry::Camera {
+        &mut self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: geometry::Camera) -> geometry::Camera {
+        std::mem::replace(&mut self.camera, camera)
     }
 
-    pub fn submit(&mut self) {}
+    pub fn scene(&self) -> &SceneTree {
+        &self.scenetree
+    }
+
+    pub fn scene_mut(&mut self) -> &mut SceneTree {
+        &mut self.scenetree
+    }
+
+    /// Replaces the scene tree, returning the old one
+    pub fn set_scene(&mut self, scene: SceneTree) -> SceneTree {
+        std::mem::replace(&mut self.scenetree, scene)
+    }
+}
+
+/// Configures a renderer before building it, starting from the same
+/// defaults `Renderer::new` uses. Most options are preferences the renderer
+/// falls back from, `Renderer::choices` tells what it went with.
+#[derive(Debug, Clone, Default)]
+pub struct RendererBuilder {
+    settings: Settings,
+}
+
+impl RendererBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn adapter(mut self, preference: AdapterPreference) -> Self {
+        self.settings.adapter = preference;
+        self
+    }
+
+    /// Whether to fall back to other adapters if none of the preferred ones
+    /// work, which it does by default. Without that, building fails instead.
+    pub fn adapter_fallback(mut self, fallback: bool) -> Self {
+        self.settings.adapter_fallback = fallback;
+        self
+    }
+
+    /// Waits for the display to show each frame if `vsync` is set, otherwise
+    /// shows them as soon as they're done.
+    pub fn vsync(mut self, vsync: bool) -> Self {
+        self.settings.present_modes = Settings::vsync_present_modes(vsync);
+        self
+    }
+
+    /// The present modes to try, in order, replacing what `vsync` chose.
+    pub fn present_modes(mut self, present_modes: Vec<PresentMode>) -> Self {
+        self.settings.present_modes = present_modes;
+        self
+    }
+
+    /// How many frames may be recorded while the GPU is still busy with
+    /// earlier ones, at least 1.
+    pub fn frames_in_flight(mut self, frames: usize) -> Self {
+        self.settings.frames_in_flight = frames;
+        self
+    }
+
+    /// The surface formats to try, in order, before the first sRGB one.
+    pub fn formats(mut self, formats: Vec<Format>) -> Self {
+        self.settings.formats = formats;
+        self
+    }
+
+    /// How many node matrices and quads to make room for up front, to avoid
+    /// growing the geometry buffer in the middle of drawing. At least 1 of
+    /// each.
+    pub fn capacity(mut self, max_matrices: u64, max_quads: u64) -> Self {
+        self.settings.max_matrices = max_matrices;
+        self.settings.max_quads = max_quads;
+        self
+    }
+
+    /// How many sprites to make room for up front, across all frames in
+    /// flight, at least 1.
+    pub fn sprite_capacity(mut self, max_sprites: u32) -> Self {
+        self.settings.max_sprites = max_sprites;
+        self
+    }
+
+    /// Device features to enable, adapters lacking them aren't considered.
+    pub fn features(mut self, features: Features) -> Self {
+        self.settings.features = features;
+        self
+    }
+
+    pub fn depth_buffer(mut self, depth_buffer: bool) -> Self {
+        self.settings.depth_buffer = depth_buffer;
+        self
+    }
+
+    /// Samples per pixel, fails to build if the adapter doesn't support it.
+    pub fn samples(mut self, samples: u8) -> Self {
+        self.settings.samples = samples;
+        self
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn build<W: HasRawWindowHandle>(
+        &self,
+        window: &W,
+        name: &str,
+    ) -> Result<Renderer, error::Error> {
+        Ok(Renderer::from_context(Context::build(
+            window,
+            name,
+            &self.settings,
+        )?))
+    }
+
+    pub fn build_headless(&self, width: u32, height: u32) -> Result<Renderer, error::Error> {
+        Ok(Renderer::from_context(Context::headless(
+            width,
+            height,
+            &self.settings,
+        )?))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn headless_targets_need_pixels() {
+        for &(width, height) in &[(0, 0), (0, 64), (64, 0)] {
+            assert!(matches!(
+                Renderer::headless(width, height),
+                Err(error::Error::EmptyExtent(w, h)) if (w, h) == (width, height)
+            ));
+        }
+    }
 }
@@ -1,53 +1,364 @@
-use crate::geometry::{Mat4, Quad};
+use core::cell::Cell;
+use core::cmp::Ordering;
+
+use std::collections::HashMap;
+use std::sync::atomic::{self, AtomicU64};
+
+use nalgebra::Point3;
+
+use crate::error::{Error, SceneError};
+use crate::geometry::{Aabb, Camera, Frustum, Mat4, Quad, Rotation, Transform3D, Vec3};
+use crate::graphics::material::{DrawBatch, QuadSource};
+use crate::graphics::{MaterialHandle, TextureHandle};
 use crate::tracker::Tracked;
 
+/// A handle to a `SceneNode`, which stays valid for as long as the node
+/// lives, wherever it's moved in its tree. Once the node is dropped, the
+/// handle never refers to another node, even if its slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    serial: u64,
+}
+
+/// A handle to a quad of a `SceneNode`, which stays valid until the quad is
+/// removed, however many quads are added or removed around it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuadId {
+    node: NodeId,
+    index: u32,
+    generation: u32,
+}
+
+impl QuadId {
+    /// The node the quad belongs to.
+    pub fn node(self) -> NodeId {
+        self.node
+    }
+}
+
+// generational slots, each holding a `T` while it's in use. A slot's
+// generation is bumped when it's freed, which invalidates its old handles
 #[derive(Debug)]
-pub struct SceneTree {
-    root: Tracked<SceneNode>,
+struct Slots<T> {
+    entries: Vec<(u32, Option<T>)>,
+    free: Vec<u32>,
 }
 
-fn compute_cache(
-    parent: &Tracked<Mat4>,
-    mat: &Tracked<Mat4>,
-    cache: &mut Tracked<Mat4>,
-    children: &mut [Tracked<SceneNode>],
-) {
-    if parent.is_unmodified() {
-        println!("parent unmodified");
-        for child in children.iter_mut() {
-            let new_parent = &*cache;
-            if child.is_modified() {
-                let &mut SceneNode {
-                    ref transform,
-                    ref mut cache,
-                    children: ref mut new_children,
-                    ..
-                } = &mut **child;
-                compute_cache(new_parent, transform, cache, new_children)
-            }
+impl<T> Slots<T> {
+    const fn new() -> Self {
+        Slots {
+            entries: Vec::new(),
+            free: Vec::new(),
         }
-    } else {
-        println!("parent modified");
-        parent.mul_to(mat, &mut **cache);
+    }
+
+    fn insert(&mut self, value: T) -> (u32, u32) {
+        if let Some(index) = self.free.pop() {
+            let entry = &mut self.entries[index as usize];
+            entry.1 = Some(value);
+            (index, entry.0)
+        } else {
+            self.entries.push((0, Some(value)));
+            (self.entries.len() as u32 - 1, 0)
+        }
+    }
 
-        let new_parent = &*cache;
-        for child in children.iter_mut() {
-            let &mut SceneNode {
-                ref transform,
-                ref mut cache,
-                children: ref mut new_children,
-                ..
-            } = &mut **child;
-            compute_cache(new_parent, transform, cache, new_children);
+    fn get(&self, index: u32, generation: u32) -> Option<&T> {
+        match self.entries.get(index as usize) {
+            Some((current, value)) if *current == generation => value.as_ref(),
+            _ => None,
         }
     }
+
+    fn remove(&mut self, index: u32, generation: u32) -> Option<T> {
+        let entry = self.entries.get_mut(index as usize)?;
+        if entry.0 != generation || entry.1.is_none() {
+            return None;
+        }
+        entry.0 = entry.0.wrapping_add(1);
+        self.free.push(index);
+        entry.1.take()
+    }
+}
+
+// nodes are built on their own before they're added to a tree, so their
+// ids are handed out for the whole process rather than per tree. They're
+// never reused, so the handle of a dropped node can't find another one
+static NEXT_NODE_ID: AtomicU64 = AtomicU64::new(0);
+
+// where a node is in its tree: its parent, and its index among the parent's
+// children. The way to a node from the root is found by following the
+// parents up, so looking it up takes as long as it is deep
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    parent: Option<NodeId>,
+    index: u32,
+}
+
+/// Something in a scene tree, together with the depth-first index of the
+/// node it belongs to.
+pub(crate) type DfIndexed<'a, T> = (&'a Tracked<usize>, &'a Tracked<T>);
+
+/// How much of a scene tree the last call to `SceneTree::cull` found to be
+/// out of view. Nodes without any quads in their subtree aren't counted.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CullStats {
+    pub nodes_culled: usize,
+    pub quads_culled: usize,
+}
+
+#[derive(Debug)]
+pub struct SceneTree {
+    root: Tracked<SceneNode>,
+    // where every node is, kept up to date by the edits made through the
+    // tree. It can also be changed through `root_mut` and `get_mut` without
+    // us knowing, so the links are only trusted once they lead to the node,
+    // and `links_stale` is set until they're checked again
+    links: HashMap<NodeId, Link>,
+    links_stale: bool,
+}
+
+// `touched` is whether `node` was accessed mutably since modifications were
+// last unset, in which case its quads or children might have changed
+fn compute_cache(parent: &Tracked<Mat4>, node: &mut SceneNode, touched: bool) {
+    // if neither the parent's cache nor our own transform changed, our cache
+    // is still valid, and only children that were modified need to be visited
+    let recompute = parent.is_modified() || node.transform.is_modified();
+    if recompute {
+        parent.mul_to(&node.transform, &mut *node.cache);
+    }
+    if touched && (node.quad_count_changed || node.quads.iter().any(|quad| quad.is_modified())) {
+        node.local_bounds = Aabb::from_points(
+            node.quads
+                .iter()
+                .flat_map(|quad| quad.points.iter().copied()),
+        );
+    }
+
+    let new_parent = &node.cache;
+    for child in node
+        .children
+        .iter_mut()
+        .filter(|child| recompute || child.is_modified())
+    {
+        let touched = child.is_modified();
+        compute_cache(new_parent, child, touched);
+    }
+
+    // nothing in an untouched subtree moves unless the cache does
+    if recompute || touched {
+        node.world_bounds = node.children.iter().fold(
+            node.local_bounds.transformed(&node.cache),
+            |bounds, child| bounds.union(&child.world_bounds),
+        );
+    }
 }
 
 impl SceneTree {
     pub fn new(mut node: SceneNode) -> SceneTree {
         node.df_index = Tracked::new(0);
-        let mut root = Tracked::new(node);
-        SceneTree { root }
+        let root = Tracked::new(node);
+        let mut links = HashMap::new();
+        link_subtree(&root, None, 0, &mut links);
+        SceneTree {
+            root,
+            links,
+            links_stale: false,
+        }
+    }
+
+    /// Looks up a node anywhere in the tree, returning `None` if it has
+    /// been dropped or isn't part of this tree.
+    pub fn get(&self, id: NodeId) -> Option<&Tracked<SceneNode>> {
+        if let Some(path) = self.linked_path(id) {
+            return self.follow(&path);
+        }
+        if !self.links_stale {
+            return None;
+        }
+        // the node may have been added directly since the links were checked
+        find_path(&self.root, id, &mut vec![]).and_then(|path| self.follow(&path))
+    }
+
+    /// Looks up a node like `get`. Every node on the way to it is marked as
+    /// modified, so changes to its transform are picked up by
+    /// `recompute_caches`.
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut Tracked<SceneNode>> {
+        let path = self.path_to(id)?;
+        // children can be added or removed through it
+        self.links_stale = true;
+        Some(node_at_mut(&mut self.root, &path))
+    }
+
+    /// Takes a node and its subtree out of the tree. Their ids stay valid,
+    /// so it can be added back anywhere, in this tree or another one.
+    pub fn detach(&mut self, id: NodeId) -> Result<SceneNode, Error> {
+        let mut path = self
+            .path_to(id)
+            .ok_or(Error::InvalidSceneEdit(SceneError::MissingNode))?;
+        let index = path
+            .pop()
+            .ok_or(Error::InvalidSceneEdit(SceneError::RootNode))?;
+
+        let parent = node_at_mut(&mut self.root, &path);
+        parent.child_count_changed = true;
+        let node = parent.children.remove(index as usize).into_inner();
+        unlink_subtree(&node, &mut self.links);
+        relink_children(parent, index as usize, &mut self.links);
+        Ok(node)
+    }
+
+    /// Removes a node and drops its subtree, after which none of their ids
+    /// are found anymore.
+    pub fn remove_child(&mut self, id: NodeId) -> Result<(), Error> {
+        self.detach(id).map(drop)
+    }
+
+    /// Inserts `node` as the child of `parent` at `index`, shifting the
+    /// children from there on back.
+    pub fn insert_child_at(
+        &mut self,
+        parent: NodeId,
+        index: usize,
+        node: SceneNode,
+    ) -> Result<NodeId, Error> {
+        let path = self
+            .path_to(parent)
+            .ok_or(Error::InvalidSceneEdit(SceneError::MissingNode))?;
+        let parent = node_at_mut(&mut self.root, &path);
+        if index > parent.children.len() {
+            return Err(Error::InvalidSceneEdit(SceneError::ChildIndex(index)));
+        }
+        let id = parent.insert_child(index, node);
+        link_subtree(
+            &parent.children[index],
+            Some(parent.id),
+            index as u32,
+            &mut self.links,
+        );
+        relink_children(parent, index + 1, &mut self.links);
+        Ok(id)
+    }
+
+    /// Moves a node and its subtree to the end of `new_parent`'s children.
+    /// Its transform stays relative to its parent, so it follows the new one
+    /// from now on.
+    pub fn reparent(&mut self, id: NodeId, new_parent: NodeId) -> Result<(), Error> {
+        let path = self
+            .path_to(id)
+            .ok_or(Error::InvalidSceneEdit(SceneError::MissingNode))?;
+        let parent_path = self
+            .path_to(new_parent)
+            .ok_or(Error::InvalidSceneEdit(SceneError::MissingNode))?;
+        if parent_path.starts_with(&path) {
+            return Err(Error::InvalidSceneEdit(if path.is_empty() {
+                SceneError::RootNode
+            } else {
+                SceneError::IntoOwnSubtree
+            }));
+        }
+
+        // detaching can move the new parent, so it's looked up again
+        let node = self.detach(id)?;
+        let end = self
+            .get(new_parent)
+            .ok_or(Error::InvalidSceneEdit(SceneError::MissingNode))?
+            .children
+            .len();
+        self.insert_child_at(new_parent, end, node).map(drop)
+    }
+
+    /// Moves a node to `index` among its siblings, shifting the ones in
+    /// between to make room.
+    pub fn set_sibling_index(&mut self, id: NodeId, index: usize) -> Result<(), Error> {
+        let mut path = self
+            .path_to(id)
+            .ok_or(Error::InvalidSceneEdit(SceneError::MissingNode))?;
+        let current = path
+            .pop()
+            .ok_or(Error::InvalidSceneEdit(SceneError::RootNode))?;
+
+        let parent = node_at_mut(&mut self.root, &path);
+        if index >= parent.children.len() {
+            return Err(Error::InvalidSceneEdit(SceneError::ChildIndex(index)));
+        }
+        if index != current as usize {
+            // the quads move around in the GPU buffers just like when adding
+            // a child, so this counts as a change in structure too
+            parent.child_count_changed = true;
+            let node = parent.children.remove(current as usize);
+            parent.children.insert(index, node);
+            relink_children(parent, index.min(current as usize), &mut self.links);
+        }
+        Ok(())
+    }
+
+    /// Looks up a quad anywhere in the tree, returning `None` if it has been
+    /// removed or isn't part of this tree.
+    pub fn get_quad(&self, id: QuadId) -> Option<&Tracked<Quad>> {
+        self.get(id.node)?.quad(id)
+    }
+
+    /// Looks up a quad like `get_quad`, marking it and every node on the way
+    /// to it as modified.
+    pub fn get_quad_mut(&mut self, id: QuadId) -> Option<&mut Tracked<Quad>> {
+        let path = self.path_to(id.node)?;
+        node_at_mut(&mut self.root, &path).quad_mut(id)
+    }
+
+    // the child indices leading to `id` from the root, linking every node
+    // again if the tree was changed directly and the links don't lead to it
+    fn path_to(&mut self, id: NodeId) -> Option<Vec<u32>> {
+        if let Some(path) = self.linked_path(id) {
+            return Some(path);
+        }
+        if !self.links_stale {
+            return None;
+        }
+        self.relink();
+        self.linked_path(id)
+    }
+
+    // the child indices the links lead to `id` along, if they still do
+    fn linked_path(&self, id: NodeId) -> Option<Vec<u32>> {
+        let mut path = Vec::new();
+        let mut link = self.links.get(&id)?;
+        while let Some(parent) = link.parent {
+            // stale links can't go on forever, but they may well go in circles
+            if path.len() > self.links.len() {
+                return None;
+            }
+            path.push(link.index);
+            link = self.links.get(&parent)?;
+        }
+        path.reverse();
+        match self.follow(&path) {
+            Some(node) if node.id == id => Some(path),
+            _ => None,
+        }
+    }
+
+    fn relink(&mut self) {
+        self.links.clear();
+        link_subtree(&self.root, None, 0, &mut self.links);
+        self.links_stale = false;
+    }
+
+    // brings the links up to date if children were added or removed
+    // directly. Such changes are only forgotten by `unset_modifications`,
+    // so checking before then is enough
+    fn check_links(&mut self) {
+        if self.links_stale {
+            if self.structure_changed() {
+                self.relink();
+            }
+            self.links_stale = false;
+        }
+    }
+
+    fn follow(&self, path: &[u32]) -> Option<&Tracked<SceneNode>> {
+        path.iter()
+            .try_fold(&self.root, |node, &index| node.children.get(index as usize))
     }
 
     pub fn root(&self) -> &Tracked<SceneNode> {
@@ -55,49 +366,310 @@ impl SceneTree {
     }
 
     pub fn root_mut(&mut self) -> &mut Tracked<SceneNode> {
+        self.links_stale = true;
         &mut self.root
     }
 
+    /// Brings every node's cache and bounds up to date, and renumbers the
+    /// nodes in depth-first order if the structure of the tree changed.
     pub fn recompute_caches(&mut self) {
+        self.check_links();
+        if self.structure_changed() {
+            assign_df_indices(&mut self.root, &mut 0);
+        }
         if self.root.is_modified() {
-            println!("root modified, recomputing caches");
-
-            let &mut SceneNode {
-                ref transform,
-                ref mut cache,
-                children: ref mut new_children,
-                ..
-            } = &mut *self.root;
-
             compute_cache(
-                &Tracked::Modified(Mat4::identity()),
-                transform,
-                cache,
-                new_children,
+                &Tracked::new_unmodified(Mat4::identity()),
+                &mut self.root,
+                true,
             );
         }
     }
 
+    /// The world space bounds of every quad in the tree, as of the last
+    /// `recompute_caches`.
+    pub fn bounds(&self) -> Aabb {
+        self.root.world_bounds
+    }
+
+    /// Marks the subtrees whose world bounds are outside of `frustum` as
+    /// culled, or nothing if there's no frustum. Culled quads aren't drawn,
+    /// and changes to them aren't uploaded until they're back in view. The
+    /// bounds have to be up to date, see `recompute_caches`.
+    pub fn cull(&self, frustum: Option<&Frustum>) -> CullStats {
+        let mut stats = CullStats::default();
+        cull(&self.root, frustum, false, &mut stats);
+        stats
+    }
+
+    /// Whether every node and every quad was culled by the last call to
+    /// `cull`, both in depth-first order.
+    pub(crate) fn culled(&self) -> (Vec<bool>, Vec<bool>) {
+        let mut nodes = Vec::new();
+        let mut quads = Vec::new();
+        collect_culled(&self.root, &mut nodes, &mut quads);
+        (nodes, quads)
+    }
+
+    /// Collects every node's cache together with its depth-first index,
+    /// and every quad in the tree together with the depth-first index of
+    /// the node that owns it, both in depth-first order.
     pub(crate) fn get_cache_and_quad_array(
-        &mut self,
-    ) -> (Vec<&mut Tracked<Mat4>>, Vec<Tracked<Quad>>) {
-        unimplemented!()
+        &self,
+    ) -> (Vec<DfIndexed<'_, Mat4>>, Vec<DfIndexed<'_, Quad>>) {
+        let mut caches = Vec::new();
+        let mut quads = Vec::new();
+        collect_caches_and_quads(&self.root, &mut caches, &mut quads);
+        (caches, quads)
+    }
+
+    /// Groups the quads into runs drawn with the same material and
+    /// sampling the same texture, which `texture` looks up for each quad's
+    /// handle. Opaque quads come first, in the depth-first order they're
+    /// uploaded in, then the quads of the materials `transparent` returns
+    /// true for, sorted back to front as seen by `camera`. Quads at the
+    /// same depth keep their depth-first order.
+    pub(crate) fn draw_batches(
+        &self,
+        camera: &Camera,
+        transparent: impl Fn(MaterialHandle) -> bool,
+        texture: impl Fn(TextureHandle) -> u32,
+    ) -> Vec<DrawBatch> {
+        let mut opaque = Vec::new();
+        let mut sorted = Vec::new();
+        let mut index = 0;
+        collect_draw_batches(
+            &self.root,
+            camera,
+            &transparent,
+            &texture,
+            &mut index,
+            &mut opaque,
+            &mut sorted,
+        );
+
+        // `sort_by` is stable
+        sorted.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        for (_, batch) in sorted {
+            push_draw_batch(&mut opaque, batch);
+        }
+        opaque
+    }
+
+    /// Returns whether a child or quad was added to or removed from any
+    /// node since the last call to `unset_modifications`, which means every
+    /// quad after it has moved in the GPU buffers.
+    pub fn structure_changed(&self) -> bool {
+        structure_changed(&self.root)
     }
 
     pub fn unset_modifications(&mut self) {
-        unset_modification(&mut self.root)
+        self.check_links();
+        unset_modification(&mut self.root, false)
+    }
+
+    /// Unsets the modifications everywhere but in culled subtrees, whose
+    /// changes are left to be uploaded once they're back in view. There
+    /// mustn't be any changes in structure, since those are uploaded even
+    /// when culled.
+    pub(crate) fn unset_visible_modifications(&mut self) {
+        self.check_links();
+        unset_modification(&mut self.root, true)
     }
 }
 
-fn unset_modification(node: &mut Tracked<SceneNode>) {
+// a node is culled along with its parent, or if its subtree is out of view
+fn cull(node: &SceneNode, frustum: Option<&Frustum>, parent_culled: bool, stats: &mut CullStats) {
+    let culled = parent_culled
+        || match frustum {
+            Some(frustum) => !frustum.intersects(&node.world_bounds),
+            None => false,
+        };
+    node.culled.set(culled);
+    // there's nothing to skip drawing in an empty subtree
+    if culled && !node.world_bounds.is_empty() {
+        stats.nodes_culled += 1;
+        stats.quads_culled += node.quads.len();
+    }
+    for child in node.iter_children() {
+        cull(child, frustum, culled, stats);
+    }
+}
+
+fn collect_culled(node: &SceneNode, nodes: &mut Vec<bool>, quads: &mut Vec<bool>) {
+    nodes.push(node.culled.get());
+    quads.extend(node.quads.iter().map(|_| node.culled.get()));
+    for child in node.iter_children() {
+        collect_culled(child, nodes, quads);
+    }
+}
+
+fn find_path(node: &SceneNode, id: NodeId, path: &mut Vec<u32>) -> Option<Vec<u32>> {
+    if node.id == id {
+        return Some(path.clone());
+    }
+    for (index, child) in node.iter_children().enumerate() {
+        path.push(index as u32);
+        if let Some(found) = find_path(child, id, path) {
+            return Some(found);
+        }
+        path.pop();
+    }
+    None
+}
+
+// marks every node on the way as modified
+fn node_at_mut<'a>(root: &'a mut Tracked<SceneNode>, path: &[u32]) -> &'a mut Tracked<SceneNode> {
+    let mut node = root;
+    for &index in path {
+        node = &mut node.children[index as usize];
+    }
+    node
+}
+
+fn link_subtree(
+    node: &SceneNode,
+    parent: Option<NodeId>,
+    index: u32,
+    links: &mut HashMap<NodeId, Link>,
+) {
+    links.insert(node.id, Link { parent, index });
+    for (index, child) in node.iter_children().enumerate() {
+        link_subtree(child, Some(node.id), index as u32, links);
+    }
+}
+
+fn unlink_subtree(node: &SceneNode, links: &mut HashMap<NodeId, Link>) {
+    links.remove(&node.id);
+    for child in node.iter_children() {
+        unlink_subtree(child, links);
+    }
+}
+
+// updates the index of every child of `node` from `first` on
+fn relink_children(node: &SceneNode, first: usize, links: &mut HashMap<NodeId, Link>) {
+    for (index, child) in node.iter_children().enumerate().skip(first) {
+        links.insert(
+            child.id,
+            Link {
+                parent: Some(node.id),
+                index: index as u32,
+            },
+        );
+    }
+}
+
+fn structure_changed(node: &SceneNode) -> bool {
+    node.child_count_changed
+        || node.quad_count_changed
+        || node.iter_children().any(|child| structure_changed(child))
+}
+
+fn collect_caches_and_quads<'a>(
+    node: &'a SceneNode,
+    caches: &mut Vec<DfIndexed<'a, Mat4>>,
+    quads: &mut Vec<DfIndexed<'a, Quad>>,
+) {
+    caches.push((&node.df_index, &node.cache));
+    quads.extend(node.quads_df_index());
+    for child in node.iter_children() {
+        collect_caches_and_quads(child, caches, quads);
+    }
+}
+
+// extends the last batch with the quads of `batch` if they follow it and
+// use the same material and texture, otherwise starts a new one
+fn push_draw_batch(batches: &mut Vec<DrawBatch>, batch: DrawBatch) {
+    match batches.last_mut() {
+        Some(last)
+            if last.material == batch.material
+                && last.texture == batch.texture
+                && last.quads.end == batch.quads.start =>
+        {
+            last.quads.end = batch.quads.end
+        }
+        _ => batches.push(batch),
+    }
+}
+
+// batches the opaque quads right away, and collects the transparent ones
+// with the depth of their center to be sorted
+fn collect_draw_batches(
+    node: &SceneNode,
+    camera: &Camera,
+    transparent: &impl Fn(MaterialHandle) -> bool,
+    texture: &impl Fn(TextureHandle) -> u32,
+    index: &mut u32,
+    opaque: &mut Vec<DrawBatch>,
+    sorted: &mut Vec<(f32, DrawBatch)>,
+) {
+    if node.culled.get() {
+        // so are all of its descendants, whose quads still take up their slots
+        *index += count_quads(node);
+        return;
+    }
+
+    let start = *index;
+    *index += node.quads.len() as u32;
+
+    let batches = node.quads.iter().zip(start..).map(|(quad, index)| {
+        let batch = DrawBatch {
+            material: node.material,
+            texture: texture(quad.texture),
+            quads: index..index + 1,
+            source: QuadSource::Geometry,
+        };
+        (quad, batch)
+    });
+    if transparent(node.material) {
+        sorted.extend(batches.map(|(quad, batch)| {
+            let center = quad.points.iter().sum::<Vec3>() / 4.0;
+            let center = node.cache.transform_point(&Point3::from(center));
+            (camera.depth(&center.coords), batch)
+        }));
+    } else {
+        for (_, batch) in batches {
+            push_draw_batch(opaque, batch);
+        }
+    }
+
+    for child in node.iter_children() {
+        collect_draw_batches(child, camera, transparent, texture, index, opaque, sorted);
+    }
+}
+
+fn count_quads(node: &SceneNode) -> u32 {
+    node.quads.len() as u32
+        + node
+            .iter_children()
+            .map(|child| count_quads(child))
+            .sum::<u32>()
+}
+
+fn unset_modification(node: &mut Tracked<SceneNode>, keep_culled: bool) {
+    if keep_culled && node.culled.get() {
+        return;
+    }
+    {
+        let node = &mut **node;
+        node.df_index.reset();
+        node.cache.reset();
+        node.transform.reset();
+        node.child_count_changed = false;
+        node.quad_count_changed = false;
+        for quad in node.iter_quads_mut() {
+            quad.reset();
+        }
+    }
     for node in node.get_children_mut() {
-        unset_modification(node)
+        unset_modification(node, keep_culled)
     }
     node.reset();
 }
 
 #[derive(Debug)]
 pub struct SceneNode {
+    id: NodeId,
     df_index: Tracked<usize>,
     // we try to maintain a cache of this "matrix multiplication chain"
     // i.e. the product M_1 * M_2 * ... * M_N where M_1 is the root and
@@ -105,8 +677,20 @@ pub struct SceneNode {
     //  yet undecided, as it doesn't change implementation much, and both have pros/cons).
     // Obviously, if any parent changes, the cache will be invalidated.
     pub(crate) cache: Tracked<Mat4>,
-    // the rightmost transform that will be applied to all the quads in this SceneNode
+    // the bounds of this node's quads before any transform, and of all the
+    // quads in its subtree in world space, kept up to date along with `cache`
+    local_bounds: Aabb,
+    world_bounds: Aabb,
+    // set by `SceneTree::cull`, which only needs a shared reference so the
+    // node isn't marked modified
+    culled: Cell<bool>,
+    // the rightmost transform that will be applied to all the quads in this SceneNode.
+    // Setting it directly is fine, `local_transform` then splits it up again
     pub transform: Tracked<Mat4>,
+    // what `transform` was last built from by the setters
+    local: Transform3D,
+    // the pipeline the quads of this node (but not its children) are drawn with
+    pub material: MaterialHandle,
     // these two bools track whether elements were added or removed to/from either
     // of our two vectors. We track this separately since adding/removing anything
     // might trigger a reallocation of our gfx-hal buffers
@@ -114,27 +698,186 @@ pub struct SceneNode {
     quad_count_changed: bool,
     children: Vec<Tracked<SceneNode>>,
     quads: Vec<Tracked<Quad>>,
+    // the position in `quads` of each quad's slot, and the slot of each quad
+    quad_ids: Slots<u32>,
+    quad_slots: Vec<u32>,
 }
 
 impl SceneNode {
     pub fn new(trans: Mat4) -> Self {
         SceneNode {
+            id: NodeId {
+                serial: NEXT_NODE_ID.fetch_add(1, atomic::Ordering::Relaxed),
+            },
             df_index: Tracked::new(0),
             cache: Tracked::new(trans),
             transform: Tracked::new(trans),
+            local: Transform3D::from_matrix(&trans),
+            local_bounds: Aabb::empty(),
+            world_bounds: Aabb::empty(),
+            culled: Cell::new(false),
+            material: MaterialHandle::DEFAULT,
             child_count_changed: false,
             quad_count_changed: false,
             children: Vec::new(),
             quads: Vec::new(),
+            quad_ids: Slots::new(),
+            quad_slots: Vec::new(),
         }
     }
 
-    pub fn add_child(&mut self, mut node: SceneNode) {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The transform relative to the parent, split into its parts.
+    pub fn local_transform(&self) -> Transform3D {
+        // unless `transform` was set directly since
+        if self.local.to_matrix() == *self.transform {
+            self.local
+        } else {
+            Transform3D::from_matrix(&self.transform)
+        }
+    }
+
+    /// Sets the transform relative to the parent, which marks only this
+    /// node's transform modified. Takes a `Transform2D` as well.
+    pub fn set_local_transform(&mut self, transform: impl Into<Transform3D>) {
+        self.local = transform.into();
+        *self.transform = self.local.to_matrix();
+    }
+
+    pub fn set_translation(&mut self, translation: Vec3) {
+        let transform = self.local_transform();
+        self.set_local_transform(Transform3D {
+            translation,
+            ..transform
+        });
+    }
+
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        let transform = self.local_transform();
+        self.set_local_transform(Transform3D {
+            rotation,
+            ..transform
+        });
+    }
+
+    /// Sets the rotation around the z axis in radians, for flat scenes.
+    pub fn set_angle_2d(&mut self, angle: f32) {
+        self.set_rotation(Rotation::from_axis_angle(&Vec3::z_axis(), angle));
+    }
+
+    pub fn set_scale(&mut self, scale: Vec3) {
+        let transform = self.local_transform();
+        self.set_local_transform(Transform3D { scale, ..transform });
+    }
+
+    /// Sets the point the node is scaled and rotated around, which stays at
+    /// the translation.
+    pub fn set_pivot(&mut self, pivot: Vec3) {
+        let transform = self.local_transform();
+        self.set_local_transform(Transform3D { pivot, ..transform });
+    }
+
+    /// The bounds of this node's quads, relative to the node, as of the last
+    /// `SceneTree::recompute_caches`.
+    pub fn local_bounds(&self) -> Aabb {
+        self.local_bounds
+    }
+
+    /// The world space bounds of the quads of this node and all of its
+    /// descendants, as of the last `SceneTree::recompute_caches`.
+    pub fn world_bounds(&self) -> Aabb {
+        self.world_bounds
+    }
+
+    /// Where the pivot ends up in the world, as of the last
+    /// `SceneTree::recompute_caches`.
+    pub fn world_position(&self) -> Vec3 {
+        self.cache
+            .transform_point(&Point3::from(self.local_transform().pivot))
+            .coords
+    }
+
+    /// The rotation relative to the world, as of the last
+    /// `SceneTree::recompute_caches`.
+    pub fn world_rotation(&self) -> Rotation {
+        Transform3D::from_matrix(&self.cache).rotation
+    }
+
+    /// The rotation around the z axis relative to the world in radians, for
+    /// flat scenes.
+    pub fn world_angle_2d(&self) -> f32 {
+        Transform3D::from_matrix(&self.cache).angle_2d()
+    }
+
+    pub fn with_material(mut self, material: MaterialHandle) -> Self {
+        self.material = material;
+        self
+    }
+
+    /// Adds `node` as the last child, returning its id, which can be used
+    /// to find it through `SceneTree::get` once this node is in a tree.
+    pub fn add_child(&mut self, node: SceneNode) -> NodeId {
+        self.insert_child(self.children.len(), node)
+    }
+
+    /// Inserts `node` as the child at `index`, shifting the children from
+    /// there on back. Panics if `index` is past the last child.
+    pub fn insert_child(&mut self, index: usize, mut node: SceneNode) -> NodeId {
         self.child_count_changed = true;
-        node.df_index = Tracked::new(self.children.len());
-        assign_df_indices(*node.df_index, node.get_children_mut());
-        let mut new = Tracked::new(node);
-        self.children.push(new);
+        // its cache has to be recomputed under its new parent, even if it
+        // was in a tree before
+        node.transform.mark_modified();
+        let id = node.id;
+        self.children.insert(index, Tracked::new(node));
+        id
+    }
+
+    pub fn add_quad(&mut self, quad: Quad) -> QuadId {
+        self.quad_count_changed = true;
+        let (index, generation) = self.quad_ids.insert(self.quads.len() as u32);
+        self.quads.push(Tracked::new(quad));
+        self.quad_slots.push(index);
+        QuadId {
+            node: self.id,
+            index,
+            generation,
+        }
+    }
+
+    /// Removes a quad of this node, keeping the others in order. Returns
+    /// `None` if it was already removed or belongs to another node.
+    pub fn remove_quad(&mut self, id: QuadId) -> Option<Quad> {
+        if id.node != self.id {
+            return None;
+        }
+        let position = self.quad_ids.remove(id.index, id.generation)? as usize;
+        self.quad_count_changed = true;
+        self.quad_slots.remove(position);
+        for &slot in &self.quad_slots[position..] {
+            if let Some(moved) = self.quad_ids.entries[slot as usize].1.as_mut() {
+                *moved -= 1;
+            }
+        }
+        Some(self.quads.remove(position).into_inner())
+    }
+
+    pub fn quad(&self, id: QuadId) -> Option<&Tracked<Quad>> {
+        if id.node != self.id {
+            return None;
+        }
+        let position = *self.quad_ids.get(id.index, id.generation)?;
+        self.quads.get(position as usize)
+    }
+
+    pub fn quad_mut(&mut self, id: QuadId) -> Option<&mut Tracked<Quad>> {
+        if id.node != self.id {
+            return None;
+        }
+        let position = *self.quad_ids.get(id.index, id.generation)?;
+        self.quads.get_mut(position as usize)
     }
 
     pub fn iter_quads(&self) -> impl Iterator<Item = &Tracked<Quad>> {
@@ -175,8 +918,8 @@ impl SceneNode {
 
     pub(crate) fn quads_df_index<'a>(
         &'a self,
-    ) -> impl Iterator<Item = (usize, &'a Tracked<Quad>)> + 'a {
-        let idx = *self.df_index;
+    ) -> impl Iterator<Item = (&'a Tracked<usize>, &'a Tracked<Quad>)> + 'a {
+        let idx = &self.df_index;
 
         self.quads
             .iter()
@@ -184,10 +927,494 @@ impl SceneNode {
     }
 }
 
-fn assign_df_indices(parent_index: usize, nodes: &mut [Tracked<SceneNode>]) {
-    for (c, node) in nodes.iter_mut().enumerate() {
-        let cur_idx = parent_index + c;
-        *((*node).df_index) = cur_idx;
-        assign_df_indices(cur_idx, node.get_children_mut());
+// numbers the nodes in depth-first order, which is also where their caches
+// go in the matrix buffer. Only indices that changed are marked modified
+fn assign_df_indices(node: &mut SceneNode, next: &mut usize) {
+    if *node.df_index != *next {
+        *node.df_index = *next;
+    }
+    *next += 1;
+    for child in node.get_children_mut() {
+        assign_df_indices(child, next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Transform2D, Vec2};
+    use core::ops::Range;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    #[test]
+    fn draw_batches_merge_runs_of_the_same_material() {
+        let custom = MaterialHandle(1);
+        let node = |material, quads| {
+            let mut node = SceneNode::new(Mat4::identity()).with_material(material);
+            for _ in 0..quads {
+                node.add_quad(Quad::default());
+            }
+            node
+        };
+
+        let mut child = node(custom, 2);
+        child.add_child(node(custom, 1));
+        child.add_child(node(MaterialHandle::DEFAULT, 0));
+        child.add_child(node(MaterialHandle::DEFAULT, 3));
+        let mut root = node(MaterialHandle::DEFAULT, 1);
+        root.add_child(child);
+
+        let batches = SceneTree::new(root).draw_batches(&Camera::default(), |_| false, |_| 0);
+        assert_eq!(
+            ranges(&batches),
+            vec![
+                (MaterialHandle::DEFAULT, 0..1),
+                (custom, 1..4),
+                (MaterialHandle::DEFAULT, 4..7),
+            ]
+        );
+    }
+
+    #[test]
+    fn draw_batches_split_where_the_texture_changes() {
+        let textured = |handle| Quad::default().with_texture(TextureHandle::new(handle));
+        let mut child = SceneNode::new(Mat4::identity());
+        child.add_quad(textured(2));
+        let mut root = SceneNode::new(Mat4::identity());
+        for &handle in &[0, 1, 1, 3, 2] {
+            root.add_quad(textured(handle));
+        }
+        root.add_child(child);
+
+        // handles 1 and 2 are regions of the same texture
+        let texture = |handle: TextureHandle| [0, 1, 1, 2][handle.index() as usize];
+        let batches = SceneTree::new(root).draw_batches(&Camera::default(), |_| false, texture);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| (batch.texture, batch.quads.clone()))
+                .collect::<Vec<_>>(),
+            vec![(0, 0..1), (1, 1..3), (2, 3..4), (1, 4..6)]
+        );
+    }
+
+    #[test]
+    fn draw_batches_sort_transparent_quads_back_to_front() {
+        let alpha = MaterialHandle(1);
+        let node = |material, z| {
+            let mut node = SceneNode::new(Mat4::new_translation(&Vec3::new(0.0, 0.0, z)))
+                .with_material(material);
+            node.add_quad(Quad::default());
+            node
+        };
+
+        // the default camera looks down -z, so lower z is further away
+        let mut root = node(MaterialHandle::DEFAULT, 0.0);
+        root.add_child(node(alpha, -0.5));
+        root.add_child(node(alpha, 0.5));
+        root.add_child(node(alpha, -0.5));
+        root.add_child(node(MaterialHandle::DEFAULT, 0.9));
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+
+        let batches = scene.draw_batches(&Camera::default(), |material| material == alpha, |_| 0);
+        assert_eq!(
+            ranges(&batches),
+            vec![
+                (MaterialHandle::DEFAULT, 0..1),
+                (MaterialHandle::DEFAULT, 4..5),
+                (alpha, 1..2),
+                (alpha, 3..4),
+                (alpha, 2..3),
+            ]
+        );
+    }
+
+    #[test]
+    fn node_ids_survive_insertions() {
+        let mut child = SceneNode::new(Mat4::identity());
+        let grandchild = child.add_child(SceneNode::new(Mat4::new_scaling(2.0)));
+        let mut root = SceneNode::new(Mat4::identity());
+        let first = root.add_child(SceneNode::new(Mat4::identity()));
+        root.add_child(child);
+        let mut scene = SceneTree::new(root);
+
+        assert_eq!(scene.get(grandchild).unwrap().id(), grandchild);
+        let root = scene.root_mut();
+        root.get_children_mut()[0].add_child(SceneNode::new(Mat4::identity()));
+        root.add_child(SceneNode::new(Mat4::identity()));
+        assert_eq!(
+            *scene.get(grandchild).unwrap().transform,
+            Mat4::new_scaling(2.0)
+        );
+        assert_eq!(scene.get(first).unwrap().id(), first);
+    }
+
+    #[test]
+    fn get_mut_marks_the_way_to_the_node_modified() {
+        let mut child = SceneNode::new(Mat4::identity());
+        let grandchild = child.add_child(SceneNode::new(Mat4::identity()));
+        let mut root = SceneNode::new(Mat4::identity());
+        root.add_child(SceneNode::new(Mat4::identity()));
+        root.add_child(child);
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+        scene.unset_modifications();
+
+        *scene.get_mut(grandchild).unwrap().transform = Mat4::new_scaling(3.0);
+        scene.recompute_caches();
+        assert_eq!(
+            **scene.get(grandchild).unwrap().cache(),
+            Mat4::new_scaling(3.0)
+        );
+    }
+
+    #[test]
+    fn dropped_nodes_are_not_found() {
+        let mut root = SceneNode::new(Mat4::identity());
+        let child = root.add_child(SceneNode::new(Mat4::identity()));
+        let mut scene = SceneTree::new(root);
+        assert!(scene.get_mut(child).is_some());
+
+        scene = SceneTree::new(SceneNode::new(Mat4::identity()));
+        // nodes made after it was dropped never get its id
+        let mut root = SceneNode::new(Mat4::identity());
+        root.add_child(SceneNode::new(Mat4::identity()));
+        scene.root_mut().add_child(root);
+        assert!(scene.get(child).is_none());
+        assert!(scene.get_mut(child).is_none());
+    }
+
+    #[test]
+    fn quad_ids_survive_removing_other_quads() {
+        let quad = |x| Quad::new([Vec3::new(x, 0.0, 0.0); 4]);
+        let x = |quad: &Quad| quad.points[0].x;
+
+        let mut root = SceneNode::new(Mat4::identity());
+        let quads: Vec<_> = (0..4).map(|i| root.add_quad(quad(i as f32))).collect();
+        let mut scene = SceneTree::new(root);
+
+        let root = scene.root_mut();
+        assert!(root.remove_quad(quads[1]).is_some());
+        assert!(root.remove_quad(quads[1]).is_none());
+        let added = root.add_quad(quad(4.0));
+
+        assert!(scene.get_quad(quads[1]).is_none());
+        assert_eq!(x(scene.get_quad(quads[2]).unwrap()), 2.0);
+        scene.get_quad_mut(quads[3]).unwrap().points[0].x = 5.0;
+        assert_eq!(x(scene.get_quad(added).unwrap()), 4.0);
+        let xs: Vec<_> = scene.root().iter_quads().map(|quad| x(quad)).collect();
+        assert_eq!(xs, vec![0.0, 2.0, 5.0, 4.0]);
+    }
+
+    #[test]
+    fn setters_mark_only_their_node_modified() {
+        let mut root = SceneNode::new(Mat4::identity());
+        let child = root.add_child(SceneNode::new(Mat4::identity()));
+        let sibling = root.add_child(SceneNode::new(Mat4::identity()));
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+        scene.unset_modifications();
+
+        scene
+            .get_mut(child)
+            .unwrap()
+            .set_translation(Vec3::new(1.0, 2.0, 0.0));
+        assert!(scene.get(child).unwrap().transform.is_modified());
+        assert!(scene.root().transform.is_unmodified());
+        assert!(scene.get(sibling).unwrap().is_unmodified());
+    }
+
+    #[test]
+    fn world_position_and_rotation_combine_the_parents() {
+        let mut root = SceneNode::new(Mat4::identity());
+        root.set_local_transform(Transform2D {
+            translation: Vec2::new(10.0, 0.0),
+            rotation: FRAC_PI_2,
+            ..Transform2D::default()
+        });
+        let mut child = SceneNode::new(Mat4::identity());
+        child.set_pivot(Vec3::new(1.0, 1.0, 0.0));
+        child.set_translation(Vec3::new(5.0, 0.0, 0.0));
+        child.set_angle_2d(FRAC_PI_4);
+        let child = root.add_child(child);
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+
+        let child = scene.get(child).unwrap();
+        assert!((child.world_position() - Vec3::new(10.0, 5.0, 0.0)).norm() < 1e-5);
+        assert!((child.world_angle_2d() - (FRAC_PI_2 + FRAC_PI_4)).abs() < 1e-5);
+        assert!((child.local_transform().angle_2d() - FRAC_PI_4).abs() < 1e-5);
+    }
+
+    #[test]
+    fn setting_the_matrix_directly_is_split_up_again() {
+        let mut node = SceneNode::new(Mat4::identity());
+        node.set_scale(Vec3::new(2.0, 2.0, 1.0));
+        *node.transform = Mat4::new_translation(&Vec3::new(3.0, 0.0, 0.0));
+        node.set_angle_2d(FRAC_PI_2);
+
+        let transform = node.local_transform();
+        assert_eq!(transform.scale, Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(transform.translation, Vec3::new(3.0, 0.0, 0.0));
+    }
+
+    fn square(x: f32, y: f32) -> Quad {
+        Quad::rect(Vec2::new(x, y), Vec2::new(1.0, 1.0))
+    }
+
+    #[test]
+    fn world_bounds_cover_the_subtree() {
+        let mut grandchild = SceneNode::new(Mat4::new_translation(&Vec3::new(10.0, 0.0, 0.0)));
+        grandchild.add_quad(square(0.0, 0.0));
+        let mut child = SceneNode::new(Mat4::new_scaling(2.0));
+        child.add_child(grandchild);
+        let mut root = SceneNode::new(Mat4::identity());
+        let quad = root.add_quad(square(-1.0, -1.0));
+        root.add_quad(square(1.0, 1.0));
+        root.add_child(child);
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+
+        assert_eq!(
+            scene.root().local_bounds(),
+            Aabb::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(2.0, 2.0, 0.0))
+        );
+        let child = &scene.root().get_children()[0];
+        assert!(child.local_bounds().is_empty());
+        assert_eq!(
+            child.world_bounds(),
+            Aabb::new(Vec3::new(20.0, 0.0, 0.0), Vec3::new(22.0, 2.0, 0.0))
+        );
+        assert_eq!(
+            scene.bounds(),
+            Aabb::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(22.0, 2.0, 0.0))
+        );
+
+        // only picked up once the caches are recomputed
+        scene.get_quad_mut(quad).unwrap().points[0].y = -5.0;
+        assert_eq!(scene.bounds().min.y, -1.0);
+        scene.recompute_caches();
+        assert_eq!(scene.bounds().min.y, -5.0);
+    }
+
+    #[test]
+    fn bounds_follow_moved_and_removed_nodes() {
+        let mut root = SceneNode::new(Mat4::identity());
+        root.add_quad(square(0.0, 0.0));
+        let mut child = SceneNode::new(Mat4::identity());
+        child.add_quad(square(0.0, 0.0));
+        let child = root.add_child(child);
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+        scene.unset_modifications();
+
+        scene
+            .get_mut(child)
+            .unwrap()
+            .set_translation(Vec3::new(5.0, 0.0, 0.0));
+        scene.recompute_caches();
+        assert_eq!(scene.bounds().max, Vec3::new(6.0, 1.0, 0.0));
+        scene.unset_modifications();
+
+        scene.remove_child(child).unwrap();
+        scene.recompute_caches();
+        assert_eq!(scene.bounds().max, Vec3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn culled_subtrees_are_not_drawn() {
+        let node = |x| {
+            let mut node = SceneNode::new(Mat4::new_translation(&Vec3::new(x, 0.0, 0.0)));
+            node.add_quad(square(0.0, 0.0));
+            node
+        };
+        let mut far = node(100.0);
+        far.add_child(node(0.0));
+        let mut root = node(0.0);
+        root.add_child(far);
+        root.add_child(node(5.0));
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+
+        let camera = Camera::default();
+        assert_eq!(
+            scene.cull(Some(&camera.frustum(10.0, 10.0))),
+            CullStats {
+                nodes_culled: 2,
+                quads_culled: 2,
+            }
+        );
+        let batches = scene.draw_batches(&camera, |_| false, |_| 0);
+        assert_eq!(
+            ranges(&batches),
+            vec![
+                (MaterialHandle::DEFAULT, 0..1),
+                (MaterialHandle::DEFAULT, 3..4),
+            ]
+        );
+
+        assert_eq!(scene.cull(None), CullStats::default());
+        let batches = scene.draw_batches(&camera, |_| false, |_| 0);
+        assert_eq!(ranges(&batches), vec![(MaterialHandle::DEFAULT, 0..4)]);
+    }
+
+    #[test]
+    fn empty_nodes_are_not_counted_as_culled() {
+        let mut far = SceneNode::new(Mat4::new_translation(&Vec3::new(100.0, 0.0, 0.0)));
+        far.add_quad(square(0.0, 0.0));
+        far.add_child(SceneNode::new(Mat4::identity()));
+        let mut root = SceneNode::new(Mat4::identity());
+        root.add_quad(square(0.0, 0.0));
+        root.add_child(far);
+        // out of view on its own, but without anything to cull
+        root.add_child(SceneNode::new(Mat4::identity()));
+        let mut scene = SceneTree::new(root);
+        scene.recompute_caches();
+
+        let camera = Camera::default();
+        assert_eq!(
+            scene.cull(Some(&camera.frustum(10.0, 10.0))),
+            CullStats {
+                nodes_culled: 1,
+                quads_culled: 1,
+            }
+        );
+    }
+
+    // the depth-first index of every node, in depth-first order
+    fn df_indices(scene: &SceneTree) -> Vec<usize> {
+        let (caches, _) = scene.get_cache_and_quad_array();
+        caches.iter().map(|&(index, _)| **index).collect()
+    }
+
+    // a root with children a and b, each of them with two children
+    fn two_levels() -> (SceneTree, Vec<NodeId>) {
+        let mut ids = vec![];
+        let mut root = SceneNode::new(Mat4::identity());
+        for _ in 0..2 {
+            let mut child = SceneNode::new(Mat4::identity());
+            let grandchildren = [
+                child.add_child(SceneNode::new(Mat4::identity())),
+                child.add_child(SceneNode::new(Mat4::identity())),
+            ];
+            ids.push(root.add_child(child));
+            ids.extend_from_slice(&grandchildren);
+        }
+        (SceneTree::new(root), ids)
+    }
+
+    #[test]
+    fn df_indices_are_unique_and_depth_first() {
+        let (mut scene, ids) = two_levels();
+        scene.recompute_caches();
+        assert_eq!(df_indices(&scene), vec![0, 1, 2, 3, 4, 5, 6]);
+        scene.unset_modifications();
+
+        scene.remove_child(ids[1]).unwrap();
+        scene.recompute_caches();
+        assert!(scene.structure_changed());
+        assert_eq!(df_indices(&scene), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(*scene.get(ids[3]).unwrap().df_index, 3);
+    }
+
+    #[test]
+    fn removed_nodes_and_their_subtrees_are_not_found() {
+        let (mut scene, ids) = two_levels();
+        scene.remove_child(ids[3]).unwrap();
+
+        assert!(scene.get(ids[3]).is_none());
+        assert!(scene.get(ids[4]).is_none());
+        assert!(scene.get(ids[0]).is_some());
+        assert!(matches!(
+            scene.remove_child(ids[3]),
+            Err(Error::InvalidSceneEdit(SceneError::MissingNode))
+        ));
+    }
+
+    #[test]
+    fn reparented_nodes_follow_their_new_parent() {
+        let (mut scene, ids) = two_levels();
+        *scene.get_mut(ids[3]).unwrap().transform = Mat4::new_scaling(2.0);
+        *scene.get_mut(ids[1]).unwrap().transform = Mat4::new_scaling(3.0);
+        scene.recompute_caches();
+        scene.unset_modifications();
+
+        scene.reparent(ids[1], ids[4]).unwrap();
+        scene.recompute_caches();
+
+        assert_eq!(scene.root().get_children()[0].get_children().len(), 1);
+        assert_eq!(**scene.get(ids[1]).unwrap().cache(), Mat4::new_scaling(6.0));
+        assert_eq!(*scene.get(ids[1]).unwrap().df_index, 5);
+    }
+
+    #[test]
+    fn nodes_cant_be_moved_into_their_own_subtree() {
+        let (mut scene, ids) = two_levels();
+        let root = scene.root().id();
+
+        for (node, parent) in [(ids[0], ids[2]), (ids[0], ids[0]), (root, ids[0])].iter() {
+            assert!(scene.reparent(*node, *parent).is_err());
+        }
+        assert!(scene.detach(root).is_err());
+        assert_eq!(df_indices_after(&mut scene), vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn siblings_can_be_reordered_and_inserted_between() {
+        let (mut scene, ids) = two_levels();
+        let root = scene.root().id();
+        scene.recompute_caches();
+        scene.unset_modifications();
+
+        scene.set_sibling_index(ids[3], 0).unwrap();
+        assert!(scene.structure_changed());
+        let inserted = scene
+            .insert_child_at(root, 1, SceneNode::new(Mat4::identity()))
+            .unwrap();
+        assert!(scene
+            .insert_child_at(root, 4, SceneNode::new(Mat4::identity()))
+            .is_err());
+        assert!(scene.set_sibling_index(ids[3], 3).is_err());
+
+        let order: Vec<_> = scene.root().iter_children().map(|node| node.id()).collect();
+        assert_eq!(order, vec![ids[3], inserted, ids[0]]);
+        scene.recompute_caches();
+        assert_eq!(*scene.get(inserted).unwrap().df_index, 4);
+        assert_eq!(*scene.get(ids[2]).unwrap().df_index, 7);
+    }
+
+    #[test]
+    fn links_follow_edits_through_the_tree_and_directly() {
+        let (mut scene, ids) = two_levels();
+        scene.reparent(ids[4], ids[1]).unwrap();
+        scene.set_sibling_index(ids[3], 0).unwrap();
+        scene.remove_child(ids[2]).unwrap();
+        assert!(!scene.links_stale);
+        for &id in &[ids[0], ids[1], ids[3], ids[4], ids[5]] {
+            assert_eq!(scene.get(id).unwrap().id(), id);
+        }
+        assert!(scene.get(ids[2]).is_none());
+
+        let added = scene
+            .get_mut(ids[1])
+            .unwrap()
+            .add_child(SceneNode::new(Mat4::identity()));
+        assert_eq!(scene.get(added).unwrap().id(), added);
+        scene.recompute_caches();
+        assert!(!scene.links_stale);
+        assert_eq!(scene.linked_path(added), Some(vec![1, 0, 1]));
+    }
+
+    fn df_indices_after(scene: &mut SceneTree) -> Vec<usize> {
+        scene.recompute_caches();
+        df_indices(scene)
+    }
+
+    fn ranges(batches: &[DrawBatch]) -> Vec<(MaterialHandle, Range<u32>)> {
+        batches
+            .iter()
+            .map(|batch| (batch.material, batch.quads.clone()))
+            .collect()
     }
 }
@@ -17,13 +17,13 @@ pub enum Tracked<T: Unpin> {
 }
 
 impl<T: Unpin> Tracked<T> {
-    /// Construct a new Tracked set to unmodified
+    /// Construct a new Tracked set to modified
     /// ```
-    /// use tracked::Tracked;
+    /// use rmge::tracker::Tracked;
     ///
     /// let tracker = Tracked::new(5);
     ///
-    /// assert!(tracker.is_unmodified());
+    /// assert!(tracker.is_modified());
     /// ```
     pub fn new(x: T) -> Self {
         Tracked::Modified(x)
@@ -35,40 +35,32 @@ impl<T: Unpin> Tracked<T> {
 
     /// Returns whether this value is unmodified
     /// ```
-    /// use tracked::Tracked;
+    /// use rmge::tracker::Tracked;
     ///
-    /// let mut tracker = Tracked::new(5);
+    /// let mut tracker = Tracked::Unmodified(5);
     /// assert!(tracker.is_unmodified());
     /// *tracker = 4;
     /// assert!(!tracker.is_unmodified());
     /// ```
     pub fn is_unmodified(&self) -> bool {
-        if let Tracked::Unmodified(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Tracked::Unmodified(_))
     }
 
     /// Returns whether this value has been modified
     /// ```
-    /// use tracked::Tracked;
+    /// use rmge::tracker::Tracked;
     ///
     /// let mut tracker = Tracked::new(5);
     /// *tracker = 4;
     /// assert!(tracker.is_modified());
     /// ```
     pub fn is_modified(&self) -> bool {
-        if let Tracked::Modified(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Tracked::Modified(_))
     }
 
     /// Reset this tracker to an unmodified state
-    /// ```
-    /// use tracked::Tracked;
+    /// ```ignore
+    /// use rmge::tracker::Tracked;
     ///
     /// let mut tracker = Tracked::new(5);
     ///
@@ -112,8 +104,7 @@ impl<T: Unpin> Deref for Tracked<T> {
 
     fn deref(&self) -> &T {
         match self {
-            &Tracked::Unmodified(ref inner) => inner,
-            &Tracked::Modified(ref inner) => inner,
+            Tracked::Unmodified(inner) | Tracked::Modified(inner) => inner,
         }
     }
 }